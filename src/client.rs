@@ -14,26 +14,144 @@
 
 extern crate sodiumoxide;
 use std::net;
+use std::net::ToSocketAddrs;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use super::common::*;
-use super::common::message::{receive, send, MessageContent};
+use super::common::message::{receive, send, MessageContent, VersionOffer};
 use std::io;
 use std::collections::HashMap;
 use std::time::Duration;
-use std::net::Shutdown;
 use proj_crypto::asymmetric::*;
-use Keypair;
+use {Keypair, ResumptionTicket, PreSharedKey, fingerprint};
+use stream;
+use datagram::Datagram;
+use known_servers::KnownServers;
+use revocation::RevocationList;
+use trust::TrustStore;
 
 /// Structure containing the state for a running client
-pub struct Client {
-    state: ProtocolState,
-    read_buff: Vec<u8>,
+pub struct Client<S: Transport = net::TcpStream> {
+    state: ProtocolState<S>,
 }
 
-/// Creates a new client and performs a key exchange
-pub fn start(socket_addr: &str, long_keypair: Keypair, trusted_pks: &HashMap<key_id::PublicKeyId, PublicKey>) -> Result<Client, Error> {
-    sodiumoxide::init();
+/// How we decide whether to trust the server's long-term key
+enum ServerTrust<'a, T: 'a + TrustStore> {
+    /// only the keys in this store
+    Keys(&'a T),
+    /// the key recorded for the server with this name. If there isn't one yet, any key the server proves it holds is recorded and trusted
+    FirstUse(&'a mut KnownServers, &'a str),
+}
+
+/// The store type of a ServerTrust which does not look keys up in a store, such as ServerTrust::FirstUse
+type NoTrustStore = HashMap<key_id::PublicKeyId, PublicKey>;
+
+/// What a client checks during its key exchanges apart from the trusted public keys. ClientConfig::new() checks nothing else.
+/// Every entry point which uses the server's long-term key takes one.
+pub struct ClientConfig<'a> {
+    /// servers whose long-term key is in here are refused with Error::Revoked even if they are still trusted
+    pub revocations: Option<&'a RevocationList>,
+    /// ask the server to use the long-term key with this id, for servers which have several (see server::ServerConfig::add_keypair()). The key must still be trusted.
+    /// The key exchange fails with Error::Rejected(ErrorReason::UnknownServerKey) if the server does not have it. Resumed sessions always use the key from the original key exchange
+    pub server_long_pk_id: Option<key_id::PublicKeyId>,
+}
+
+impl<'a> ClientConfig<'a> {
+    /// A client which only checks the trusted public keys
+    pub fn new() -> ClientConfig<'a> {
+        ClientConfig {
+            revocations: None,
+            server_long_pk_id: None,
+        }
+    }
+}
+
+/// Creates a new client and performs a key exchange. It fails with Error::Revoked if the server's key is in config.revocations, even if it is still in trusted_pks.
+pub fn start<T: TrustStore>(socket_addr: &str, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client, Error> {
     // attempt connection
-    let mut stream = match net::TcpStream::connect(socket_addr) {
+    let stream = match net::TcpStream::connect(socket_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    log("Connected successfully", LOG_DEBUG);
+
+    start_on(stream, long_keypair, trusted_pks, config)
+}
+
+/// Creates a new client connected to a Unix domain socket and performs a key exchange
+pub fn start_unix<P: AsRef<Path>, T: TrustStore>(socket_path: P, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client<UnixStream>, Error> {
+    // attempt connection
+    let stream = match UnixStream::connect(socket_path) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    log("Connected successfully", LOG_DEBUG);
+
+    start_on(stream, long_keypair, trusted_pks, config)
+}
+
+/// Creates a new client which talks to the server over UDP and performs a key exchange. See the datagram module for what changes over UDP.
+pub fn start_udp<A: ToSocketAddrs, T: TrustStore>(server_addr: A, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client<Datagram>, Error> {
+    let server_addr = match server_addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(a)) => a,
+        Ok(None) => return Err(Error::Connect(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))),
+        Err(e) => return Err(Error::Connect(e)),
+    };
+
+    // any local port will do
+    let local_addr: net::SocketAddr = if server_addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+
+    let stream = match Datagram::connect(local_addr, server_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    start_on(stream, long_keypair, trusted_pks, config)
+}
+
+/// Performs a key exchange over a stream which is already connected to the server
+pub fn start_on<S: Transport, T: TrustStore>(stream: S, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client<S>, Error> {
+    key_exchange(stream, Some(long_keypair), false, ServerTrust::Keys(trusted_pks), config)
+}
+
+/// Creates a new client and performs a key exchange, trusting the server's long-term key the first time we connect to socket_addr.
+/// The key is recorded in known_servers and later connections fail with Error::ServerKeyMismatch if the server presents a different one.
+pub fn start_tofu(socket_addr: &str, long_keypair: Keypair, known_servers: &mut KnownServers, config: &ClientConfig) -> Result<Client, Error> {
+    let stream = match net::TcpStream::connect(socket_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    log("Connected successfully", LOG_DEBUG);
+
+    start_tofu_on(stream, socket_addr, long_keypair, known_servers, config)
+}
+
+/// Performs a key exchange over a stream which is already connected to the server, trusting the server's long-term key on first use.
+/// server_name is what the key is recorded under in known_servers.
+pub fn start_tofu_on<S: Transport>(stream: S, server_name: &str, long_keypair: Keypair, known_servers: &mut KnownServers, config: &ClientConfig) -> Result<Client<S>, Error> {
+    let trust: ServerTrust<NoTrustStore> = ServerTrust::FirstUse(known_servers, server_name);
+    key_exchange(stream, Some(long_keypair), false, trust, config)
+}
+
+/// Creates a new client without a long-term identity and performs a key exchange. The server still has to prove that it is in trusted_pks.
+/// The server must allow anonymous devices (see server::ServerConfig).
+pub fn start_anonymous<T: TrustStore>(socket_addr: &str, trusted_pks: &T, config: &ClientConfig) -> Result<Client, Error> {
+    let stream = match net::TcpStream::connect(socket_addr) {
         Ok(s) => s,
         Err(e) => {
             log("Failed to connect", LOG_RELEASE);
@@ -41,106 +159,523 @@ pub fn start(socket_addr: &str, long_keypair: Keypair, trusted_pks: &HashMap<key
     };
 
     log("Connected successfully", LOG_DEBUG);
-    let mut expected_next_n: u16 = 0;
 
-    // send device first
-    let session_keypair = match send::device_first(&mut stream, &long_keypair.0) {
+    start_anonymous_on(stream, trusted_pks, config)
+}
+
+/// Performs a key exchange without a long-term identity over a stream which is already connected to the server
+pub fn start_anonymous_on<S: Transport, T: TrustStore>(stream: S, trusted_pks: &T, config: &ClientConfig) -> Result<Client<S>, Error> {
+    key_exchange(stream, None, false, ServerTrust::Keys(trusted_pks), config)
+}
+
+/// Creates a new client and performs a key exchange without letting anyone but the server see who we are.
+/// Ordinary key exchanges send the id of our long-term public key in clear text, which would let eavesdroppers follow the device around.
+/// Fails with Error::Rejected(ErrorReason::UntrustedIdentity) if the server does not trust our key, like the other key exchanges.
+pub fn start_hidden<T: TrustStore>(socket_addr: &str, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client, Error> {
+    let stream = match net::TcpStream::connect(socket_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    log("Connected successfully", LOG_DEBUG);
+
+    start_hidden_on(stream, long_keypair, trusted_pks, config)
+}
+
+/// Performs a key exchange which hides our identity from eavesdroppers over a stream which is already connected to the server
+pub fn start_hidden_on<S: Transport, T: TrustStore>(stream: S, long_keypair: Keypair, trusted_pks: &T, config: &ClientConfig) -> Result<Client<S>, Error> {
+    key_exchange(stream, Some(long_keypair), true, ServerTrust::Keys(trusted_pks), config)
+}
+
+/// long_keypair is None for anonymous devices. hide_identity only makes a difference if there is a long_keypair
+fn key_exchange<S: Transport, T: TrustStore>(mut stream: S, long_keypair: Option<Keypair>, hide_identity: bool, trust: ServerTrust<T>, config: &ClientConfig) -> Result<Client<S>, Error> {
+    sodiumoxide::init();
+    let mut expected_next_n: u64 = 0;
+
+    let server_long_pk_id = config.server_long_pk_id.as_ref();
+    let hidden = hide_identity && long_keypair.is_some();
+
+    let first_use = match trust {
+        ServerTrust::FirstUse(..) => true,
+        ServerTrust::Keys(_) => false,
+    };
+
+    // only offer versions which can do everything we ask for, newest feature first. Older servers only have one key,
+    // we can only check a key we don't know yet if the server sends all of it, and older servers can't accept hidden or anonymous devices
+    let min_version = if server_long_pk_id.is_some() {
+        message::KEY_SELECTION_VERSION
+    } else if first_use {
+        message::TOFU_VERSION
+    } else if hidden {
+        message::HIDDEN_IDENTITY_VERSION
+    } else if long_keypair.is_none() {
+        message::ANONYMOUS_VERSION
+    } else {
+        message::MIN_PROTOCOL_VERSION
+    };
+    let offer = VersionOffer { min: min_version, max: message::PROTOCOL_VERSION };
+
+    // send device first. It is kept in case it needs sending again
+    let mut device_first = Vec::new();
+    let sent = match (long_keypair.as_ref(), server_long_pk_id) {
+        (Some(_), _) if hidden => send::hidden_device_first(&mut device_first, server_long_pk_id, &offer),
+        (Some(keypair), Some(id)) => send::targeted_device_first(&mut device_first, &keypair.0, id, &offer),
+        (Some(keypair), None) => send::device_first(&mut device_first, &keypair.0, &offer),
+        (None, _) => send::anonymous_device_first(&mut device_first, server_long_pk_id, &offer),
+    };
+
+    let session_keypair = match sent {
         Ok(k) => k,
+        Err(e) => return Err(Error::DeviceFirst(e)),
+    };
+
+    match stream.write_all(&device_first) {
+        Ok(()) => (),
         Err(e) => {
             log("Problem sending device_first", LOG_RELEASE);
-            return Err(Error::DeviceFirst(e)); },
+            return Err(Error::DeviceFirst(message::Error::Write(e))); },
     };
 
     log("Sent device_first successfully", LOG_DEBUG);
 
     // receive server response
-    let server_first = match receive::server_first(&mut stream, &session_keypair, trusted_pks) {
+    let server_first = match await_handshake_reply(&mut stream, &device_first, |s| match trust {
+        ServerTrust::Keys(trusted_pks) => receive::server_first(s, &session_keypair, trusted_pks),
+        ServerTrust::FirstUse(..) => receive::server_first_any_key(s, &session_keypair),
+    }) {
         Ok(m) => m,
         Err(e) => {
             log("Failed to receive server_first", LOG_RELEASE);
             send_error(&mut stream, 1);
-            stream.shutdown(Shutdown::Both).unwrap();
+            let _ = stream.close();
             return Err(Error::ServerFirst(e)); },
     };
 
     if !check_message_n(&mut expected_next_n, &server_first) {
         send_error(&mut stream, 1);
-        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = stream.close();
         return Err(Error::BadMessageN);
     }
 
-    let (server_session_pk, challenge, server_long_pk) = match server_first.content {
-        MessageContent::ServerFirst(pk, c, long_pk) => (pk, c, long_pk),
+    let (server_session_pk, challenge, server_long_pk, version) = match server_first.content {
+        MessageContent::ServerFirst(pk, c, long_pk, v, echoed_offer) => {
+            if !offer.check_choice(&echoed_offer, v) {
+                log("The server did not see the versions we offered. Refusing to be downgraded.", LOG_RELEASE);
+                send_error(&mut stream, 1);
+                let _ = stream.close();
+                return Err(Error::ServerFirst(message::Error::Version));
+            }
+            (pk, c, long_pk, v) },
+        MessageContent::Rejected(reason) => {
+            log(&format!("The server refused the key exchange: {}", reason), LOG_RELEASE);
+            let _ = stream.close();
+            return Err(Error::Rejected(reason)); },
         _ => return Err(Error::ServerFirst(message::Error::InvalidOpcode)),
     };
 
+    match check_revocation(config.revocations, &server_long_pk) {
+        Ok(()) => (),
+        Err(e) => {
+            send_error(&mut stream, 1);
+            let _ = stream.close();
+            return Err(e); },
+    };
+
+    match server_long_pk_id {
+        Some(id) if key_id::id_of_pk(&server_long_pk) != *id => {
+            log("The server answered with a different long-term key to the one we asked for", LOG_RELEASE);
+            send_error(&mut stream, 1);
+            let _ = stream.close();
+            return Err(Error::ServerFirst(message::Error::ServerKeyId)); },
+        _ => (),
+    };
+
+    match trust {
+        ServerTrust::FirstUse(known_servers, server_name) => match check_first_use(known_servers, server_name, &server_long_pk) {
+            Ok(()) => (),
+            Err(e) => {
+                send_error(&mut stream, 1);
+                let _ = stream.close();
+                return Err(e); },
+        },
+        ServerTrust::Keys(_) => (),
+    };
+
     log("received server_first successfully", LOG_DEBUG);    
 
     // send challenge response
-    let session_keys = match send::device_second(&mut stream, &server_long_pk, &server_session_pk, &challenge, &long_keypair, &session_keypair) {
+    let mut device_second = Vec::new();
+    let sent = match long_keypair {
+        Some(ref keypair) if hidden => send::hidden_device_second(&mut device_second, &server_long_pk, &server_session_pk, &challenge, keypair, server_long_pk_id, &session_keypair, version, &offer),
+        _ => send::device_second(&mut device_second, &server_long_pk, &server_session_pk, &challenge, long_keypair.as_ref(), server_long_pk_id, &session_keypair, version, &offer),
+    };
+    let session_keys = match sent {
         Ok(sk) => sk,
         Err(e) => return Err(Error::DeviceSecond(e)),
     };
 
+    match stream.write_all(&device_second) {
+        Ok(()) => (),
+        Err(e) => return Err(Error::DeviceSecond(message::Error::Write(e))),
+    };
+
+    // the server only finds out who we are from hidden_device_second, so it tells us whether it trusts us
+    if hidden {
+        let server_second = match await_handshake_reply(&mut stream, &device_second, |s| receive::hidden_server_second(s, &session_keys, &challenge)) {
+            Ok(m) => m,
+            Err(e) => {
+                log("Failed to receive hidden_server_second", LOG_RELEASE);
+                send_error(&mut stream, 2);
+                let _ = stream.close();
+                return Err(Error::ServerSecond(e)); },
+        };
+
+        // a rejection is numbered 0 like the ones sent in place of server_first
+        match server_second.content {
+            MessageContent::HiddenServerSecond => (),
+            MessageContent::Rejected(reason) => {
+                log(&format!("The server refused the key exchange: {}", reason), LOG_RELEASE);
+                let _ = stream.close();
+                return Err(Error::Rejected(reason)); },
+            _ => {
+                let _ = stream.close();
+                return Err(Error::ServerSecond(message::Error::InvalidOpcode)); },
+        };
+
+        if !check_message_n(&mut expected_next_n, &server_second) {
+            send_error(&mut stream, 2);
+            let _ = stream.close();
+            return Err(Error::BadMessageN);
+        }
+    }
+
     log("Key exchange complete", LOG_DEBUG);
 
-    let client = ProtocolState {
-        stream: stream,
-        long_keypair: long_keypair,
-        next_send_n: 2,
-        next_recv_n: expected_next_n,
-        session_keys: session_keys,
-        send_as_device: true,
+    let mut client = ProtocolState::new(stream, long_keypair, Some(server_long_pk), session_keys, true, version, 2, expected_next_n);
+
+    // over datagrams we don't know that the server has it until the server sends us something
+    if client.stream.is_datagram() && !hidden {
+        client.handshake_reply = Some(device_second);
+    }
+
+    Ok(Client{ state: client })
+}
+
+/// Check the server's key against the one recorded for it, or record it if this is the first time we have seen the server
+fn check_first_use(known_servers: &mut KnownServers, server_name: &str, server_long_pk: &PublicKey) -> Result<(), Error> {
+    let known = match known_servers.get(server_name) {
+        Some(pk) => pk.clone(),
+        None => {
+            log(&format!("Trusting {} with key {} on first use", server_name, fingerprint(server_long_pk)), LOG_RELEASE);
+            return match known_servers.insert(server_name, server_long_pk.clone()) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(Error::KnownServers(e)),
+            };
+        },
     };
 
-    Ok(Client{ state: client, read_buff: Vec::new() })
+    if known == *server_long_pk {
+        Ok(())
+    } else {
+        let (expected, presented) = (fingerprint(&known), fingerprint(server_long_pk));
+        log(&format!("{} presented key {} but we know it by key {}. Someone may be impersonating it.", server_name, presented, expected), LOG_RELEASE);
+        Err(Error::ServerKeyMismatch(expected, presented))
+    }
 }
 
-/// Sending data
-impl io::Write for Client{
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        general_write(&mut self.state, buf)
+/// Creates a new client and performs a key exchange authenticated by a pre-shared key instead of long-term keypairs
+pub fn start_psk(socket_addr: &str, psk: &PreSharedKey) -> Result<Client, Error> {
+    let stream = match net::TcpStream::connect(socket_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
+
+    log("Connected successfully", LOG_DEBUG);
+
+    start_psk_on(stream, psk)
+}
+
+/// Performs a key exchange authenticated by a pre-shared key over a stream which is already connected to the server.
+/// The server must be using do_psk_key_exchange().
+pub fn start_psk_on<S: Transport>(mut stream: S, psk: &PreSharedKey) -> Result<Client<S>, Error> {
+    sodiumoxide::init();
+    let mut expected_next_n: u64 = 0;
+
+    // pre-shared keys only exist from this version so there is no point offering anything older
+    let offer = VersionOffer { min: message::PSK_VERSION, max: message::PROTOCOL_VERSION };
+    let mut device_first = Vec::new();
+    let session_keypair = match send::psk_device_first(&mut device_first, &psk.id, &offer) {
+        Ok(k) => k,
+        Err(e) => return Err(Error::DeviceFirst(e)),
+    };
+
+    match stream.write_all(&device_first) {
+        Ok(()) => (),
+        Err(e) => {
+            log("Problem sending psk_device_first", LOG_RELEASE);
+            return Err(Error::DeviceFirst(message::Error::Write(e))); },
+    };
+
+    log("Sent psk_device_first successfully", LOG_DEBUG);
+
+    let server_first = match await_handshake_reply(&mut stream, &device_first, |s| receive::psk_server_first(s, &session_keypair, &psk.key, &psk.id)) {
+        Ok(m) => m,
+        Err(e) => {
+            log("Failed to receive psk_server_first", LOG_RELEASE);
+            send_error(&mut stream, 1);
+            let _ = stream.close();
+            return Err(Error::ServerFirst(e)); },
+    };
+
+    if !check_message_n(&mut expected_next_n, &server_first) {
+        send_error(&mut stream, 1);
+        let _ = stream.close();
+        return Err(Error::BadMessageN);
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.state.stream.flush()
+    let (server_session_pk, challenge, version) = match server_first.content {
+        MessageContent::PskServerFirst(pk, c, v, echoed_offer) => {
+            if !offer.check_choice(&echoed_offer, v) {
+                log("The server did not see the versions we offered. Refusing to be downgraded.", LOG_RELEASE);
+                send_error(&mut stream, 1);
+                let _ = stream.close();
+                return Err(Error::ServerFirst(message::Error::Version));
+            }
+            (pk, c, v) },
+        MessageContent::Rejected(reason) => {
+            log(&format!("The server refused the key exchange: {}", reason), LOG_RELEASE);
+            let _ = stream.close();
+            return Err(Error::Rejected(reason)); },
+        _ => return Err(Error::ServerFirst(message::Error::InvalidOpcode)),
+    };
+
+    log("received psk_server_first successfully", LOG_DEBUG);
+
+    let transcript = send::psk_transcript_hash(&psk.id, &session_keypair.0, &server_session_pk, &challenge, version, &offer);
+    let session_keys = send::psk_session_keys(&psk.key, &key_exchange::key_exchange(&server_session_pk, &session_keypair.1, &session_keypair.0, true), &transcript);
+
+    // send challenge response
+    let mut device_second = Vec::new();
+    match send::challenge_response(&mut device_second, &session_keys, &challenge) {
+        None => (),
+        Some(e) => return Err(Error::DeviceSecond(e)),
+    };
+
+    match stream.write_all(&device_second) {
+        Ok(()) => (),
+        Err(e) => return Err(Error::DeviceSecond(message::Error::Write(e))),
+    };
+
+    log("Key exchange complete", LOG_DEBUG);
+
+    let mut client = ProtocolState::new(stream, None, None, session_keys, true, version, 2, expected_next_n);
+    client.psk_id = Some(psk.id.clone());
+
+    // over datagrams we don't know that the server has it until the server sends us something
+    if client.stream.is_datagram() {
+        client.handshake_reply = Some(device_second);
     }
+
+    Ok(Client{ state: client })
 }
 
-/// Receiving data
-impl io::Read for Client {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let ret = general_read(&mut self.state, &mut self.read_buff);
+/// Connects to the server and resumes an earlier session using a ticket from Client::resumption_ticket().
+/// If the server won't resume the session, a new connection is made with the full key exchange instead.
+pub fn resume<T: TrustStore>(socket_addr: &str, long_keypair: Keypair, trusted_pks: &T, ticket: &ResumptionTicket, config: &ClientConfig) -> Result<Client, Error> {
+    let resumed = match net::TcpStream::connect(socket_addr) {
+        Ok(s) => resume_on(s, long_keypair.clone(), trusted_pks, ticket, config),
+        Err(e) => {
+            log("Failed to connect", LOG_RELEASE);
+            return Err(Error::Connect(e)); },
+    };
 
-        if ret.is_err() {
-            return ret;
-        }
+    match resumed {
+        Ok(c) => Ok(c),
+        Err(e) => {
+            log(&format!("Could not resume the session ({:?}). Doing the full key exchange instead.", e), LOG_DEBUG);
+            start(socket_addr, long_keypair, trusted_pks, config)
+        },
+    }
+}
+
+/// Resumes an earlier session over a stream which is already connected to the server. This takes one round trip instead of the full key exchange.
+/// The server must have a ticket key (see server::ServerConfig). Fails with Error::Resume(message::Error::PubKeyId) if the key of the server which issued the ticket is no longer in trusted_pks, and with Error::Revoked if it is in config.revocations.
+pub fn resume_on<S: Transport, T: TrustStore>(mut stream: S, long_keypair: Keypair, trusted_pks: &T, ticket: &ResumptionTicket, config: &ClientConfig) -> Result<Client<S>, Error> {
+    sodiumoxide::init();
+
+    // the server only proves that it issued the ticket, so we must still trust the key it had then
+    match trusted_pks.lookup(&key_id::id_of_pk(&ticket.server_long_pk)) {
+        Some(ref pk) if *pk == ticket.server_long_pk => (),
+        _ => {
+            log("The server which issued the ticket is not trusted any more", LOG_RELEASE);
+            return Err(Error::Resume(message::Error::PubKeyId)); },
+    };
+    match check_revocation(config.revocations, &ticket.server_long_pk) {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
 
-        let num_elements = {
-            if buf.len() > self.read_buff.len() {
-                self.read_buff.len()
-            } else {
-                buf.len()
+    // tickets only exist from this version so there is no point offering anything older
+    let offer = VersionOffer { min: message::RESUMPTION_VERSION, max: message::PROTOCOL_VERSION };
+    let mut resume = Vec::new();
+    let device_nonce = match send::resume(&mut resume, &ticket.secret, &ticket.ticket, &offer) {
+        Ok(n) => n,
+        Err(e) => return Err(Error::Resume(e)),
+    };
+
+    match stream.write_all(&resume) {
+        Ok(()) => (),
+        Err(e) => {
+            log("Problem sending resume", LOG_RELEASE);
+            return Err(Error::Resume(message::Error::Write(e))); },
+    };
+
+    log("Sent resume successfully", LOG_DEBUG);
+
+    let resume_accept = match await_handshake_reply(&mut stream, &resume, |s| receive::resume_accept(s, &ticket.secret, &ticket.ticket, &device_nonce)) {
+        Ok(m) => m,
+        Err(e) => {
+            log("Failed to receive resume accept", LOG_RELEASE);
+            send_error(&mut stream, 1);
+            let _ = stream.close();
+            return Err(Error::Resume(e)); },
+    };
+
+    let (server_nonce, version) = match resume_accept.content {
+        MessageContent::ResumeAccept(nonce, v, echoed_offer) => {
+            if !offer.check_choice(&echoed_offer, v) {
+                log("The server did not see the versions we offered. Refusing to be downgraded.", LOG_RELEASE);
+                send_error(&mut stream, 1);
+                let _ = stream.close();
+                return Err(Error::Resume(message::Error::Version));
             }
-        };
+            (nonce, v) },
+        _ => {
+            log("The server would not resume the session", LOG_DEBUG);
+            let _ = stream.close();
+            return Err(Error::Resume(message::Error::InvalidOpcode)); },
+    };
 
-        for i in 0..num_elements {
-            buf[i] = self.read_buff.remove(0);
-        }
+    let transcript = send::resume_transcript_hash(&ticket.ticket, &device_nonce, &server_nonce, version, &offer);
+    let session_keys = send::resumed_session_keys(&ticket.secret, &transcript);
 
-        Ok(num_elements)
+    log("Session resumed", LOG_DEBUG);
+
+    // the resume packets were number 0 in each direction
+    let client = ProtocolState::new(stream, Some(long_keypair), Some(ticket.server_long_pk.clone()), session_keys, true, version, 1, 1);
+
+    Ok(Client{ state: client })
+}
+
+/// Sending data
+impl<S: Transport> io::Write for Client<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        general_write(&mut self.state, message::DEFAULT_STREAM, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        general_flush(&mut self.state)
+    }
+}
+
+/// Receiving data
+impl<S: Transport> io::Read for Client<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        general_read_into(&mut self.state, message::DEFAULT_STREAM, buf)
     }
 }
 
-impl Client {
-    /// Give up on IO after blocking for a timeout
+impl<S: Transport> Client<S> {
+    /// Give up on IO after blocking for a timeout. Panics if the transport can't time out reads.
     pub fn blocking_off(&mut self, milliseconds: u64) {
-        self.state.stream.set_read_timeout(Some(Duration::from_millis(milliseconds))).unwrap(); // 1ms read timeout
+        general_set_read_timeout(&mut self.state, Some(Duration::from_millis(milliseconds)));
     }
 
     /// Block indefinably for IO
     pub fn blocking_on(&mut self) {
-        self.state.stream.set_read_timeout(None).unwrap();
+        general_set_read_timeout(&mut self.state, None);
+    }
+
+    /// The protocol version agreed with the server
+    pub fn protocol_version(&self) -> u8 {
+        self.state.version
+    }
+
+    /// The server's long-term public key. After a full key exchange the server has proved that it holds the key. A resumed session reuses the key from the ticket: the server has only proved that it could open the ticket, which a server with the same ticket key could do too.
+    /// None if the server has no long-term identity in this session (see the pre-shared key mode).
+    pub fn peer_long_pk(&self) -> Option<&PublicKey> {
+        self.state.peer_long_pk.as_ref()
+    }
+
+    /// The id of the server's long-term public key, as used in the trusted public key map
+    pub fn peer_key_id(&self) -> Option<key_id::PublicKeyId> {
+        self.state.peer_long_pk.as_ref().map(key_id::id_of_pk)
+    }
+
+    /// The id of our own long-term public key, which the server used to look us up. None if we have no long-term identity in this session.
+    pub fn key_id(&self) -> Option<key_id::PublicKeyId> {
+        self.state.long_keypair.as_ref().map(|keypair| key_id::id_of_pk(&keypair.0))
+    }
+
+    /// 32 bytes of secret keying material which only we and the server can derive, for binding application data to this session.
+    /// Different labels (or contexts) give unrelated secrets. Rekeying does not change the result.
+    pub fn export_keying_material(&self, label: &[u8], context: &[u8]) -> Vec<u8> {
+        send::export(&self.state.exporter_secret, label, context)
+    }
+
+    /// An identifier for this session which is the same on both ends and different for every other session (including resumed ones). It is not secret.
+    pub fn session_id(&self) -> Vec<u8> {
+        send::session_id(&self.state.exporter_secret)
+    }
+
+    /// Send data like write() but return a handle which can be used to find out when the peer has received it
+    pub fn write_tracked(&mut self, buf: &[u8]) -> io::Result<u64> {
+        general_write_tracked(&mut self.state, message::DEFAULT_STREAM, buf)
+    }
+
+    /// Has the peer acknowledged the tracked write with this handle?
+    pub fn is_acknowledged(&self, handle: u64) -> bool {
+        self.state.acked_count >= handle
+    }
+
+    /// Block until the peer acknowledges the tracked write with this handle. Data received in the mean time is kept for read()
+    pub fn wait_for_ack(&mut self, handle: u64) -> io::Result<()> {
+        general_wait_for_ack(&mut self.state, handle)
+    }
+
+    /// Replace the session keys without waiting for the message numbers to run out. Returns straight away: data written before the peer answers is held back and sent under the new keys.
+    /// Call flush() to be sure held back data has been sent. Dropping the session only waits DROP_REKEY_WAIT_MS for the peer's answer
+    pub fn rekey(&mut self) -> io::Result<()> {
+        general_rekey(&mut self.state)
+    }
+
+    /// Tell the peer that we have finished sending. Its reads return 0 once it has read everything we sent, but it can still write to us and we can still read.
+    /// Writes fail from now on. Fails if the peer is too old to understand this or over datagram transports.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        general_shutdown_write(&mut self.state)
+    }
+
+    /// Ping the peer whenever it has been quiet for interval while we are reading or writing. If it stays quiet for liveness_timeout while we read, the connection is closed and the read fails with io::ErrorKind::TimedOut.
+    /// Pings are only sent from within reads and writes, so an application which does neither for a while should read with a timeout (see blocking_off()) to keep the connection alive.
+    /// Passing None for interval turns keepalives off. Fails if the peer is too old to answer pings or the transport can't peek (see Transport::can_peek()), such as Unix sockets.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, liveness_timeout: Duration) -> io::Result<()> {
+        general_set_keepalive(&mut self.state, interval, liveness_timeout)
+    }
+
+    /// Share the session out between logical streams, each of which can be used from its own thread. Reads and writes on the client itself become stream::Streams::default_stream().
+    /// Opening a stream fails if the peer is too old to support streams.
+    pub fn into_streams(self) -> stream::Streams<S> {
+        stream::new(self.state)
+    }
+
+    /// The newest resumption ticket the server has given us, if any. Tickets arrive along with data so this is only updated by reads.
+    /// Pass it to resume() to skip the full key exchange when reconnecting.
+    pub fn resumption_ticket(&self) -> Option<&ResumptionTicket> {
+        self.state.resumption_ticket.as_ref()
     }
 }
 
@@ -7,22 +7,72 @@
 //! # The Protocol
 //! ## Device Message 0
 //! + generate ephemeral keypair
-//! + send ephemeral public key to the server along with the ID of the device's long-term public key
+//! + send ephemeral public key to the server along with the ID of the device's long-term public key and the range of protocol versions the device speaks
+//!
+//! Devices which predate version negotiation send the same packet without the versions, under its original opcode. The server treats this as an offer of version 1 only and leaves the versions out of its answer too.
 //!
 //! ## Server Message 0
 //! + Generate ephemeral keypair
 //! + Compute session keys
 //! + Pick a random challenge number
-//! + Send ephemeral public key and r to the client, along with the ID of the server's long-term public key. Plaintext authentication (as the client does not yet have the encryption key)
+//! + Pick the newest protocol version both sides speak
+//! + Send ephemeral public key, r, the chosen version and the version range the device offered to the client, along with the ID of the server's long-term public key. Plaintext authentication (as the client does not yet have the encryption key)
 //!
 //! ## Device Message 1
 //! + Check auth
+//! + Check that the version range echoed by the server is the one we sent. Otherwise someone has tampered with it to force an older version.
 //! + Compute session keys
 //! + Send r to server, encrypted and authenticated. This authenticates the ephemeral public key we sent in message 0
 //!
 //! ## Server
 //! + Decrypt and authenticate and check the challenge response
 //!
+//! ## Streams
+//! From version 3 every data packet says which logical stream it belongs to. Stream 0 always exists; the device opens streams with odd ids and the server with even ones, so they never pick the same id.
+//! A stream is opened by sending data on it. Either party can send an authenticated close stream packet once it has finished writing on a stream, after which the other party's reads on that stream return 0.
+//! Data on a stream the sender has closed, or on one of the receiver's ids which the receiver has not opened yet, is a protocol error. Each side keeps at most a fixed amount of unread data per stream and closes the session with a buffer full error if the peer sends more.
+//!
+//! ## Resuming a session
+//! From version 6 the server can give the device a resumption ticket during a session, along with a random resumption secret. The ticket is the secret and the device's long-term public key, encrypted under a key only the server knows.
+//! + The device sends the ticket, the versions it speaks and a random nonce, authenticated with a key derived from the resumption secret
+//! + The server opens the ticket, checks the authentication and answers with its own nonce and the chosen version
+//! + Both derive the new session keys from the resumption secret and a hash of everything sent, including the two nonces
+//!
+//! This takes one round trip and no key exchanges, but the new session is only as forward secret as the resumption secret: anyone who later steals the ticket key and a recorded ticket can read it.
+//!
+//! ## Pre-shared keys
+//! From version 7 the device and the server can share a secret instead of having long-term keypairs. Device message 0 carries the id of the secret in place of the id of the device's long-term key.
+//! Every session key is derived from the ephemeral key exchange mixed with the shared secret, so only someone holding the secret can authenticate server message 0 or answer the challenge.
+//! The ephemeral keys mean that a stolen secret does not reveal earlier sessions.
+//! A server which does not have the id answers under a random secret, so the device fails to authenticate server message 0 just as it would with the wrong secret. This costs the server a key exchange for every unknown id, but does not tell anyone which ids it has.
+//!
+//! ## Anonymous devices
+//! From version 8 a device without a long-term keypair can start the key exchange by sending only its ephemeral public key and the versions it speaks.
+//! The server answers as usual and still proves who it is. The device's authentication key is derived from the ephemeral key exchange instead of from its long-term key, so the server learns nothing about who the device is.
+//!
+//! ## Binding the transcript
+//! From version 9 the session keys from the full key exchange (including the anonymous one) are derived from the key exchange outputs hashed with a transcript of the handshake: whether the device is anonymous, the id of its long-term key, the versions offered and chosen, both ephemeral public keys, the id of the server's long-term key and the challenge.
+//! If any of these were changed in transit the two parties derive different keys, so the challenge response fails.
+//! Keys from a pre-shared key are bound the same way to the id of the key, the versions, both ephemeral public keys and the challenge. Resumed keys are bound to the versions, the ticket and both nonces.
+//!
+//! ## Hiding the device's identity
+//! Device message 0 gives away which device is connecting to anyone who can see it. From version 10 the device can start the key exchange like an anonymous device instead.
+//! Its final message then carries the id of its long-term key, encrypted and authenticated under the anonymous session keys, and the challenge response, encrypted under session keys derived from the anonymous session secret and the key exchange between the device's long-term key and the server's ephemeral key.
+//! Only the server can see who the device is, and only the holder of the device's long-term secret key can answer the challenge.
+//! The server only finds out who the device is from the device's final message, so it answers it: with a rejection if it does not trust the device, or otherwise with its own message 1, an authentication tag over the challenge made with the final session keys.
+//! The device waits for that answer before the session starts, and the server's session message numbers start from 2.
+//!
+//! ## Trust on first use
+//! From version 11 server message 0 carries the server's whole long-term public key in place of its id. A device which has never seen the server can still check the authentication, because only the holder of the matching secret key could have produced it, and then decide for itself whether to trust the key.
+//!
+//! ## Choosing the server's key
+//! A server may hold more than one long-term keypair, for example while its key is being replaced. From version 13 device message 0 can also carry the id of the server key the device expects, and the server answers with that key.
+//! Anonymous devices and devices hiding their identity can ask for a key in the same way, with their own device message 0 followed by the id. The opcode and the id are both part of the transcript, so changing either in transit leaves the two sides with different session keys.
+//! The device also checks that the server answered with the key it asked for. A resumption ticket records which server key the device authenticated, and the session is only resumed with that key.
+//!
+//! ## Half-closing the session
+//! From version 14 either party can send an authenticated end of stream packet once it has finished sending data. The session stays open in both directions so that the other party can still answer, for example with a digest of everything it received.
+//!
 //! ## An important note:
 //! Authentication session keys are symmetric therefore either party can impersonate the other. In an interactive setting this is not a problem because the keys are fixed to only this pair and the other side would not be expecting to receive a message authenticated using their key. However, if Bob decided to publish all his key material he could fabricate messages which look to a third party as though they are sent by Alice. This was intentional in the design of Signal's key exchange because it gives both parties plausible deniability.
 //!
@@ -42,10 +92,12 @@
 use proj_crypto::asymmetric::PublicKey;
 use proj_crypto::asymmetric::key_id;
 use std::io;
+use std::fmt;
+use std::error;
 
 #[derive(Debug)]
 pub struct Message {
-    pub number: u16,
+    pub number: u64,
     pub content: MessageContent,
 }
 
@@ -58,39 +110,332 @@ pub enum Error {
     InvalidOpcode,
     Crypto,
     PubKeyId,
-    BadPacket
+    BadPacket,
+    Version,
+    Expired,
+    PskId,
+    ServerKeyId,
 }
 
 /// The number of bytes in the random challenge sent from the server to the client
 const CHALLENGE_BYTES: usize = 32;
 
+/// The newest protocol version we speak
+///
+/// + Version 1: 16 bit message numbers
+/// + Version 2: 64 bit message numbers. Each block of 2^16 message numbers (an epoch) uses its own keys derived from the session secret
+/// + Version 3: data packets say which logical stream they belong to
+/// + Version 4: ping and pong packets for keeping idle connections alive
+/// + Version 5: errors after the key exchange are authenticated and say what went wrong. Unauthenticated error packets are ignored once the session is set up
+/// + Version 6: the server can give the device a ticket which lets it resume the session later without the full key exchange
+/// + Version 7: the key exchange can be authenticated with a pre-shared key instead of long-term keypairs
+/// + Version 8: devices without a long-term keypair can connect anonymously if the server allows it
+/// + Version 9: the session keys from the full key exchange are bound to a hash of everything sent during it
+/// + Version 10: devices can keep their identity from eavesdroppers by only sending their key id once it can be encrypted
+/// + Version 11: the server sends its whole long-term public key instead of its id, so devices can trust a server they have not seen before
+/// + Version 12: the server says why it refused a key exchange, for example because it does not trust the device's key
+/// + Version 13: devices can say which of the server's long-term keys they expect, so that a server can hold several while its key is replaced
+/// + Version 14: either party can say it has finished sending data while it carries on receiving
+pub const PROTOCOL_VERSION: u8 = 14;
+
+/// The oldest protocol version we are willing to speak
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// The key exchange happens before a version is agreed so it always uses version 1 packet headers
+pub const HANDSHAKE_VERSION: u8 = 1;
+
+/// The first protocol version with 64 bit message numbers
+pub const LONG_NUMBERS_VERSION: u8 = 2;
+
+/// The first protocol version with more than one stream
+pub const STREAMS_VERSION: u8 = 3;
+
+/// The first protocol version which understands ping and pong packets
+pub const KEEPALIVE_VERSION: u8 = 4;
+
+/// The first protocol version with authenticated session errors
+pub const SESSION_ERROR_VERSION: u8 = 5;
+
+/// The first protocol version with resumption tickets
+pub const RESUMPTION_VERSION: u8 = 6;
+
+/// The first protocol version with pre-shared keys
+pub const PSK_VERSION: u8 = 7;
+
+/// The first protocol version with anonymous devices
+pub const ANONYMOUS_VERSION: u8 = 8;
+
+/// The first protocol version which derives the session keys from the transcript of the key exchange
+pub const TRANSCRIPT_VERSION: u8 = 9;
+
+/// The first protocol version in which devices can hide their identity
+pub const HIDDEN_IDENTITY_VERSION: u8 = 10;
+
+/// The first protocol version in which the server sends its whole long-term public key
+pub const TOFU_VERSION: u8 = 11;
+
+/// The first protocol version in which the server can say why it refused a key exchange
+pub const REJECT_VERSION: u8 = 12;
+
+/// The first protocol version in which devices can say which of the server's keys they expect
+pub const KEY_SELECTION_VERSION: u8 = 13;
+
+/// The first protocol version with end of stream packets
+pub const HALF_CLOSE_VERSION: u8 = 14;
+
+/// The longest id a pre-shared key can have. The length is sent in one byte
+pub const MAX_PSK_ID_BYTES: usize = 255;
+
+/// The number of bytes in the secret which goes with a resumption ticket
+pub const RESUMPTION_SECRET_BYTES: usize = 32;
+
+/// The number of bytes in the random nonces each side contributes when a session is resumed
+pub const RESUME_NONCE_BYTES: usize = 32;
+
+/// The number of bytes in the random id at the start of a resumption ticket
+pub const TICKET_ID_BYTES: usize = 32;
+
+/// The stream used by older protocol versions and by plain reads and writes on a client or server
+pub const DEFAULT_STREAM: u16 = 0;
+
+/// The largest message number which fits in a packet header for this protocol version
+pub fn max_message_number(version: u8) -> u64 {
+    if version >= LONG_NUMBERS_VERSION {
+        u64::max_value()
+    } else {
+        u16::max_value() as u64
+    }
+}
+
+/// The epoch a message number belongs to. The symmetric state is only given the bottom 16 bits of the message number so each epoch needs different keys.
+pub fn epoch_of(message_number: u64) -> u64 {
+    message_number >> 16
+}
+
+/// The part of the message number which is given to the symmetric state as the nonce
+pub fn nonce_of(message_number: u64) -> u16 {
+    (message_number & 0xFFFF) as u16
+}
+
+/// Why a party closed the session, as carried by an authenticated error packet, or why the server refused a key exchange
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorReason {
+    /// A packet arrived with a message number which was not the next one expected
+    BadMessageNumber,
+    /// A packet failed authentication or could not be decrypted
+    AuthFailure,
+    /// The message numbers were about to overflow
+    Overflow,
+    /// The session is not allowed to continue. For example the peer is no longer authorised
+    Policy,
+    /// A packet was well formed and authentic but made no sense at that point in the protocol
+    Protocol,
+    /// The server does not trust the long-term key (or pre-shared key) the device tried to authenticate with
+    UntrustedIdentity,
+    /// The server does not hold the long-term key the device expected it to use
+    UnknownServerKey,
+    /// The peer sent more data on a stream than the application had read and we would keep for it
+    BufferFull,
+    /// A reason code we don't know about, probably from a newer version of the protocol
+    Unknown(u8),
+}
+
+impl ErrorReason {
+    /// The reason code sent on the wire
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            ErrorReason::BadMessageNumber => 1,
+            ErrorReason::AuthFailure => 2,
+            ErrorReason::Overflow => 3,
+            ErrorReason::Policy => 4,
+            ErrorReason::Protocol => 5,
+            ErrorReason::UntrustedIdentity => 6,
+            ErrorReason::UnknownServerKey => 7,
+            ErrorReason::BufferFull => 8,
+            ErrorReason::Unknown(b) => b,
+        }
+    }
+
+    /// Interpret a reason code from the wire
+    pub fn from_byte(b: u8) -> ErrorReason {
+        match b {
+            1 => ErrorReason::BadMessageNumber,
+            2 => ErrorReason::AuthFailure,
+            3 => ErrorReason::Overflow,
+            4 => ErrorReason::Policy,
+            5 => ErrorReason::Protocol,
+            6 => ErrorReason::UntrustedIdentity,
+            7 => ErrorReason::UnknownServerKey,
+            8 => ErrorReason::BufferFull,
+            _ => ErrorReason::Unknown(b),
+        }
+    }
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorReason::BadMessageNumber => write!(f, "the peer received a bad message number"),
+            ErrorReason::AuthFailure => write!(f, "a packet failed authentication at the peer"),
+            ErrorReason::Overflow => write!(f, "the peer ran out of message numbers"),
+            ErrorReason::Policy => write!(f, "the peer refused to continue the session"),
+            ErrorReason::Protocol => write!(f, "the peer received an unexpected packet"),
+            ErrorReason::UntrustedIdentity => write!(f, "the peer does not trust our identity"),
+            ErrorReason::UnknownServerKey => write!(f, "the server does not have the key we expected"),
+            ErrorReason::BufferFull => write!(f, "we sent more on a stream than the peer would keep for its application"),
+            ErrorReason::Unknown(b) => write!(f, "the peer reported an unknown error ({})", b),
+        }
+    }
+}
+
+/// So that the reason can be carried inside an io::Error
+impl error::Error for ErrorReason {}
+
+/// Is this a key exchange packet which the peer sends again over datagram transports when it thinks our answer was lost?
+/// The server repeats server_first when it has no device_second, and the device repeats resume or hidden_device_second when it has no answer.
+pub fn repeats_handshake(opcode: u8, i_am_device: bool) -> bool {
+    if i_am_device {
+        (opcode == opcodes::SERVER_FIRST) || (opcode == opcodes::KEYED_SERVER_FIRST) || (opcode == opcodes::PSK_SERVER_FIRST)
+    } else {
+        (opcode == opcodes::RESUME) || (opcode == opcodes::HIDDEN_DEVICE_SECOND)
+    }
+}
+
+/// The range of protocol versions offered by the device
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionOffer {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl VersionOffer {
+    /// Everything we speak
+    pub fn ours() -> VersionOffer {
+        VersionOffer { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION }
+    }
+
+    /// What a device which predates version negotiation speaks
+    pub fn unversioned() -> VersionOffer {
+        VersionOffer { min: HANDSHAKE_VERSION, max: HANDSHAKE_VERSION }
+    }
+
+    /// The newest version which both the offer and we speak
+    pub fn choose(&self) -> Option<u8> {
+        let chosen = if self.max < PROTOCOL_VERSION {
+            self.max
+        } else {
+            PROTOCOL_VERSION
+        };
+
+        if (chosen < self.min) || (chosen < MIN_PROTOCOL_VERSION) {
+            None
+        } else {
+            Some(chosen)
+        }
+    }
+
+    /// Check the version chosen by the server, along with its copy of our offer
+    pub fn check_choice(&self, echoed_offer: &VersionOffer, chosen: u8) -> bool {
+        (self == echoed_offer) && (chosen >= self.min) && (chosen <= self.max)
+    }
+}
+
 /// Representation of the information that we care about within a message
 #[derive(Debug)]
 pub enum MessageContent {
     /// Initiates the key exchange. 
-    DeviceFirst(PublicKey, key_id::PublicKeyId),
+    DeviceFirst(PublicKey, key_id::PublicKeyId, VersionOffer),
+
+    /// Initiates the key exchange from a device which predates version negotiation. Like DeviceFirst offering only version 1. The answer carries no versions either.
+    UnversionedDeviceFirst(PublicKey, key_id::PublicKeyId),
+
+    /// Initiates the key exchange with a server which may have several long-term keys. Like DeviceFirst followed by the id of the server key the device expects.
+    TargetedDeviceFirst(PublicKey, key_id::PublicKeyId, key_id::PublicKeyId, VersionOffer),
 
-    /// Second message in the key exchange. First public key is for the session, the second is long-term
-    ServerFirst(PublicKey, [u8; CHALLENGE_BYTES], PublicKey),
+    /// Second message in the key exchange. First public key is for the session, the second is long-term. Then the chosen protocol version and the server's copy of the device's offer.
+    ServerFirst(PublicKey, [u8; CHALLENGE_BYTES], PublicKey, u8, VersionOffer),
 
     /// Final message in a successful key exchange
     DeviceSecond,
 
+    /// Initiates a key exchange authenticated by a pre-shared key. The device's ephemeral public key, the id of the pre-shared key and the versions the device speaks.
+    PskDeviceFirst(PublicKey, Vec<u8>, VersionOffer),
+
+    /// Initiates a key exchange for a device without a long-term identity. The device's ephemeral public key and the versions it speaks. Answered with ServerFirst.
+    AnonymousDeviceFirst(PublicKey, Option<key_id::PublicKeyId>, VersionOffer),
+
+    /// Initiates a key exchange in which the device only says who it is in HiddenDeviceSecond. The device's ephemeral public key, the id of the server key it expects (if it asked for one) and the versions it speaks. Answered with ServerFirst.
+    HiddenDeviceFirst(PublicKey, Option<key_id::PublicKeyId>, VersionOffer),
+
+    /// Final message in a key exchange started with HiddenDeviceFirst. The long-term public key of the device, which has answered the challenge.
+    HiddenDeviceSecond(PublicKey),
+
+    /// A HiddenDeviceSecond carrying the id of a key the server does not trust. The server answers it with Rejected.
+    UntrustedHiddenDevice,
+
+    /// The server's answer to HiddenDeviceSecond when it trusts the device. Authenticated with the final session keys.
+    HiddenServerSecond,
+
+    /// The server's answer to PskDeviceFirst. Its ephemeral public key, the challenge, the chosen version and its copy of the device's offer.
+    /// Answered with DeviceSecond.
+    PskServerFirst(PublicKey, [u8; CHALLENGE_BYTES], u8, VersionOffer),
+
+    /// The server refused the key exchange, and why. From version 12 this replaces Error when the server has a reason to give.
+    /// Not authenticated either: the device may not know the server's key, and an attacker who can forge this could just as well drop the packets.
+    Rejected(ErrorReason),
+
     /// Destroys the connection and logs an error. Unsigned so that it works before we have keys exchanged.
     /// An active man in the middle attacker could spam this message for DoS but they could also just drop the packets so I don't *think* this is a problem?
+    /// From version 5 this is only used during the key exchange.
     Error,
 
-    /// Actually send data from one party to the other.
-    Message(Vec<u8>),
+    /// Destroys the connection after the key exchange, saying why. Authenticated so that nobody else can close the session.
+    SessionError(ErrorReason),
+
+    /// Actually send data from one party to the other, on the given stream.
+    Message(u16, Vec<u8>),
 
-//    /// Acknowledge receipt of a message
-//    Ack(u16),
+    /// The same as Message except that the receiver answers with an Ack once it has the data.
+    /// Acknowledgements are only sent when asked for so that a party which never reads does not have them pile up.
+    TrackedMessage(u16, Vec<u8>),
 
-//    /// From server to client requesting a new key exchange. If a device wants to do this (or to respond to this) it closes this session and immediately begins a new session (and key exchange) immediately.
-//    ReKey,
+    /// Data which is too long for one packet is split up. Each piece except the last is sent as a Fragment; the last piece is an ordinary (or tracked) message on the same stream.
+    /// The receiver does not hand over the data until it has the whole thing.
+    Fragment(u16, Vec<u8>),
+
+    /// Acknowledge receipt of the tracked message packet with the given message number
+    Ack(u64),
+
+    /// Replaces the session keys without closing the connection. Carries a fresh ephemeral public key.
+    /// Whoever sends the first rekey packet sends nothing else until the other party answers with their own rekey packet.
+    /// Both parties then derive new session keys from the two ephemeral keys and start counting message numbers from 0 again.
+    ReKey(PublicKey),
+
+    /// Asks the peer to answer with a Pong so that we know it is still there. Also stops idle connections from being forgotten by NAT boxes.
+    Ping,
+
+    /// The answer to a Ping
+    Pong,
+
+    /// Gives the device the secret to go with a resumption ticket, and then the ticket itself. Only sent by the server.
+    Ticket(Vec<u8>, Vec<u8>),
+
+    /// Starts a resumed session instead of DeviceFirst. The versions the device speaks, its random nonce, the ticket and the binder: an authentication tag made with the resumption secret.
+    /// The binder can't be checked until the server has opened the ticket.
+    Resume(VersionOffer, Vec<u8>, Vec<u8>, Vec<u8>),
+
+    /// The server's answer to Resume. Its random nonce, the chosen version and its copy of the device's offer. Authenticated with the new session keys.
+    ResumeAccept(Vec<u8>, u8, VersionOffer),
 
     /// Tear down the connection without reporting an error. Requires authentication so that a man in the middle can't downgrade an error to a stop to avoid logging.
     Stop,
+
+    /// The sender will not send any more data, although it still answers pings, acknowledges tracked messages and so on. The receiver's reads return 0 once it has read everything sent before it.
+    /// Authenticated for the same reason as Stop: otherwise a man in the middle could cut the data short.
+    EndOfStream,
+
+    /// Like EndOfStream but only for the logical stream with this id. The other streams carry on as before.
+    CloseStream(u16),
 }
 
 pub mod receive;
@@ -105,6 +450,7 @@ mod tests {
     use super::receive;
     use super::Message;
     use super::MessageContent;
+    use super::{VersionOffer, ErrorReason, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, HANDSHAKE_VERSION, DEFAULT_STREAM, LONG_NUMBERS_VERSION, RESUMPTION_VERSION, RESUMPTION_SECRET_BYTES, RESUME_NONCE_BYTES, PSK_VERSION, ANONYMOUS_VERSION, TRANSCRIPT_VERSION, KEY_SELECTION_VERSION, epoch_of};
     extern crate sodiumoxide;
     use sodiumoxide::randombytes;
     use proj_crypto::asymmetric::key_exchange;
@@ -118,8 +464,8 @@ mod tests {
         // medium over which to send messages
         let mut channel: Vec<u8> = Vec::new();
 
-        send_error(&mut channel, 6000);
-        assert!(errorp(receive::general(&mut channel.as_slice(), &server_keys.from_server)));
+        assert!(send::error(&mut channel, 6000, PROTOCOL_VERSION).is_none());
+        assert!(errorp(receive::general(&mut channel.as_slice(), &server_keys.from_server, PROTOCOL_VERSION)));
     }
     
     #[test]
@@ -130,11 +476,11 @@ mod tests {
 
         let mut channel: Vec<u8> = Vec::new();
 
-        assert!(send::message(&mut channel, &message, &device_keys.from_device, 1055).is_none());
+        assert!(send::message(&mut channel, DEFAULT_STREAM, &message, &device_keys.from_device, 1055, PROTOCOL_VERSION).is_none());
 
-        let received = receive::general(&mut channel.as_slice(), &server_keys.from_device).unwrap();
+        let received = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
         let received_msg = match received.content {
-            MessageContent::Message(v) => v,
+            MessageContent::Message(_, v) => v,
             _ => panic!("that is not a message!"),
         };
 
@@ -142,15 +488,91 @@ mod tests {
         assert_eq!(received_msg, message);
     }
 
-/*    #[test]
+    #[test]
+    fn stream_message() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let message = randombytes::randombytes(64);
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::message(&mut channel, 513, &message, &device_keys.from_device, 77, PROTOCOL_VERSION).is_none());
+
+        let received = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
+        match received.content {
+            MessageContent::Message(stream, v) => {
+                assert_eq!(stream, 513);
+                assert_eq!(v, message);
+            },
+            _ => panic!("that is not a message!"),
+        };
+
+        // tampering with the stream id breaks the authentication
+        channel[9] ^= 1; // the first byte after the version 3 header
+        assert!(receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).is_err());
+
+        // older versions do not have stream ids
+        channel.clear();
+        assert!(send::message(&mut channel, DEFAULT_STREAM, &message, &device_keys.from_device, 78, LONG_NUMBERS_VERSION).is_none());
+        let received = receive::general(&mut channel.as_slice(), &server_keys.from_device, LONG_NUMBERS_VERSION).unwrap();
+        match received.content {
+            MessageContent::Message(stream, v) => {
+                assert_eq!(stream, DEFAULT_STREAM);
+                assert_eq!(v, message);
+            },
+            _ => panic!("that is not a message!"),
+        };
+    }
+
+    #[test]
+    fn tracked_message() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let message = randombytes::randombytes(20);
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::tracked_message(&mut channel, DEFAULT_STREAM, &message, &server_keys.from_server, 12, PROTOCOL_VERSION).is_none());
+
+        let received = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
+        let received_msg = match received.content {
+            MessageContent::TrackedMessage(_, v) => v,
+            _ => panic!("that is not a tracked message!"),
+        };
+
+        assert_eq!(received.number, 12);
+        assert_eq!(received_msg, message);
+    }
+
+    #[test]
+    fn fragment() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let message = randombytes::randombytes(send::MAX_PACKET_DATA);
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::fragment(&mut channel, DEFAULT_STREAM, &message, &device_keys.from_device, 40, PROTOCOL_VERSION).is_none());
+
+        let received = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
+        let received_msg = match received.content {
+            MessageContent::Fragment(_, v) => v,
+            _ => panic!("that is not a fragment!"),
+        };
+
+        assert_eq!(received.number, 40);
+        assert!(received_msg == message);
+    }
+
+    #[test]
     fn ack() {
         let (server_keys, device_keys) = do_full_exchange();
 
         let mut channel: Vec<u8> = Vec::new();
 
-        assert!(send::ack(&mut channel, 2003, &server_keys.from_server, 8).is_none());
+        assert!(send::ack(&mut channel, 2003, &server_keys.from_server, 8, PROTOCOL_VERSION).is_none());
 
-        let ack = receive::general(&mut channel.as_slice(), &device_keys.from_server).unwrap();
+        let ack = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
         let ack_num = match ack.content {
             MessageContent::Ack(n) => n,
             _ => panic!("that is not an ack"),
@@ -158,25 +580,293 @@ mod tests {
 
         assert_eq!(ack.number, 8);
         assert_eq!(ack_num, 2003);
-    }*/
+    }
 
-/*    #[test]
+    #[test]
     fn rekey() {
         let (server_keys, device_keys) = do_full_exchange();
 
         let mut channel: Vec<u8> = Vec::new();
 
-        assert!(send::rekey(&mut channel, &device_keys.from_device, 5).is_none());
+        let device_rekey_keypair = key_exchange::gen_keypair();
+        assert!(send::rekey(&mut channel, &device_rekey_keypair.0, &device_keys.from_device, 5, PROTOCOL_VERSION).is_none());
 
-        let rekey = receive::general(&mut channel.as_slice(), &server_keys.from_device).unwrap();
+        let rekey = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
 
-        match rekey.content {
-            MessageContent::ReKey => (),
+        let received_pk = match rekey.content {
+            MessageContent::ReKey(pk) => pk,
             _ => panic!("that was not a rekey"),
         };
 
         assert_eq!(rekey.number, 5);
-    }*/
+        assert_eq!(received_pk, device_rekey_keypair.0);
+
+        // both sides should derive the same new keys
+        let server_rekey_keypair = key_exchange::gen_keypair();
+        let new_device_keys = send::rekey_session_keys(&device_keys, &device_rekey_keypair, &server_rekey_keypair.0, true);
+        let new_server_keys = send::rekey_session_keys(&server_keys, &server_rekey_keypair, &received_pk, false);
+
+        channel.clear();
+        let message = randombytes::randombytes(100);
+        assert!(send::message(&mut channel, DEFAULT_STREAM, &message, &new_server_keys.from_server, 0, PROTOCOL_VERSION).is_none());
+        let received = receive::general(&mut channel.as_slice(), &new_device_keys.from_server, PROTOCOL_VERSION).unwrap();
+
+        match received.content {
+            MessageContent::Message(_, v) => assert_eq!(v, message),
+            _ => panic!("that is not a message!"),
+        };
+
+        // the old keys should no longer work
+        channel.clear();
+        assert!(send::message(&mut channel, DEFAULT_STREAM, &message, &new_device_keys.from_device, 0, PROTOCOL_VERSION).is_none());
+        assert!(receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).is_err());
+
+        // someone who only saw the rekey packets' ephemeral keys can't derive the new keys without the old session secret
+        let (other_server_keys, _) = do_full_exchange();
+        let unchained_keys = send::rekey_session_keys(&other_server_keys, &server_rekey_keypair, &received_pk, false);
+        assert!(unchained_keys.secret != new_server_keys.secret);
+    }
+
+    #[test]
+    fn long_message_numbers() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let message = randombytes::randombytes(50);
+        // in the third epoch
+        let message_number = (2 << 16) + 7;
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        let device_epoch_state = send::epoch_state(&device_keys, true, epoch_of(message_number));
+        assert!(send::message(&mut channel, DEFAULT_STREAM, &message, &device_epoch_state, message_number, PROTOCOL_VERSION).is_none());
+
+        let server_epoch_state = send::epoch_state(&server_keys, true, epoch_of(message_number));
+        let received = receive::general(&mut channel.as_slice(), &server_epoch_state, PROTOCOL_VERSION).unwrap();
+        let received_msg = match received.content {
+            MessageContent::Message(_, v) => v,
+            _ => panic!("that is not a message!"),
+        };
+
+        assert_eq!(received.number, message_number);
+        assert_eq!(received_msg, message);
+
+        // the keys for a different epoch with the same bottom 16 bits should not work
+        let wrong_epoch_state = send::epoch_state(&server_keys, true, epoch_of(message_number) + 1);
+        assert!(receive::general(&mut channel.as_slice(), &wrong_epoch_state, PROTOCOL_VERSION).is_err());
+
+        // neither should the other direction's keys
+        let wrong_direction_state = send::epoch_state(&server_keys, false, epoch_of(message_number));
+        assert!(receive::general(&mut channel.as_slice(), &wrong_direction_state, PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn ping_pong() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::ping(&mut channel, &server_keys.from_server, 31, PROTOCOL_VERSION).is_none());
+        let ping = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
+        match ping.content {
+            MessageContent::Ping => (),
+            _ => panic!("that is not a ping"),
+        };
+        assert_eq!(ping.number, 31);
+
+        channel.clear();
+        assert!(send::pong(&mut channel, &device_keys.from_device, 32, PROTOCOL_VERSION).is_none());
+        let pong = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
+        match pong.content {
+            MessageContent::Pong => (),
+            _ => panic!("that is not a pong"),
+        };
+        assert_eq!(pong.number, 32);
+    }
+
+    #[test]
+    fn session_error() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::session_error(&mut channel, ErrorReason::BadMessageNumber, &server_keys.from_server, 700, PROTOCOL_VERSION).is_none());
+
+        let error = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
+        match error.content {
+            MessageContent::SessionError(reason) => assert_eq!(reason, ErrorReason::BadMessageNumber),
+            _ => panic!("that is not a session error"),
+        };
+        assert_eq!(error.number, 700);
+
+        // nobody else can change the reason
+        let last = channel.len() - 1;
+        channel[last] ^= 1;
+        assert!(receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).is_err());
+
+        // reason codes survive the trip to the wire and back
+        for reason in [ErrorReason::BadMessageNumber, ErrorReason::AuthFailure, ErrorReason::Overflow, ErrorReason::Policy, ErrorReason::Protocol, ErrorReason::UntrustedIdentity, ErrorReason::UnknownServerKey, ErrorReason::BufferFull, ErrorReason::Unknown(200)].iter() {
+            assert_eq!(ErrorReason::from_byte(reason.to_byte()), *reason);
+        }
+    }
+
+    #[test]
+    fn rejection() {
+        let device_session_keypair = key_exchange::gen_keypair();
+        let trusted_pks = HashMap::new();
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::reject(&mut channel, ErrorReason::UntrustedIdentity, 0).is_none());
+
+        match receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap().content {
+            MessageContent::Rejected(reason) => assert_eq!(reason, ErrorReason::UntrustedIdentity),
+            _ => panic!("that is not a rejection"),
+        };
+
+        match receive::psk_server_first(&mut channel.as_slice(), &device_session_keypair, &[0; 32], b"device 7").unwrap().content {
+            MessageContent::Rejected(reason) => assert_eq!(reason, ErrorReason::UntrustedIdentity),
+            _ => panic!("that is not a rejection"),
+        };
+    }
+
+    #[test]
+    fn targeted_device_first() {
+        sodiumoxide::init();
+        let device_long_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+        let mut channel: Vec<u8> = Vec::new();
+
+        let device_session_keypair = send::targeted_device_first(&mut channel, &device_long_keypair.0, &id_of_pk(&server_long_keypair.0), &VersionOffer::ours()).unwrap();
+
+        let device_first = receive::receive_device_first(&mut channel.as_slice()).unwrap();
+        match device_first.content {
+            MessageContent::TargetedDeviceFirst(pk, device_id, server_id, offer) => {
+                assert_eq!(pk, device_session_keypair.0);
+                assert_eq!(device_id, id_of_pk(&device_long_keypair.0));
+                assert_eq!(server_id, id_of_pk(&server_long_keypair.0));
+                assert_eq!(offer, VersionOffer::ours());
+            },
+            _ => panic!("that is not a targeted device first packet"),
+        };
+        assert_eq!(device_first.number, 0);
+    }
+
+    #[test]
+    fn unversioned_device_first() {
+        sodiumoxide::init();
+        let device_long_keypair = key_exchange::gen_keypair();
+        let device_session_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+
+        // as sent by a device from before version negotiation: the DEVICE_FIRST opcode, message number 0, then the keys
+        let mut channel: Vec<u8> = vec![1, 0, 0];
+        channel.extend_from_slice(&device_session_keypair.0[..]);
+        channel.extend_from_slice(&id_of_pk(&device_long_keypair.0).digest[..]);
+
+        match receive::receive_device_first(&mut channel.as_slice()).unwrap().content {
+            MessageContent::UnversionedDeviceFirst(pk, device_id) => {
+                assert_eq!(pk, device_session_keypair.0);
+                assert_eq!(device_id, id_of_pk(&device_long_keypair.0));
+            },
+            _ => panic!("that is not an unversioned device first packet"),
+        };
+
+        // the answer is the same as a version 1 answer to a versioned device, without the versions
+        let mut unversioned = Vec::new();
+        let _ = send::unversioned_server_first(&mut unversioned, &server_long_keypair, &device_session_keypair.0, &device_long_keypair.0).unwrap();
+        let mut versioned = Vec::new();
+        let _ = send::server_first(&mut versioned, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), None, HANDSHAKE_VERSION, &VersionOffer::ours()).unwrap();
+        assert_eq!(unversioned[0], versioned[0]);
+        assert_eq!(unversioned.len() + 3, versioned.len());
+    }
+
+    #[test]
+    fn ticket() {
+        let (server_keys, device_keys) = do_full_exchange();
+        let device_long_pk = key_exchange::gen_keypair().0;
+        let ticket_key = randombytes::randombytes(32);
+        let secret = randombytes::randombytes(RESUMPTION_SECRET_BYTES);
+
+        let server_long_pk_id = id_of_pk(&key_exchange::gen_keypair().0);
+
+        let sealed = send::seal_ticket(&ticket_key, &secret, &device_long_pk, &server_long_pk_id, 1234);
+
+        let mut channel: Vec<u8> = Vec::new();
+        assert!(send::ticket(&mut channel, &secret, &sealed, &server_keys.from_server, 3, PROTOCOL_VERSION).is_none());
+
+        let m = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
+        let received_ticket = match m.content {
+            MessageContent::Ticket(s, t) => {
+                assert_eq!(s, secret);
+                t },
+            _ => panic!("that is not a ticket"),
+        };
+
+        // only the server can open it
+        let (opened_secret, opened_pk, opened_server_id, expiry) = receive::open_ticket(&ticket_key, &received_ticket).unwrap();
+        assert_eq!(opened_secret, secret);
+        assert!(opened_pk == device_long_pk);
+        assert!(opened_server_id == server_long_pk_id);
+        assert_eq!(expiry, 1234);
+
+        assert!(receive::open_ticket(&randombytes::randombytes(32), &received_ticket).is_none());
+
+        let mut tampered = received_ticket.clone();
+        tampered[40] ^= 1;
+        assert!(receive::open_ticket(&ticket_key, &tampered).is_none());
+    }
+
+    #[test]
+    fn resume() {
+        let secret = randombytes::randombytes(RESUMPTION_SECRET_BYTES);
+        let ticket = randombytes::randombytes(100);
+        let offer = VersionOffer { min: RESUMPTION_VERSION, max: PROTOCOL_VERSION };
+
+        let mut channel: Vec<u8> = Vec::new();
+        let device_nonce = send::resume(&mut channel, &secret, &ticket, &offer).unwrap();
+
+        let m = receive::receive_device_first(&mut channel.as_slice()).unwrap();
+        let (received_offer, received_nonce, received_ticket, binder) = match m.content {
+            MessageContent::Resume(o, n, t, b) => (o, n, t, b),
+            _ => panic!("that is not a resume packet"),
+        };
+        assert_eq!(received_offer, offer);
+        assert_eq!(received_nonce, device_nonce);
+        assert_eq!(received_ticket, ticket);
+
+        // the binder shows that the device knows the secret
+        assert!(receive::check_binder(&secret, &received_offer, &received_nonce, &received_ticket, &binder));
+        assert!(!receive::check_binder(&randombytes::randombytes(RESUMPTION_SECRET_BYTES), &received_offer, &received_nonce, &received_ticket, &binder));
+        let downgraded = VersionOffer { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION };
+        assert!(!receive::check_binder(&secret, &downgraded, &received_nonce, &received_ticket, &binder));
+
+        // answer it
+        let mut answer: Vec<u8> = Vec::new();
+        let server_keys = send::resume_accept(&mut answer, &secret, &received_ticket, &received_nonce, PROTOCOL_VERSION, &received_offer).unwrap();
+
+        let m = receive::resume_accept(&mut answer.as_slice(), &secret, &ticket, &device_nonce).unwrap();
+        let server_nonce = match m.content {
+            MessageContent::ResumeAccept(n, v, echoed_offer) => {
+                assert_eq!(v, PROTOCOL_VERSION);
+                assert_eq!(echoed_offer, offer);
+                n },
+            _ => panic!("that is not a resume accept packet"),
+        };
+
+        // the answer is only good for our nonce
+        assert!(receive::resume_accept(&mut answer.as_slice(), &secret, &ticket, &randombytes::randombytes(RESUME_NONCE_BYTES)).is_err());
+        // and for the ticket the device sent
+        assert!(receive::resume_accept(&mut answer.as_slice(), &secret, &randombytes::randombytes(100), &device_nonce).is_err());
+
+        // both sides end up with the same keys
+        let device_keys = send::resumed_session_keys(&secret, &send::resume_transcript_hash(&ticket, &device_nonce, &server_nonce, PROTOCOL_VERSION, &offer));
+        let mut channel: Vec<u8> = Vec::new();
+        assert!(send::message(&mut channel, DEFAULT_STREAM, b"resumed", &device_keys.from_device, 1, PROTOCOL_VERSION).is_none());
+        let m = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
+        match m.content {
+            MessageContent::Message(_, v) => assert_eq!(v, b"resumed".to_vec()),
+            _ => panic!("that is not a message"),
+        };
+    }
 
     #[test]
     fn stop() {
@@ -184,9 +874,9 @@ mod tests {
 
         let mut channel: Vec<u8> = Vec::new();
 
-        assert!(send::stop(&mut channel, &server_keys.from_server, 5000).is_none());
+        assert!(send::stop(&mut channel, &server_keys.from_server, 5000, PROTOCOL_VERSION).is_none());
 
-        let stop = receive::general(&mut channel.as_slice(), &device_keys.from_server).unwrap();
+        let stop = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
 
         match stop.content {
             MessageContent::Stop => (),
@@ -197,11 +887,329 @@ mod tests {
 
     }
 
+    #[test]
+    fn end_of_stream() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::end_of_stream(&mut channel, &device_keys.from_device, 81, PROTOCOL_VERSION).is_none());
+
+        let end = receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap();
+
+        match end.content {
+            MessageContent::EndOfStream => (),
+            _ => panic!("that was not an end of stream packet"),
+        };
+
+        assert_eq!(end.number, 81);
+
+        // a stop packet's contents under the end of stream opcode
+        channel[0] = 7;
+        assert!(receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn close_stream() {
+        let (server_keys, device_keys) = do_full_exchange();
+
+        let mut channel: Vec<u8> = Vec::new();
+
+        assert!(send::close_stream(&mut channel, 513, &server_keys.from_server, 82, PROTOCOL_VERSION).is_none());
+
+        let close = receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).unwrap();
+
+        match close.content {
+            MessageContent::CloseStream(stream) => assert_eq!(stream, 513),
+            _ => panic!("that was not a close stream packet"),
+        };
+
+        assert_eq!(close.number, 82);
+
+        // the stream id is authenticated
+        let last = channel.len() - 1;
+        channel[last] ^= 1;
+        assert!(receive::general(&mut channel.as_slice(), &device_keys.from_server, PROTOCOL_VERSION).is_err());
+    }
+
     #[test]
     fn full_exchange() {
         let _ = do_full_exchange();
     }
 
+    #[test]
+    fn version_negotiation() {
+        let ours = VersionOffer::ours();
+        assert_eq!(ours.choose(), Some(PROTOCOL_VERSION));
+
+        // a device which only speaks versions newer than ours
+        let too_new = VersionOffer { min: PROTOCOL_VERSION + 1, max: PROTOCOL_VERSION + 3 };
+        assert_eq!(too_new.choose(), None);
+
+        // a device which speaks everything up to something newer than ours gets our newest version
+        let newer = VersionOffer { min: 0, max: PROTOCOL_VERSION + 1 };
+        assert_eq!(newer.choose(), Some(PROTOCOL_VERSION));
+
+        // a man in the middle altering the offer to force an older version
+        let tampered = VersionOffer { min: 0, max: ours.min };
+        assert!(!ours.check_choice(&tampered, ours.min));
+
+        // a server choosing something we never offered
+        assert!(!ours.check_choice(&ours, ours.max + 1));
+    }
+
+    #[test]
+    fn tampered_version_offer() {
+        let device_long_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(id_of_pk(&server_long_keypair.0), server_long_keypair.0.clone());
+
+        let mut channel: Vec<u8> = Vec::new();
+        let device_session_keypair = send::device_first(&mut channel, &device_long_keypair.0, &VersionOffer::ours()).unwrap();
+        channel.clear();
+
+        // the server answers an offer which is not the one the device sent
+        let tampered = VersionOffer { min: 0, max: 0 };
+        let _ = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), None, 0, &tampered).unwrap();
+        let server_first = receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap();
+
+        match server_first.content {
+            MessageContent::ServerFirst(_, _, _, chosen, echoed) => assert!(!VersionOffer::ours().check_choice(&echoed, chosen)),
+            _ => panic!("receive::server_first returned the wrong message type!"),
+        };
+
+        // flipping the chosen version in transit breaks the authentication
+        channel.clear();
+        let _ = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), None, PROTOCOL_VERSION, &VersionOffer::ours()).unwrap();
+        let version_index = channel.len() - 3;
+        channel[version_index] ^= 1;
+        assert!(receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).is_err());
+    }
+
+    #[test]
+    fn transcript_binding() {
+        let device_long_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(id_of_pk(&server_long_keypair.0), server_long_keypair.0.clone());
+
+        // the server sees a different offer to the one the device sent. Only the transcript catches this if the device doesn't check the echoed offer
+        for &(version, keys_match) in &[(ANONYMOUS_VERSION, true), (TRANSCRIPT_VERSION, false)] {
+            let sent = VersionOffer { min: MIN_PROTOCOL_VERSION, max: version };
+            let tampered = VersionOffer { min: version, max: version };
+
+            let mut channel: Vec<u8> = Vec::new();
+            let device_session_keypair = send::device_first(&mut channel, &device_long_keypair.0, &sent).unwrap();
+            channel.clear();
+
+            let (server_keys, challenge) = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), None, version, &tampered).unwrap();
+            let (server_session_pk, received_challenge) = match receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap().content {
+                MessageContent::ServerFirst(pk, c, _, _, _) => (pk, c),
+                _ => panic!("that is not a server_first packet"),
+            };
+
+            channel.clear();
+            let _ = send::device_second(&mut channel, &server_long_keypair.0, &server_session_pk, &received_challenge, Some(&device_long_keypair), None, &device_session_keypair, version, &sent).unwrap();
+            assert_eq!(receive::device_second(&mut channel.as_slice(), &server_keys, &challenge).is_ok(), keys_match);
+        }
+    }
+
+    #[test]
+    fn requested_server_key_binding() {
+        let device_long_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+        let server_id = id_of_pk(&server_long_keypair.0);
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(server_id.clone(), server_long_keypair.0.clone());
+        let offer = VersionOffer::ours();
+
+        // the device asked for the key the server answers with, but the server saw an ordinary device_first (or the other way round)
+        for &(device_asked, server_saw) in &[(true, false), (false, true), (true, true)] {
+            let device_request = if device_asked { Some(&server_id) } else { None };
+            let server_request = if server_saw { Some(&server_id) } else { None };
+
+            let mut channel: Vec<u8> = Vec::new();
+            let device_session_keypair = send::device_first(&mut channel, &device_long_keypair.0, &offer).unwrap();
+            channel.clear();
+
+            let (server_keys, challenge) = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), server_request, PROTOCOL_VERSION, &offer).unwrap();
+            let (server_session_pk, received_challenge) = match receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap().content {
+                MessageContent::ServerFirst(pk, c, _, _, _) => (pk, c),
+                _ => panic!("that is not a server_first packet"),
+            };
+
+            channel.clear();
+            let _ = send::device_second(&mut channel, &server_long_keypair.0, &server_session_pk, &received_challenge, Some(&device_long_keypair), device_request, &device_session_keypair, PROTOCOL_VERSION, &offer).unwrap();
+            assert_eq!(receive::device_second(&mut channel.as_slice(), &server_keys, &challenge).is_ok(), device_asked == server_saw);
+        }
+    }
+
+    #[test]
+    fn hidden_exchange() {
+        let device_long_keypair = key_exchange::gen_keypair();
+        let server_long_keypair = key_exchange::gen_keypair();
+        let device_id = id_of_pk(&device_long_keypair.0);
+        let server_id = id_of_pk(&server_long_keypair.0);
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(server_id.clone(), server_long_keypair.0.clone());
+        trusted_pks.insert(device_id.clone(), device_long_keypair.0.clone());
+        let offer = VersionOffer { min: KEY_SELECTION_VERSION, max: PROTOCOL_VERSION };
+
+        let contains_id = |channel: &[u8]| channel.windows(32).any(|w| w == &device_id.digest[..]);
+
+        // the device also asks for the server's key, which gives nothing away about the device
+        let mut channel: Vec<u8> = Vec::new();
+        let device_session_keypair = send::hidden_device_first(&mut channel, Some(&server_id), &offer).unwrap();
+        assert!(!contains_id(&channel));
+
+        let device_session_pk = match receive::receive_device_first(&mut channel.as_slice()).unwrap().content {
+            MessageContent::HiddenDeviceFirst(pk, received_server_id, received_offer) => {
+                assert!(received_server_id == Some(server_id.clone()));
+                assert_eq!(received_offer, offer);
+                pk },
+            _ => panic!("that is not a hidden_device_first packet"),
+        };
+
+        channel.clear();
+        let (anonymous_keys, challenge, server_session_keypair) = send::hidden_server_first(&mut channel, &server_long_keypair, &device_session_pk, Some(&server_id), PROTOCOL_VERSION, &offer).unwrap();
+        let (server_session_pk, received_challenge, server_long_pk) = match receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap().content {
+            MessageContent::ServerFirst(pk, c, long_pk, _, _) => (pk, c, long_pk),
+            _ => panic!("that is not a server_first packet"),
+        };
+
+        channel.clear();
+        let device_keys = send::hidden_device_second(&mut channel, &server_long_pk, &server_session_pk, &received_challenge, &device_long_keypair, Some(&server_id), &device_session_keypair, PROTOCOL_VERSION, &offer).unwrap();
+        assert!(!contains_id(&channel));
+
+        let device_long_pk = match receive::hidden_device_second(&mut channel.as_slice(), &anonymous_keys, &server_session_keypair, &challenge, &trusted_pks).unwrap().content {
+            MessageContent::HiddenDeviceSecond(pk) => pk,
+            _ => panic!("that is not a hidden_device_second packet"),
+        };
+        assert_eq!(device_long_pk, device_long_keypair.0);
+
+        // the server can't finish if it doesn't know the device
+        let mut strangers = HashMap::new();
+        strangers.insert(id_of_pk(&server_long_keypair.0), server_long_keypair.0.clone());
+        match receive::hidden_device_second(&mut channel.as_slice(), &anonymous_keys, &server_session_keypair, &challenge, &strangers).unwrap().content {
+            MessageContent::UntrustedHiddenDevice => (),
+            _ => panic!("the server trusted a device it does not know"),
+        };
+
+        let identity_shared = key_exchange::key_exchange(&device_long_pk, &server_session_keypair.1, &server_session_keypair.0, false);
+        let server_keys = send::hidden_session_keys(&anonymous_keys, &identity_shared);
+
+        // the device waits to hear whether the server trusts it
+        channel.clear();
+        assert!(send::hidden_server_second(&mut channel, &server_keys, &challenge).is_none());
+        match receive::hidden_server_second(&mut channel.as_slice(), &device_keys, &received_challenge).unwrap().content {
+            MessageContent::HiddenServerSecond => (),
+            _ => panic!("that is not a hidden_server_second packet"),
+        };
+        assert!(receive::hidden_server_second(&mut channel.as_slice(), &anonymous_keys, &received_challenge).is_err());
+
+        channel.clear();
+        assert!(send::reject(&mut channel, ErrorReason::UntrustedIdentity, 0).is_none());
+        match receive::hidden_server_second(&mut channel.as_slice(), &device_keys, &received_challenge).unwrap().content {
+            MessageContent::Rejected(ErrorReason::UntrustedIdentity) => (),
+            _ => panic!("that is not a rejection"),
+        };
+
+        channel.clear();
+        assert!(send::message(&mut channel, DEFAULT_STREAM, b"hidden", &device_keys.from_device, 2, PROTOCOL_VERSION).is_none());
+        match receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap().content {
+            MessageContent::Message(_, v) => assert_eq!(v, b"hidden".to_vec()),
+            _ => panic!("that is not a message"),
+        };
+    }
+
+    #[test]
+    fn anonymous_exchange() {
+        let server_long_keypair = key_exchange::gen_keypair();
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(id_of_pk(&server_long_keypair.0), server_long_keypair.0.clone());
+        let offer = VersionOffer { min: ANONYMOUS_VERSION, max: PROTOCOL_VERSION };
+
+        let mut channel: Vec<u8> = Vec::new();
+        let device_session_keypair = send::anonymous_device_first(&mut channel, None, &offer).unwrap();
+
+        let device_first = receive::receive_device_first(&mut channel.as_slice()).unwrap();
+        let device_session_pk = match device_first.content {
+            MessageContent::AnonymousDeviceFirst(pk, received_server_id, received_offer) => {
+                assert!(received_server_id.is_none());
+                assert_eq!(received_offer, offer);
+                pk },
+            _ => panic!("that is not an anonymous_device_first packet"),
+        };
+
+        // the server still proves who it is
+        channel.clear();
+        let (server_keys, challenge) = send::server_first(&mut channel, &server_long_keypair, &device_session_pk, None, None, PROTOCOL_VERSION, &offer).unwrap();
+        let server_first = receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap();
+        let (server_session_pk, received_challenge, server_long_pk) = match server_first.content {
+            MessageContent::ServerFirst(pk, c, long_pk, _, _) => (pk, c, long_pk),
+            _ => panic!("that is not a server_first packet"),
+        };
+
+        channel.clear();
+        let device_keys = send::device_second(&mut channel, &server_long_pk, &server_session_pk, &received_challenge, None, None, &device_session_keypair, PROTOCOL_VERSION, &offer).unwrap();
+        match receive::device_second(&mut channel.as_slice(), &server_keys, &challenge).unwrap().content {
+            MessageContent::DeviceSecond => (),
+            _ => panic!("that is not a device_second packet"),
+        };
+
+        channel.clear();
+        assert!(send::message(&mut channel, DEFAULT_STREAM, b"anonymous", &device_keys.from_device, 2, PROTOCOL_VERSION).is_none());
+        match receive::general(&mut channel.as_slice(), &server_keys.from_device, PROTOCOL_VERSION).unwrap().content {
+            MessageContent::Message(_, v) => assert_eq!(v, b"anonymous".to_vec()),
+            _ => panic!("that is not a message"),
+        };
+    }
+
+    #[test]
+    fn psk_exchange() {
+        let psk = randombytes::randombytes(32);
+        let offer = VersionOffer { min: PSK_VERSION, max: PROTOCOL_VERSION };
+
+        let mut channel: Vec<u8> = Vec::new();
+        let device_session_keypair = send::psk_device_first(&mut channel, b"device 7", &offer).unwrap();
+
+        let device_first = receive::receive_device_first(&mut channel.as_slice()).unwrap();
+        let device_session_pk = match device_first.content {
+            MessageContent::PskDeviceFirst(pk, id, received_offer) => {
+                assert_eq!(id, b"device 7".to_vec());
+                assert_eq!(received_offer, offer);
+                pk },
+            _ => panic!("that is not a psk_device_first packet"),
+        };
+
+        channel.clear();
+        let (server_keys, challenge) = send::psk_server_first(&mut channel, &psk, b"device 7", &device_session_pk, PROTOCOL_VERSION, &offer).unwrap();
+
+        // only someone with the pre-shared key can check the server's answer
+        assert!(receive::psk_server_first(&mut channel.as_slice(), &device_session_keypair, &randombytes::randombytes(32), b"device 7").is_err());
+        // and only for the id the server saw
+        assert!(receive::psk_server_first(&mut channel.as_slice(), &device_session_keypair, &psk, b"device 8").is_err());
+
+        let server_first = receive::psk_server_first(&mut channel.as_slice(), &device_session_keypair, &psk, b"device 7").unwrap();
+        let (server_session_pk, received_challenge, version) = match server_first.content {
+            MessageContent::PskServerFirst(pk, c, v, echoed_offer) => {
+                assert!(offer.check_choice(&echoed_offer, v));
+                (pk, c, v) },
+            _ => panic!("that is not a psk_server_first packet"),
+        };
+
+        let transcript = send::psk_transcript_hash(b"device 7", &device_session_keypair.0, &server_session_pk, &received_challenge, version, &offer);
+        let device_keys = send::psk_session_keys(&psk, &key_exchange::key_exchange(&server_session_pk, &device_session_keypair.1, &device_session_keypair.0, true), &transcript);
+
+        channel.clear();
+        assert!(send::challenge_response(&mut channel, &device_keys, &received_challenge).is_none());
+        match receive::device_second(&mut channel.as_slice(), &server_keys, &challenge).unwrap().content {
+            MessageContent::DeviceSecond => (),
+            _ => panic!("that is not a device_second packet"),
+        };
+    }
+
     fn errorp(msg: Result<Message, super::Error>) -> bool {
         match msg.unwrap().content {
             MessageContent::Error => true,
@@ -209,8 +1217,8 @@ mod tests {
         }
     }
 
-    fn send_error(mut channel: &mut Vec<u8>, message_number: u16) {
-        assert!(send::error(&mut channel, message_number).is_none());
+    fn send_error(mut channel: &mut Vec<u8>, message_number: u64) {
+        assert!(send::error(&mut channel, message_number, HANDSHAKE_VERSION).is_none());
     }
 
     // also tests error packets
@@ -226,16 +1234,19 @@ mod tests {
         // device_first:
 
         // send message
-        let device_session_keypair = send::device_first(&mut channel, &device_long_keypair.0).unwrap();
+        let device_session_keypair = send::device_first(&mut channel, &device_long_keypair.0, &VersionOffer::ours()).unwrap();
 
         // receive message
         let device_first = receive::receive_device_first(&mut channel.as_slice()).unwrap();
-        let (sent_pk, device_id) = match device_first.content {
-            MessageContent::DeviceFirst(p, id) => (p, id),
+        let (sent_pk, device_id, offer) = match device_first.content {
+            MessageContent::DeviceFirst(p, id, o) => (p, id, o),
             _ => panic!("receive::device_first did not return a device first packet")
         };
 
         assert_eq!(device_id, id_of_pk(&device_long_keypair.0));
+        assert_eq!(offer, VersionOffer::ours());
+        let version = offer.choose().unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
         assert_eq!(sent_pk, device_session_keypair.0);
         assert_eq!(device_first.number, 0);
 
@@ -251,16 +1262,17 @@ mod tests {
         trusted_pks.insert(id_of_pk(&server_long_keypair.0), server_long_keypair.0.clone());
 
         // send 
-        let (server_session_keys, server_challenge) = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, &device_long_keypair.0).unwrap();
+        let (server_session_keys, server_challenge) = send::server_first(&mut channel, &server_long_keypair, &device_session_keypair.0, Some(&device_long_keypair.0), None, version, &offer).unwrap();
 
         // receive 
         let server_first = receive::server_first(&mut channel.as_slice(), &device_session_keypair, &trusted_pks).unwrap();
-        let (server_session_pub_key, challenge, server_id) = match server_first.content {
-            MessageContent::ServerFirst(x, y, z) => (x, y, z),
+        let (server_session_pub_key, challenge, server_id, chosen_version, echoed_offer) = match server_first.content {
+            MessageContent::ServerFirst(v, w, x, y, z) => (v, w, x, y, z),
             _ => panic!("receive::server_first returned the wrong message type!"),
         };
 
         assert_eq!(server_id, server_long_keypair.0);
+        assert!(VersionOffer::ours().check_choice(&echoed_offer, chosen_version));
         assert_eq!(server_challenge, challenge);
         assert_eq!(server_first.number, 0);
 
@@ -274,7 +1286,7 @@ mod tests {
         // device_second
 
         // send message
-        let device_session_keys = send::device_second(&mut channel, &server_long_keypair.0, &server_session_pub_key, &challenge, &device_long_keypair, &device_session_keypair).unwrap();
+        let device_session_keys = send::device_second(&mut channel, &server_long_keypair.0, &server_session_pub_key, &challenge, Some(&device_long_keypair), None, &device_session_keypair, chosen_version, &VersionOffer::ours()).unwrap();
 
         // receive message
         let device_second = receive::device_second(&mut channel.as_slice(), &server_session_keys, &server_challenge.as_slice()).unwrap();
@@ -22,15 +22,65 @@ pub const MAX_NOCRYPT: u8 = SERVER_FIRST;
 // range 2: stuff that does need crypto
 pub const DEVICE_SECOND: u8 = 3;
 pub const MESSAGE: u8 = 4;
-//pub const ACK: u8 = 5;
-//pub const REKEY: u8 = 6;
+pub const ACK: u8 = 5;
+pub const REKEY: u8 = 6;
 pub const STOP: u8 = 7;
+pub const TRACKED_MESSAGE: u8 = 8;
+pub const FRAGMENT: u8 = 9;
+pub const PING: u8 = 10;
+pub const PONG: u8 = 11;
+pub const SESSION_ERROR: u8 = 12;
+pub const TICKET: u8 = 13;
+
+// range 3: key exchange packets authenticated with a resumption secret instead of the long-term keys
+pub const RESUME: u8 = 14;
+pub const RESUME_ACCEPT: u8 = 15;
+
+// range 4: key exchange packets for devices and servers which share a secret instead of having long-term keypairs
+pub const PSK_DEVICE_FIRST: u8 = 16;
+pub const PSK_SERVER_FIRST: u8 = 17;
+
+// range 5: the start of a key exchange for a device without a long-term identity. The server answers with an ordinary SERVER_FIRST
+pub const ANONYMOUS_DEVICE_FIRST: u8 = 18;
+
+// range 6: a key exchange in which the device only says who it is once that can be encrypted. The server answers HIDDEN_DEVICE_FIRST with an ordinary SERVER_FIRST
+pub const HIDDEN_DEVICE_FIRST: u8 = 19;
+pub const HIDDEN_DEVICE_SECOND: u8 = 20;
+
+// range 7: SERVER_FIRST carrying the server's whole long-term public key in place of its id
+pub const KEYED_SERVER_FIRST: u8 = 21;
+
+// range 8: the server refusing a key exchange, with the reason. Not authenticated, like ERROR
+pub const REJECT: u8 = 22;
+
+// range 9: VERSIONED_DEVICE_FIRST followed by the id of the server's long-term key which the device expects. The server answers with an ordinary SERVER_FIRST
+pub const TARGETED_DEVICE_FIRST: u8 = 23;
+
+// range 10: the sender has finished sending data but can still receive. Needs crypto like STOP
+pub const END_OF_STREAM: u8 = 24;
+
+// range 11: DEVICE_FIRST preceded by the range of protocol versions the device speaks. A plain DEVICE_FIRST comes from a device which predates version negotiation and only speaks version 1
+pub const VERSIONED_DEVICE_FIRST: u8 = 25;
+
+// range 12: the sender will not write on one logical stream any more. Needs crypto
+pub const CLOSE_STREAM: u8 = 26;
+
+// range 13: the server's answer to HIDDEN_DEVICE_SECOND when it trusts the device. A REJECT if it does not
+pub const HIDDEN_SERVER_SECOND: u8 = 27;
+
+// range 14: HIDDEN_DEVICE_FIRST followed by the id of the server's long-term key which the device expects. The server answers with an ordinary SERVER_FIRST
+pub const TARGETED_HIDDEN_DEVICE_FIRST: u8 = 28;
+
+// range 15: ANONYMOUS_DEVICE_FIRST followed by the id of the server's long-term key which the device expects
+pub const TARGETED_ANONYMOUS_DEVICE_FIRST: u8 = 29;
 
 #[allow(dead_code)]
-pub const MAX_OPCODE: u8 = STOP;
+pub const MAX_OPCODE: u8 = TARGETED_ANONYMOUS_DEVICE_FIRST;
 
 // contents of constant messages
 // don't change the type of these without updating message.rs::parse_constant_contents_message()
 pub const CONST_MSG_LEN: usize = 1;
 pub const STOP_CONTENTS: u8 = 0;
-//pub const REKEY_CONTENTS: u8 = 1;
+pub const PING_CONTENTS: u8 = 1;
+pub const PONG_CONTENTS: u8 = 2;
+pub const END_OF_STREAM_CONTENTS: u8 = 3;
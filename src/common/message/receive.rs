@@ -23,12 +23,14 @@ use proj_crypto::symmetric::AUTH_TAG_BYTES;
 use proj_crypto::symmetric;
 use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::utils::memcmp;
-use super::{Message, CHALLENGE_BYTES};
+use super::{Message, VersionOffer, ErrorReason, CHALLENGE_BYTES, HANDSHAKE_VERSION, LONG_NUMBERS_VERSION, STREAMS_VERSION, DEFAULT_STREAM, RESUMPTION_SECRET_BYTES, RESUME_NONCE_BYTES, TICKET_ID_BYTES, nonce_of};
+use super::send;
 use {SessionKeys, Keypair};
+use trust::TrustStore;
 use std::collections::HashMap;
 
 pub fn receive_device_first <R: io::Read> (source: &mut R) -> Result<Message, Error> {
-    let (opcode, message_number) = match get_header(source) {
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
         Err(e) => return Err(e),
         Ok(x) => x
     };
@@ -36,35 +38,65 @@ pub fn receive_device_first <R: io::Read> (source: &mut R) -> Result<Message, Er
     parse_clear_message(source, opcode, message_number)
 }
  
-pub fn server_first <R: io::Read> (source: &mut R, session_keypair: &Keypair, trusted_pks: &HashMap<PublicKeyId, PublicKey>) -> Result<Message, Error> {
+pub fn server_first <R: io::Read, T: TrustStore> (source: &mut R, session_keypair: &Keypair, trusted_pks: &T) -> Result<Message, Error> {
+    parse_server_first(source, session_keypair, Some(trusted_pks))
+}
+
+/// Like server_first but accepts any long-term key which the server proves it holds. It is up to the caller to decide whether to trust it.
+/// This only works from TOFU_VERSION because older servers only send the id of their key.
+pub fn server_first_any_key <R: io::Read> (source: &mut R, session_keypair: &Keypair) -> Result<Message, Error> {
+    parse_server_first(source, session_keypair, None::<&HashMap<PublicKeyId, PublicKey>>)
+}
+
+/// trusted_pks is None to accept any key in a KEYED_SERVER_FIRST packet
+fn parse_server_first <R: io::Read, T: TrustStore> (source: &mut R, session_keypair: &Keypair, trusted_pks: Option<&T>) -> Result<Message, Error> {
     let (ref pk_session, ref sk_session) = *session_keypair;
-    let (opcode, message_number) = match get_header(source) {
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
         Err(e) => return Err(e),
         Ok(x) => x
     };
 
     if opcode == opcodes::ERROR {
         Ok(Message { number: message_number, content: MessageContent::Error })
-    } else if opcode == opcodes::SERVER_FIRST {
+    } else if opcode == opcodes::REJECT {
+        rejection(source, message_number)
+    } else if (opcode == opcodes::SERVER_FIRST) || (opcode == opcodes::KEYED_SERVER_FIRST) {
         if message_number != 0 {
             return Err(Error::BadPacket);
         }
 
         // get the content section of the message
-        let buff = match get_n_bytes(source, PUBLIC_KEY_BYTES + CHALLENGE_BYTES + 32 + AUTH_TAG_BYTES) { // the 32 is for the key id
+        let buff = match get_n_bytes(source, PUBLIC_KEY_BYTES + CHALLENGE_BYTES + 3 + 32 + AUTH_TAG_BYTES) { // the 3 is for the versions, the 32 is for the key id or the key
             Err(e) => return Err(e),
             Ok(x) => x,
         };
 
-        // get the key id 
-        let (key_id_bytes, authenticated_bit) = buff.split_at(32);
-        let key_id = PublicKeyId {
-            digest: sha256::Digest::from_slice(key_id_bytes).unwrap(),
-        };
+        let (long_key_field, authenticated_bit) = buff.split_at(32);
+
+        let server_long_pk = if opcode == opcodes::KEYED_SERVER_FIRST {
+            let pk = public_key_from_slice(long_key_field).unwrap();
+            match trusted_pks {
+                None => pk,
+                Some(trusted_pks) => match trusted_pks.lookup(&id_of_pk(&pk)) {
+                    Some(ref trusted) if *trusted == pk => pk,
+                    _ => return Err(Error::PubKeyId),
+                },
+            }
+        } else {
+            // get the key id
+            let key_id = PublicKeyId {
+                digest: sha256::Digest::from_slice(long_key_field).unwrap(),
+            };
+
+            let found = match trusted_pks {
+                Some(trusted_pks) => trusted_pks.lookup(&key_id),
+                None => None,
+            };
 
-        let server_long_pk = match find_public_key(&key_id, trusted_pks) {
-            None => return Err(Error::PubKeyId),
-            Some(pk) => pk,
+            match found {
+                None => return Err(Error::PubKeyId),
+                Some(pk) => pk,
+            }
         };
 
         // separate the authentication tag from the message and check that it is correct
@@ -75,13 +107,14 @@ pub fn server_first <R: io::Read> (source: &mut R, session_keypair: &Keypair, tr
         let server_authenticator = symmetric::State::new(&from_server_auth.as_slice(), &from_server_auth.as_slice()); // we don't use or have encryption keys at this point
 
         // verify authentication tag
-        if !server_authenticator.verify_auth_tag(auth_tag, the_rest, message_number) {
+        if !server_authenticator.verify_auth_tag(auth_tag, the_rest, nonce_of(message_number)) {
             return Err(Error::Crypto);
         } // else continue...
         
         // parse the message
-        let (pub_key_bytes, challenge) = the_rest.split_at(PUBLIC_KEY_BYTES);
+        let (pub_key_bytes, the_rest) = the_rest.split_at(PUBLIC_KEY_BYTES);
         let pub_key = public_key_from_slice(pub_key_bytes).unwrap();
+        let (challenge, versions) = the_rest.split_at(CHALLENGE_BYTES);
 
         // the rust compiler is not smart enough to notice that challenge always has length 32 so we are going to have to waste some time
         let mut challenge_sized: [u8; CHALLENGE_BYTES] = [0; CHALLENGE_BYTES];
@@ -89,7 +122,9 @@ pub fn server_first <R: io::Read> (source: &mut R, session_keypair: &Keypair, tr
             challenge_sized[i] = challenge[i];
         }
 
-        Ok(Message{ number: message_number, content: MessageContent::ServerFirst(pub_key, challenge_sized, server_long_pk) })
+        let echoed_offer = VersionOffer { min: versions[1], max: versions[2] };
+
+        Ok(Message{ number: message_number, content: MessageContent::ServerFirst(pub_key, challenge_sized, server_long_pk, versions[0], echoed_offer) })
     } else {
         Err(Error::InvalidOpcode)
     }
@@ -97,7 +132,7 @@ pub fn server_first <R: io::Read> (source: &mut R, session_keypair: &Keypair, tr
 
 pub fn device_second <R: io::Read> (source: &mut R, session_keys: &SessionKeys, challenge: &[u8]) -> Result<Message, Error> {
     assert_eq!(challenge.len(), CHALLENGE_BYTES);
-    let (opcode, message_number) = match get_header(source) {
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
         Err(e) => return Err(e),
         Ok(x) => x
     };
@@ -114,7 +149,7 @@ pub fn device_second <R: io::Read> (source: &mut R, session_keys: &SessionKeys,
             Ok(x) => x,
         };
 
-        let challenge_recvd = match session_keys.from_device.authenticated_decryption(&contents, message_number) {
+        let challenge_recvd = match session_keys.from_device.authenticated_decryption(&contents, nonce_of(message_number)) {
             None => return Err(Error::Crypto),
             Some(c) => c,
         };
@@ -129,17 +164,240 @@ pub fn device_second <R: io::Read> (source: &mut R, session_keys: &SessionKeys,
     }
 }
 
-pub fn general <R: io::Read> (source: &mut R, session_keys: &symmetric::State) -> Result<Message, Error> {
-    let (opcode, message_number) = match get_header(source) {
+/// Receive the last packet of a key exchange started with hidden_device_first. anonymous_keys and session_keypair are the ones from hidden_server_first.
+/// The device's long-term public key is only returned once it has answered the challenge.
+pub fn hidden_device_second <R: io::Read, T: TrustStore> (source: &mut R, anonymous_keys: &SessionKeys, session_keypair: &Keypair, challenge: &[u8], trusted_pks: &T) -> Result<Message, Error> {
+    assert_eq!(challenge.len(), CHALLENGE_BYTES);
+    let (ref pk_session, ref sk_session) = *session_keypair;
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
+        Err(e) => return Err(e),
+        Ok(x) => x
+    };
+
+    if opcode == opcodes::ERROR {
+        return Ok(Message{ number: message_number, content: MessageContent::Error });
+    } else if opcode != opcodes::HIDDEN_DEVICE_SECOND {
+        return Err(Error::InvalidOpcode);
+    }
+
+    if message_number != 1 {
+        return Err(Error::BadPacket);
+    }
+
+    let contents = match get_n_bytes(source, 32 + AUTH_TAG_BYTES + CHALLENGE_BYTES + AUTH_TAG_BYTES) { // the 32 is for the key id
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+    let (encrypted_id, ciphertext) = contents.split_at(32 + AUTH_TAG_BYTES);
+
+    // who is it?
+    let key_id_bytes = match anonymous_keys.from_device.authenticated_decryption(encrypted_id, nonce_of(message_number)) {
+        None => return Err(Error::Crypto),
+        Some(id) => id,
+    };
+    let key_id = PublicKeyId {
+        digest: sha256::Digest::from_slice(&key_id_bytes).unwrap(),
+    };
+
+    // the server answers this with a rejection rather than an error, so it is not an Err
+    let device_long_pk = match trusted_pks.lookup(&key_id) {
+        None => return Ok(Message{ number: message_number, content: MessageContent::UntrustedHiddenDevice }),
+        Some(pk) => pk,
+    };
+
+    // check that they hold its secret key
+    let identity_shared = key_exchange(&device_long_pk, sk_session, pk_session, false);
+    let session_keys = send::hidden_session_keys(anonymous_keys, &identity_shared);
+
+    let challenge_recvd = match session_keys.from_device.authenticated_decryption(ciphertext, nonce_of(message_number)) {
+        None => return Err(Error::Crypto),
+        Some(c) => c,
+    };
+
+    if memcmp(&challenge_recvd, challenge) {
+        Ok(Message{ number: message_number, content: MessageContent::HiddenDeviceSecond(device_long_pk) })
+    } else {
+        Err(Error::Crypto)
+    }
+}
+
+/// Receive the server's answer to hidden_device_second. session_keys are the ones returned by send::hidden_device_second
+pub fn hidden_server_second <R: io::Read> (source: &mut R, session_keys: &SessionKeys, challenge: &[u8]) -> Result<Message, Error> {
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
+        Err(e) => return Err(e),
+        Ok(x) => x
+    };
+
+    if opcode == opcodes::ERROR {
+        return Ok(Message{ number: message_number, content: MessageContent::Error });
+    } else if opcode == opcodes::REJECT {
+        return rejection(source, message_number);
+    } else if opcode != opcodes::HIDDEN_SERVER_SECOND {
+        return Err(Error::InvalidOpcode);
+    }
+
+    if message_number != 1 {
+        return Err(Error::BadPacket);
+    }
+
+    let auth_tag = match get_n_bytes(source, AUTH_TAG_BYTES) {
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+
+    if session_keys.from_server.verify_auth_tag(&auth_tag, challenge, nonce_of(message_number)) {
+        Ok(Message{ number: message_number, content: MessageContent::HiddenServerSecond })
+    } else {
+        Err(Error::Crypto)
+    }
+}
+
+/// The rest of a REJECT packet: why the server refused the key exchange
+fn rejection <R: io::Read> (source: &mut R, message_number: u64) -> Result<Message, Error> {
+    match get_n_bytes(source, 1) {
+        Err(e) => Err(e),
+        Ok(reason) => Ok(Message{ number: message_number, content: MessageContent::Rejected(ErrorReason::from_byte(reason[0])) }),
+    }
+}
+
+/// Receive the server's answer to a key exchange authenticated by a pre-shared key
+pub fn psk_server_first <R: io::Read> (source: &mut R, session_keypair: &Keypair, psk: &[u8], psk_id: &[u8]) -> Result<Message, Error> {
+    let (ref pk_session, ref sk_session) = *session_keypair;
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
+        Err(e) => return Err(e),
+        Ok(x) => x
+    };
+
+    if opcode == opcodes::ERROR {
+        return Ok(Message { number: message_number, content: MessageContent::Error });
+    } else if opcode == opcodes::REJECT {
+        return rejection(source, message_number);
+    } else if opcode != opcodes::PSK_SERVER_FIRST {
+        return Err(Error::InvalidOpcode);
+    }
+
+    if message_number != 0 {
+        return Err(Error::BadPacket);
+    }
+
+    let buff = match get_n_bytes(source, AUTH_TAG_BYTES + PUBLIC_KEY_BYTES + CHALLENGE_BYTES + 3) { // the 3 is for the versions
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+
+    let (auth_tag, the_rest) = buff.split_at(AUTH_TAG_BYTES);
+    let (pub_key_bytes, challenge_and_versions) = the_rest.split_at(PUBLIC_KEY_BYTES);
+    let server_session_pk = match public_key_from_slice(pub_key_bytes) {
+        None => return Err(Error::BadPacket),
+        Some(pk) => pk,
+    };
+
+    let (challenge, versions) = challenge_and_versions.split_at(CHALLENGE_BYTES);
+    let echoed_offer = VersionOffer { min: versions[1], max: versions[2] };
+
+    // the tag is made with the session keys so derive them first
+    let transcript = send::psk_transcript_hash(psk_id, pk_session, &server_session_pk, challenge, versions[0], &echoed_offer);
+    let session_keys = send::psk_session_keys(psk, &key_exchange(&server_session_pk, sk_session, pk_session, true), &transcript);
+    if !session_keys.from_server.verify_auth_tag(auth_tag, the_rest, nonce_of(message_number)) {
+        return Err(Error::Crypto);
+    }
+
+    let mut challenge_sized: [u8; CHALLENGE_BYTES] = [0; CHALLENGE_BYTES];
+    challenge_sized.copy_from_slice(challenge);
+
+    Ok(Message{ number: message_number, content: MessageContent::PskServerFirst(server_session_pk, challenge_sized, versions[0], echoed_offer) })
+}
+
+/// Receive the server's answer to our resume packet
+pub fn resume_accept <R: io::Read> (source: &mut R, resumption_secret: &[u8], ticket: &[u8], device_nonce: &[u8]) -> Result<Message, Error> {
+    let (opcode, message_number) = match get_header(source, HANDSHAKE_VERSION) {
+        Err(e) => return Err(e),
+        Ok(x) => x
+    };
+
+    if opcode == opcodes::ERROR {
+        return Ok(Message { number: message_number, content: MessageContent::Error });
+    } else if opcode != opcodes::RESUME_ACCEPT {
+        return Err(Error::InvalidOpcode);
+    }
+
+    if message_number != 0 {
+        return Err(Error::BadPacket);
+    }
+
+    let buff = match get_n_bytes(source, AUTH_TAG_BYTES + RESUME_NONCE_BYTES + 3) { // the 3 is for the versions
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+
+    let (auth_tag, the_rest) = buff.split_at(AUTH_TAG_BYTES);
+    let (server_nonce, versions) = the_rest.split_at(RESUME_NONCE_BYTES);
+    let echoed_offer = VersionOffer { min: versions[1], max: versions[2] };
+
+    // the tag is made with the new keys so derive them first
+    let transcript = send::resume_transcript_hash(ticket, device_nonce, server_nonce, versions[0], &echoed_offer);
+    let session_keys = send::resumed_session_keys(resumption_secret, &transcript);
+    let fields = send::resume_accept_authenticated_fields(device_nonce, server_nonce, versions[0], &echoed_offer);
+
+    if !session_keys.from_server.verify_auth_tag(auth_tag, &fields, nonce_of(message_number)) {
+        return Err(Error::Crypto);
+    }
+
+    Ok(Message{ number: message_number, content: MessageContent::ResumeAccept(server_nonce.to_vec(), versions[0], echoed_offer) })
+}
+
+/// Decrypt a resumption ticket which we sealed earlier. Returns the resumption secret, the device's long-term public key, the id of the server key used in the original key exchange and the expiry time, or None if the ticket was not made with this key.
+pub fn open_ticket(ticket_key: &[u8], ticket: &[u8]) -> Option<(Vec<u8>, PublicKey, PublicKeyId, u64)> {
+    if ticket.len() != TICKET_ID_BYTES + RESUMPTION_SECRET_BYTES + PUBLIC_KEY_BYTES + 32 + 8 + AUTH_TAG_BYTES {
+        return None;
+    }
+
+    let (ticket_id, ciphertext) = ticket.split_at(TICKET_ID_BYTES);
+    let plaintext = match send::ticket_state(ticket_key, ticket_id).authenticated_decryption(ciphertext, 0) {
+        None => return None,
+        Some(p) => p,
+    };
+
+    let (secret, the_rest) = plaintext.split_at(RESUMPTION_SECRET_BYTES);
+    let (pk_bytes, the_rest) = the_rest.split_at(PUBLIC_KEY_BYTES);
+    let (server_key_id_bytes, expiry_bytes) = the_rest.split_at(32);
+    let server_key_id = PublicKeyId {
+        digest: sha256::Digest::from_slice(server_key_id_bytes).unwrap(),
+    };
+
+    match public_key_from_slice(pk_bytes) {
+        None => None,
+        Some(pk) => Some((secret.to_vec(), pk, server_key_id, eight_bytes_to_u64(expiry_bytes))),
+    }
+}
+
+/// Check the binder on a resume packet once the resumption secret is known
+pub fn check_binder(resumption_secret: &[u8], offer: &VersionOffer, device_nonce: &[u8], ticket: &[u8], binder: &[u8]) -> bool {
+    let fields = send::resume_authenticated_fields(offer, device_nonce, ticket);
+    send::binder_state(resumption_secret).verify_auth_tag(binder, &fields, 0)
+}
+
+pub fn general <R: io::Read> (source: &mut R, session_keys: &symmetric::State, version: u8) -> Result<Message, Error> {
+    let (opcode, message_number) = match get_header(source, version) {
         Err(e) => return Err(e),
         Ok(x) => x
     };
 
+    body(source, opcode, message_number, session_keys, version)
+}
+
+/// Receive just the opcode and message number of a packet. Useful when the keys depend on the message number.
+pub fn header <R: io::Read> (source: &mut R, version: u8) -> Result<(u8, u64), Error> {
+    get_header(source, version)
+}
+
+/// Receive the rest of a packet after header()
+pub fn body <R: io::Read> (source: &mut R, opcode: u8, message_number: u64, session_keys: &symmetric::State, version: u8) -> Result<Message, Error> {
     // these functions check if the opcode is valid for us
     if opcode <= opcodes::MAX_NOCRYPT {
         parse_clear_message(source, opcode, message_number) 
     } else {
-        parse_crypt_message(source, opcode, message_number, session_keys)
+        parse_crypt_message(source, opcode, message_number, session_keys, version)
     }
 }
 
@@ -150,15 +408,19 @@ fn get_n_bytes<R: io::Read> (source: &mut R, n: usize) -> Result<Vec<u8>, Error>
     for _ in 0..n {
         buffer.push(0);
     }
-    
-    match source.read(&mut buffer) {
-        Err(e) => Err(Error::Read(e)),
-        Ok(bytes_read) => if bytes_read == n {
-            Ok(buffer)
-        } else {
-            Err(Error::NotEnoughRead(bytes_read))
+
+    // a stream may hand us a packet in several pieces so keep reading until we have all of it
+    let mut bytes_read = 0;
+    while bytes_read < n {
+        match source.read(&mut buffer[bytes_read..]) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(Error::Read(e)),
+            Ok(0) => return Err(Error::NotEnoughRead(bytes_read)),
+            Ok(m) => bytes_read += m,
         }
     }
+
+    Ok(buffer)
 }
 
 fn two_bytes_to_u16(bytes: &[u8]) -> u16 {
@@ -167,68 +429,203 @@ fn two_bytes_to_u16(bytes: &[u8]) -> u16 {
     bytes[1] as u16 + ((bytes[0] as u16) << 8)
 }
 
-fn get_header<R: io::Read> (source: &mut R) -> Result<(u8, u16), Error> {
-    let header_buffer = match get_n_bytes(source, 3) { // one byte opcode, 2 byte message number
+fn eight_bytes_to_u64(bytes: &[u8]) -> u64 {
+    assert_eq!(bytes.len(), 8);
+
+    bytes.iter().fold(0, |n, b| (n << 8) | (*b as u64))
+}
+
+fn get_header<R: io::Read> (source: &mut R, version: u8) -> Result<(u8, u64), Error> {
+    let number_len = if version >= LONG_NUMBERS_VERSION { 8 } else { 2 };
+
+    let header_buffer = match get_n_bytes(source, 1 + number_len) { // one byte opcode, then the message number
         Err(e) => return Err(e),
         Ok(buff) => buff
     };
 
     let (opcode, message_number_bytes) = header_buffer.split_at(1);
 
-    // message_number_bytes is always 2 items long
-    let message_number = two_bytes_to_u16(message_number_bytes);
+    let message_number = if version >= LONG_NUMBERS_VERSION {
+        eight_bytes_to_u64(message_number_bytes)
+    } else {
+        two_bytes_to_u16(message_number_bytes) as u64
+    };
 
     Ok((opcode[0], message_number))
 }
     
-// error and device_first are the only two clear messages that we can receive without explicitly expecting them to arrive
-fn parse_clear_message <R: io::Read> (source: &mut R, opcode: u8, message_number: u16) -> Result<Message, Error> {
+/// The device's ephemeral public key followed by the id of its long-term key, as sent in device_first
+fn get_device_keys<R: io::Read> (source: &mut R) -> Result<(PublicKey, PublicKeyId), Error> {
+    let pub_key_bytes = match get_n_bytes(source, PUBLIC_KEY_BYTES) {
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+    let pub_key = public_key_from_slice(&pub_key_bytes).unwrap();
+
+    let key_id_bytes = match get_n_bytes(source, 32) {
+        Err(e) => return Err(e),
+        Ok(x) => x,
+    };
+    let digest = sha256::Digest::from_slice(&key_id_bytes).unwrap();
+    let key_id = PublicKeyId {
+        digest: digest,
+    };
+
+    Ok((pub_key, key_id))
+}
+
+// error, the kinds of device_first and resume are the only clear messages that we can receive without explicitly expecting them to arrive
+fn parse_clear_message <R: io::Read> (source: &mut R, opcode: u8, message_number: u64) -> Result<Message, Error> {
     match opcode {
         opcodes::ERROR => Ok(Message{ number: message_number, content: MessageContent::Error, }),
         opcodes::DEVICE_FIRST => {
             if message_number != 0 {
                 return Err(Error::BadPacket);
             }
-            
-            let pub_key_bytes = match get_n_bytes(source, PUBLIC_KEY_BYTES) {
+
+            let (pub_key, key_id) = match get_device_keys(source) {
                 Err(e) => return Err(e),
                 Ok(x) => x,
             };
-            let pub_key = public_key_from_slice(&pub_key_bytes).unwrap();
 
-            let key_id_bytes = match get_n_bytes(source, 32) {
+            Ok(Message{ number: message_number, content: MessageContent::UnversionedDeviceFirst(pub_key, key_id)})
+        },
+        opcodes::VERSIONED_DEVICE_FIRST | opcodes::TARGETED_DEVICE_FIRST => {
+            if message_number != 0 {
+                return Err(Error::BadPacket);
+            }
+
+            let version_bytes = match get_n_bytes(source, 2) {
                 Err(e) => return Err(e),
                 Ok(x) => x,
             };
-            let digest = sha256::Digest::from_slice(&key_id_bytes).unwrap();
-            let key_id = PublicKeyId {
-                digest: digest,
+            let offer = VersionOffer { min: version_bytes[0], max: version_bytes[1] };
+
+            let (pub_key, key_id) = match get_device_keys(source) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
             };
-            
-            Ok(Message{ number: message_number, content: MessageContent::DeviceFirst(pub_key, key_id )})
+
+            if opcode == opcodes::VERSIONED_DEVICE_FIRST {
+                return Ok(Message{ number: message_number, content: MessageContent::DeviceFirst(pub_key, key_id, offer)});
+            }
+
+            let server_key_id_bytes = match get_n_bytes(source, 32) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+            let server_key_id = PublicKeyId {
+                digest: sha256::Digest::from_slice(&server_key_id_bytes).unwrap(),
+            };
+
+            Ok(Message{ number: message_number, content: MessageContent::TargetedDeviceFirst(pub_key, key_id, server_key_id, offer)})
+        },
+        opcodes::ANONYMOUS_DEVICE_FIRST | opcodes::HIDDEN_DEVICE_FIRST | opcodes::TARGETED_ANONYMOUS_DEVICE_FIRST | opcodes::TARGETED_HIDDEN_DEVICE_FIRST => {
+            if message_number != 0 {
+                return Err(Error::BadPacket);
+            }
+
+            let fields = match get_n_bytes(source, 2 + PUBLIC_KEY_BYTES) { // versions, ephemeral public key
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+            let offer = VersionOffer { min: fields[0], max: fields[1] };
+            let pub_key = public_key_from_slice(&fields[2..]).unwrap();
+
+            let server_key_id = if (opcode == opcodes::TARGETED_ANONYMOUS_DEVICE_FIRST) || (opcode == opcodes::TARGETED_HIDDEN_DEVICE_FIRST) {
+                let server_key_id_bytes = match get_n_bytes(source, 32) {
+                    Err(e) => return Err(e),
+                    Ok(x) => x,
+                };
+                Some(PublicKeyId {
+                    digest: sha256::Digest::from_slice(&server_key_id_bytes).unwrap(),
+                })
+            } else {
+                None
+            };
+
+            let content = if (opcode == opcodes::HIDDEN_DEVICE_FIRST) || (opcode == opcodes::TARGETED_HIDDEN_DEVICE_FIRST) {
+                MessageContent::HiddenDeviceFirst(pub_key, server_key_id, offer)
+            } else {
+                MessageContent::AnonymousDeviceFirst(pub_key, server_key_id, offer)
+            };
+
+            Ok(Message{ number: message_number, content: content })
+        },
+        opcodes::PSK_DEVICE_FIRST => {
+            if message_number != 0 {
+                return Err(Error::BadPacket);
+            }
+
+            let fixed_fields = match get_n_bytes(source, 2 + PUBLIC_KEY_BYTES + 1) { // versions, ephemeral public key, id length
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+            let offer = VersionOffer { min: fixed_fields[0], max: fixed_fields[1] };
+            let pub_key = public_key_from_slice(&fixed_fields[2..(2 + PUBLIC_KEY_BYTES)]).unwrap();
+
+            let psk_id = match get_n_bytes(source, fixed_fields[2 + PUBLIC_KEY_BYTES] as usize) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            Ok(Message{ number: message_number, content: MessageContent::PskDeviceFirst(pub_key, psk_id, offer) })
+        },
+        opcodes::RESUME => {
+            if message_number != 0 {
+                return Err(Error::BadPacket);
+            }
+
+            let fixed_fields = match get_n_bytes(source, 2 + RESUME_NONCE_BYTES + 2) { // versions, nonce, ticket length
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+            let offer = VersionOffer { min: fixed_fields[0], max: fixed_fields[1] };
+            let (device_nonce, length_bytes) = fixed_fields[2..].split_at(RESUME_NONCE_BYTES);
+
+            let ticket = match get_n_bytes(source, two_bytes_to_u16(length_bytes) as usize) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let binder = match get_n_bytes(source, AUTH_TAG_BYTES) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            Ok(Message{ number: message_number, content: MessageContent::Resume(offer, device_nonce.to_vec(), ticket, binder) })
         },
         _ => Err(Error::InvalidOpcode),
     } 
 }
 
-fn parse_crypt_message <R: io::Read> (source: &mut R, opcode: u8, message_number: u16, session_keys: &symmetric::State) -> Result<Message, Error> {
+fn parse_crypt_message <R: io::Read> (source: &mut R, opcode: u8, message_number: u64, session_keys: &symmetric::State, version: u8) -> Result<Message, Error> {
+    let nonce = nonce_of(message_number);
+
     match opcode {
         opcodes::ERROR => Ok(Message{ number: message_number, content: MessageContent::Error }),
 
-        opcodes::MESSAGE => {
+        opcodes::MESSAGE | opcodes::TRACKED_MESSAGE | opcodes::FRAGMENT => {
             // get the fixed fields
-            let fixed_fields = match get_n_bytes(source, 2 + AUTH_TAG_BYTES) { // u16 message length + authentication on the length
+            let stream_id_len = if version >= STREAMS_VERSION { 2 } else { 0 };
+            let fixed_fields = match get_n_bytes(source, stream_id_len + 2 + AUTH_TAG_BYTES) { // u16 stream id (if we have streams) + u16 message length + authentication on those
                 Err(e) => return Err(e),
                 Ok(x) => x,
             };
 
-            let (length_bytes, auth_tag) = fixed_fields.split_at(2);
+            let (authenticated_fields, auth_tag) = fixed_fields.split_at(stream_id_len + 2);
             
             // test auth_tag
-            if !session_keys.verify_auth_tag(auth_tag, length_bytes, message_number) {
+            if !session_keys.verify_auth_tag(auth_tag, authenticated_fields, nonce) {
                 return Err(Error::Crypto);
             }
 
+            let (stream_bytes, length_bytes) = authenticated_fields.split_at(stream_id_len);
+            let stream = if version >= STREAMS_VERSION {
+                two_bytes_to_u16(stream_bytes)
+            } else {
+                DEFAULT_STREAM
+            };
             let length = two_bytes_to_u16(length_bytes);
 
             // now get the ciphertext
@@ -238,41 +635,146 @@ fn parse_crypt_message <R: io::Read> (source: &mut R, opcode: u8, message_number
             };
 
             // decrypt
-            match session_keys.authenticated_decryption(&ciphertext, message_number) {
+            let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
                 None => return Err(Error::Crypto),
-                Some(plaintext) => Ok(Message{ number: message_number, content: MessageContent::Message(plaintext) })
-            }
+                Some(p) => p,
+            };
+
+            let content = match opcode {
+                opcodes::TRACKED_MESSAGE => MessageContent::TrackedMessage(stream, plaintext),
+                opcodes::FRAGMENT => MessageContent::Fragment(stream, plaintext),
+                _ => MessageContent::Message(stream, plaintext),
+            };
+
+            Ok(Message{ number: message_number, content: content })
         }
 
-        /*opcodes::ACK => {
-            let ciphertext = match get_n_bytes(source, 2 + AUTH_TAG_BYTES) { // u16 message number + the authentication tag on the message number
+        opcodes::ACK => {
+            let number_len = if version >= LONG_NUMBERS_VERSION { 8 } else { 2 };
+
+            let ciphertext = match get_n_bytes(source, number_len + AUTH_TAG_BYTES) { // message number + the authentication tag on the message number
                 Err(e) => return Err(e),
                 Ok(x) => x,
             };
 
-            match session_keys.authenticated_decryption(&ciphertext, message_number) {
+            let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
                 None => return Err(Error::Crypto),
-                Some(plaintext) => Ok(Message{ number: message_number, content: MessageContent::Ack(two_bytes_to_u16(&plaintext))})
-            }
-        }*/
+                Some(p) => p,
+            };
+
+            let acked_number = if version >= LONG_NUMBERS_VERSION {
+                eight_bytes_to_u64(&plaintext)
+            } else {
+                two_bytes_to_u16(&plaintext) as u64
+            };
+
+            Ok(Message{ number: message_number, content: MessageContent::Ack(acked_number)})
+        }
             
-        //opcodes::REKEY => parse_constant_contents_message(source, opcode, message_number, session_keys),
+        opcodes::REKEY => {
+            let ciphertext = match get_n_bytes(source, PUBLIC_KEY_BYTES + AUTH_TAG_BYTES) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
+                None => return Err(Error::Crypto),
+                Some(p) => p,
+            };
+
+            match public_key_from_slice(&plaintext) {
+                None => Err(Error::BadPacket),
+                Some(pk) => Ok(Message{ number: message_number, content: MessageContent::ReKey(pk) }),
+            }
+        },
            
-        opcodes::STOP => parse_constant_contents_message(source, opcode, message_number, session_keys),
+        opcodes::SESSION_ERROR => {
+            let ciphertext = match get_n_bytes(source, 1 + AUTH_TAG_BYTES) { // reason code + authentication tag
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
+                None => return Err(Error::Crypto),
+                Some(p) => p,
+            };
+
+            if plaintext.len() != 1 {
+                return Err(Error::BadPacket);
+            }
+
+            Ok(Message{ number: message_number, content: MessageContent::SessionError(ErrorReason::from_byte(plaintext[0])) })
+        },
+
+        opcodes::TICKET => {
+            let fixed_fields = match get_n_bytes(source, 2 + AUTH_TAG_BYTES) { // u16 length + authentication on it
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let (length_bytes, auth_tag) = fixed_fields.split_at(2);
+            if !session_keys.verify_auth_tag(auth_tag, length_bytes, nonce) {
+                return Err(Error::Crypto);
+            }
+
+            let length = two_bytes_to_u16(length_bytes) as usize;
+            if length < RESUMPTION_SECRET_BYTES {
+                return Err(Error::BadPacket);
+            }
+
+            let ciphertext = match get_n_bytes(source, length + AUTH_TAG_BYTES) {
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let mut plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
+                None => return Err(Error::Crypto),
+                Some(p) => p,
+            };
+
+            let ticket = plaintext.split_off(RESUMPTION_SECRET_BYTES);
+            Ok(Message{ number: message_number, content: MessageContent::Ticket(plaintext, ticket) })
+        },
+
+        opcodes::CLOSE_STREAM => {
+            let ciphertext = match get_n_bytes(source, 2 + AUTH_TAG_BYTES) { // stream id + authentication tag
+                Err(e) => return Err(e),
+                Ok(x) => x,
+            };
+
+            let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce) {
+                None => return Err(Error::Crypto),
+                Some(p) => p,
+            };
+
+            if plaintext.len() != 2 {
+                return Err(Error::BadPacket);
+            }
+
+            Ok(Message{ number: message_number, content: MessageContent::CloseStream(two_bytes_to_u16(&plaintext)) })
+        },
+
+        opcodes::STOP | opcodes::PING | opcodes::PONG | opcodes::END_OF_STREAM => parse_constant_contents_message(source, opcode, message_number, session_keys),
 
         _ => Err(Error::InvalidOpcode),
     }
 }
 
-fn parse_constant_contents_message<R: io::Read> (source: &mut R, opcode: u8, message_number: u16, session_keys: &symmetric::State) -> Result<Message, Error> {
-    assert!(/*(opcode == opcodes::REKEY ) ||*/ (opcode == opcodes::STOP));
-    
+fn parse_constant_contents_message<R: io::Read> (source: &mut R, opcode: u8, message_number: u64, session_keys: &symmetric::State) -> Result<Message, Error> {
+    let (expected_contents, content) = match opcode {
+        opcodes::STOP => (opcodes::STOP_CONTENTS, MessageContent::Stop),
+        opcodes::PING => (opcodes::PING_CONTENTS, MessageContent::Ping),
+        opcodes::PONG => (opcodes::PONG_CONTENTS, MessageContent::Pong),
+        opcodes::END_OF_STREAM => (opcodes::END_OF_STREAM_CONTENTS, MessageContent::EndOfStream),
+        _ => panic!("parse_constant_contents_message called for opcode {}", opcode),
+    };
+
     let ciphertext = match get_n_bytes(source, opcodes::CONST_MSG_LEN + AUTH_TAG_BYTES) {
         Err(e) => return Err(e),
         Ok(x) => x,
     };
 
-    let plaintext = match session_keys.authenticated_decryption(&ciphertext, message_number) {
+    let plaintext = match session_keys.authenticated_decryption(&ciphertext, nonce_of(message_number)) {
         None => return Err(Error::Crypto),
         Some(p) => p,
     };
@@ -285,18 +787,8 @@ fn parse_constant_contents_message<R: io::Read> (source: &mut R, opcode: u8, mes
         return Err(Error::BadPacket);
     }
 
-    let expected = /*if opcode == opcodes::REKEY {
-        opcodes::REKEY_CONTENTS
-    } else {*/
-        opcodes::STOP_CONTENTS
-    /*}*/;
-
-    if plaintext[0] == expected {
-    /*    if opcode == opcodes::REKEY {
-            Ok(Message{ number: message_number, content: MessageContent::ReKey } )
-        } else {*/
-            Ok(Message{ number: message_number, content: MessageContent::Stop } )
-        //}
+    if plaintext[0] == expected_contents {
+        Ok(Message{ number: message_number, content: content } )
     } else {
         Err(Error::Crypto)
     }
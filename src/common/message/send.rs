@@ -14,6 +14,7 @@
 
 use super::opcodes;
 use super::Error;
+use super::{VersionOffer, ErrorReason, HANDSHAKE_VERSION, TRANSCRIPT_VERSION, TOFU_VERSION, LONG_NUMBERS_VERSION, STREAMS_VERSION, DEFAULT_STREAM, RESUMPTION_SECRET_BYTES, RESUME_NONCE_BYTES, TICKET_ID_BYTES, MAX_PSK_ID_BYTES, nonce_of};
 use std::io;
 use proj_crypto::asymmetric::key_exchange::*;
 use proj_crypto::asymmetric::key_id::*;
@@ -26,21 +27,56 @@ use {SessionKeys, Keypair};
 
 /// The number of bytes in the random challenge
 const CHALLENGE_BYES: usize = 32;
+/// The most data which fits into a single message packet. Anything longer must be split into fragments.
+pub const MAX_PACKET_DATA: usize = u16::max_value() as usize;
 /// Differentiates the device encryption key from the server encryption key
 const DEVICE_ENC_KEY_CONSTANT: &'static [u8] = b"device";
 const SERVER_ENC_KEY_CONSTANT: &'static [u8] = b"server";
+/// Differentiates the authentication keys derived when rekeying. The key exchange gives these to us for free during the handshake.
+const DEVICE_AUTH_KEY_CONSTANT: &'static [u8] = b"device auth";
+const SERVER_AUTH_KEY_CONSTANT: &'static [u8] = b"server auth";
+/// Differentiates the session secret from the keys derived alongside it
+const SECRET_CONSTANT: &'static [u8] = b"secret";
+/// Differentiates the keys derived from a resumption secret or a ticket key
+const BINDER_KEY_CONSTANT: &'static [u8] = b"resume binder";
+const TICKET_ENC_KEY_CONSTANT: &'static [u8] = b"ticket enc";
+const TICKET_AUTH_KEY_CONSTANT: &'static [u8] = b"ticket auth";
+/// Differentiates the secrets handed to the application from the keys used by the protocol
+const EXPORTER_CONSTANT: &'static [u8] = b"exporter";
+const SESSION_ID_CONSTANT: &'static [u8] = b"session id";
+/// Starts the transcript of the key exchange so that it can't be mistaken for anything hashed by another protocol
+const TRANSCRIPT_LABEL: &'static [u8] = b"project-net key exchange";
+
+pub fn device_first<W: io::Write>(dest: &mut W, long_pk: &PublicKey, offer: &VersionOffer) -> Result<Keypair, Error> {
+    identified_device_first(dest, long_pk, None, offer)
+}
+
+/// Like device_first but asks the server to use the long-term key with this id. The server's answer is the same as to device_first.
+pub fn targeted_device_first<W: io::Write>(dest: &mut W, long_pk: &PublicKey, server_long_pk_id: &PublicKeyId, offer: &VersionOffer) -> Result<Keypair, Error> {
+    identified_device_first(dest, long_pk, Some(server_long_pk_id), offer)
+}
 
-pub fn device_first<W: io::Write>(dest: &mut W, long_pk: &PublicKey) -> Result<Keypair, Error> {
-    let mut message = construct_header(opcodes::DEVICE_FIRST, 0);
+/// A device first packet which says who we are, and which server key we expect if server_long_pk_id is not None
+fn identified_device_first<W: io::Write>(dest: &mut W, long_pk: &PublicKey, server_long_pk_id: Option<&PublicKeyId>, offer: &VersionOffer) -> Result<Keypair, Error> {
+    let opcode = if server_long_pk_id.is_some() { opcodes::TARGETED_DEVICE_FIRST } else { opcodes::VERSIONED_DEVICE_FIRST };
+    let mut message = construct_header(opcode, 0, HANDSHAKE_VERSION);
     
     let keypair = gen_keypair();
 
+    message.push(offer.min);
+    message.push(offer.max);
+
     let pubkey_bytes = &keypair.0.clone()[..];
     message.extend_from_slice(pubkey_bytes);
 
     let key_id = id_of_pk(long_pk);
     message.extend_from_slice(&key_id.digest[..]);
 
+    match server_long_pk_id {
+        Some(id) => message.extend_from_slice(&id.digest[..]),
+        None => (),
+    };
+
     match write_bytes(dest, &message) {
         None => Ok(keypair),
         Some(e) => Err(e),
@@ -59,38 +95,94 @@ fn hash_two_things(thing1: &[u8], thing2: &[u8]) -> symmetric::Digest {
     result
 }
 
-/// returns the session keys and the random challenge
-pub fn server_first<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, device_session_pk: &PublicKey, device_long_pk: &PublicKey) -> Result<(SessionKeys, Vec<u8>), Error> {
-    let mut message = construct_header(opcodes::SERVER_FIRST, 0);
-
+/// returns the session keys and the random challenge. device_long_pk is None if the device is anonymous. server_long_pk_id is the id the device asked for in targeted_device_first, if it did
+pub fn server_first<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, device_session_pk: &PublicKey, device_long_pk: Option<&PublicKey>, server_long_pk_id: Option<&PublicKeyId>, version: u8, offer: &VersionOffer) -> Result<(SessionKeys, Vec<u8>), Error> {
     // generate the server's ephemeral keypair
-    let (pub_key, sec_key) = gen_keypair(); // sec_key implements drop to clear memory
+    let session_keypair = gen_keypair(); // the secret key implements drop to clear memory
+
+    let device_first = identified_device_first_opcode(device_long_pk.is_some(), server_long_pk_id.is_some());
+    server_first_with_keypair(dest, long_term_keypair, &session_keypair, device_session_pk, device_first, device_long_pk, server_long_pk_id, version, offer)
+}
+
+/// The opcode of the device first packet which starts a key exchange where the device says who it is straight away (or is anonymous)
+fn identified_device_first_opcode(has_long_pk: bool, targeted: bool) -> u8 {
+    match (has_long_pk, targeted) {
+        (true, true) => opcodes::TARGETED_DEVICE_FIRST,
+        (true, false) => opcodes::VERSIONED_DEVICE_FIRST,
+        (false, true) => opcodes::TARGETED_ANONYMOUS_DEVICE_FIRST,
+        (false, false) => opcodes::ANONYMOUS_DEVICE_FIRST,
+    }
+}
+
+/// The opcode of the device first packet which starts a key exchange where the device hides who it is
+fn hidden_device_first_opcode(targeted: bool) -> u8 {
+    if targeted { opcodes::TARGETED_HIDDEN_DEVICE_FIRST } else { opcodes::HIDDEN_DEVICE_FIRST }
+}
+
+/// The answer to a device which predates version negotiation. Laid out as server_first was before then: version 1, with no versions in it
+pub fn unversioned_server_first<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, device_session_pk: &PublicKey, device_long_pk: &PublicKey) -> Result<(SessionKeys, Vec<u8>), Error> {
+    let session_keypair = gen_keypair();
+    server_first_with_keypair(dest, long_term_keypair, &session_keypair, device_session_pk, opcodes::DEVICE_FIRST, Some(device_long_pk), None, HANDSHAKE_VERSION, &VersionOffer::unversioned())
+}
+
+/// The answer to hidden_device_first. The session keys are the ones for an anonymous device, which only protect hidden_device_second.
+/// Also returns our ephemeral keypair because it is needed again once the device says who it is.
+pub fn hidden_server_first<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, device_session_pk: &PublicKey, server_long_pk_id: Option<&PublicKeyId>, version: u8, offer: &VersionOffer) -> Result<(SessionKeys, Vec<u8>, Keypair), Error> {
+    let session_keypair = gen_keypair();
+
+    match server_first_with_keypair(dest, long_term_keypair, &session_keypair, device_session_pk, hidden_device_first_opcode(server_long_pk_id.is_some()), None, server_long_pk_id, version, offer) {
+        Ok((session_keys, challenge)) => Ok((session_keys, challenge, session_keypair)),
+        Err(e) => Err(e),
+    }
+}
+
+/// device_first is the opcode the device started the key exchange with
+fn server_first_with_keypair<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, session_keypair: &Keypair, device_session_pk: &PublicKey, device_first: u8, device_long_pk: Option<&PublicKey>, server_long_pk_id: Option<&PublicKeyId>, version: u8, offer: &VersionOffer) -> Result<(SessionKeys, Vec<u8>), Error> {
+    // from TOFU_VERSION the device gets our whole long-term public key instead of its id, so that it can check a key it has not seen before
+    let (opcode, long_key_field) = if version >= TOFU_VERSION {
+        (opcodes::KEYED_SERVER_FIRST, long_term_keypair.0[..].to_vec())
+    } else {
+        (opcodes::SERVER_FIRST, id_of_pk(&long_term_keypair.0).digest[..].to_vec())
+    };
+
+    let mut message = construct_header(opcode, 0, HANDSHAKE_VERSION);
+    let (ref pub_key, ref sec_key) = *session_keypair;
 
     let challenge = randombytes::randombytes(CHALLENGE_BYES);
 
     // do key exchange
-    let encryption_key_shared = key_exchange(device_session_pk, &sec_key, &pub_key, false);
-    let device_enc_key = hash_two_things(&encryption_key_shared.digest[..], DEVICE_ENC_KEY_CONSTANT);
-    let server_enc_key = hash_two_things(&encryption_key_shared.digest[..], SERVER_ENC_KEY_CONSTANT);
-
-    let device_auth_key = key_exchange(device_long_pk, &sec_key, &pub_key, false);
+    let encryption_key_shared = key_exchange(device_session_pk, sec_key, pub_key, false);
+    let device_auth_key = match device_long_pk {
+        Some(pk) => key_exchange(pk, sec_key, pub_key, false),
+        None => anonymous_device_auth_key(&encryption_key_shared),
+    };
     let server_auth_key = key_exchange(device_session_pk, &long_term_keypair.1, &long_term_keypair.0, false);
 
-    let session_keys = SessionKeys {
-        from_device: symmetric::State::new(&device_enc_key.as_slice(), &device_auth_key.as_slice()),
-        from_server: symmetric::State::new(&server_enc_key.as_slice(), &server_auth_key.as_slice()),
+    let session_keys = if version >= TRANSCRIPT_VERSION {
+        let transcript = transcript_hash(device_first, device_session_pk, device_long_pk, server_long_pk_id, pub_key, &long_term_keypair.0, &challenge, version, offer);
+        handshake_session_keys(&encryption_key_shared.as_slice(), &device_auth_key.as_slice(), &server_auth_key.as_slice(), Some(&transcript))
+    } else {
+        handshake_session_keys(&encryption_key_shared.as_slice(), &device_auth_key.as_slice(), &server_auth_key.as_slice(), None)
     };
 
     // message to send to the device
     let mut plaintext = vec!();
     plaintext.extend_from_slice(&pub_key[..]);
     plaintext.extend_from_slice(&challenge);
-    let auth_tag = session_keys.from_server.plain_auth_tag(&plaintext, 0); // message number = 0
+    // authenticated so that the device can tell if its offer was tampered with. A device which made no offer does not expect them
+    if device_first != opcodes::DEVICE_FIRST {
+        plaintext.push(version);
+        plaintext.push(offer.min);
+        plaintext.push(offer.max);
+    }
+    // the device checks this before it knows the version, so it is always authenticated with the key straight from the key exchange
+    let server_authenticator = symmetric::State::new(&server_auth_key.as_slice(), &server_auth_key.as_slice());
+    let auth_tag = server_authenticator.plain_auth_tag(&plaintext, 0); // message number = 0
     
     // construct message
-    message.extend_from_slice(&id_of_pk(&long_term_keypair.0).digest[..]);
+    message.extend_from_slice(&long_key_field);
     message.extend_from_slice(&auth_tag);
-    message.append(&mut plaintext); // plaintext is the public key + challenge
+    message.append(&mut plaintext); // plaintext is the public key + challenge + versions (if any)
 
     // send message
     match write_bytes(dest, &message) {
@@ -99,83 +191,566 @@ pub fn server_first<W: io::Write>(dest: &mut W, long_term_keypair: &Keypair, dev
     }
 }
 
-pub fn device_second<W: io::Write>(dest: &mut W, server_long_pk: &PublicKey, server_session_pk: &PublicKey, challenge: &[u8], long_keypair: &Keypair, session_keypair: &Keypair) -> Result<SessionKeys, Error> {
+/// long_keypair is None if we are anonymous. server_long_pk_id is the id we asked for in targeted_device_first, if we did. version is the one chosen by the server and offer is the one we sent in device_first
+pub fn device_second<W: io::Write>(dest: &mut W, server_long_pk: &PublicKey, server_session_pk: &PublicKey, challenge: &[u8], long_keypair: Option<&Keypair>, server_long_pk_id: Option<&PublicKeyId>, session_keypair: &Keypair, version: u8, offer: &VersionOffer) -> Result<SessionKeys, Error> {
+    let device_first = identified_device_first_opcode(long_keypair.is_some(), server_long_pk_id.is_some());
+    let session_keys = device_session_keys(server_long_pk, server_session_pk, challenge, device_first, long_keypair, server_long_pk_id, session_keypair, version, offer);
+
+    match challenge_response(dest, &session_keys, challenge) {
+        None => Ok(session_keys),
+        Some(e) => Err(e),
+    }
+}
+
+/// Finish a key exchange started with hidden_device_first. Our key id is encrypted under the anonymous session keys, so only the server can see who we are.
+/// The challenge response is encrypted under the session keys returned, which also need our long-term secret key.
+pub fn hidden_device_second<W: io::Write>(dest: &mut W, server_long_pk: &PublicKey, server_session_pk: &PublicKey, challenge: &[u8], long_keypair: &Keypair, server_long_pk_id: Option<&PublicKeyId>, session_keypair: &Keypair, version: u8, offer: &VersionOffer) -> Result<SessionKeys, Error> {
     assert_eq!(challenge.len(), CHALLENGE_BYES);
-    
-    let mut message = construct_header(opcodes::DEVICE_SECOND, 1);
 
-    // re-derive this so that we don't have to copy it everywhere between parsing and sending
-    let from_server_auth = &key_exchange(server_long_pk, &session_keypair.1, &session_keypair.0, true).as_slice();
+    let anonymous_keys = device_session_keys(server_long_pk, server_session_pk, challenge, hidden_device_first_opcode(server_long_pk_id.is_some()), None, server_long_pk_id, session_keypair, version, offer);
+    let identity_shared = key_exchange(server_session_pk, &long_keypair.1, &long_keypair.0, true);
+    let session_keys = hidden_session_keys(&anonymous_keys, &identity_shared);
 
-    // the other authentication key
-    let from_device_auth = &key_exchange(server_session_pk, &long_keypair.1, &long_keypair.0, true).as_slice();
+    let mut message = construct_header(opcodes::HIDDEN_DEVICE_SECOND, 1, HANDSHAKE_VERSION);
+
+    let mut encrypted_id = anonymous_keys.from_device.authenticated_encryption(&id_of_pk(&long_keypair.0).digest[..], 1); // message number = 1
+    let mut ciphertext = session_keys.from_device.authenticated_encryption(challenge, 1);
+    message.append(&mut encrypted_id);
+    message.append(&mut ciphertext);
+
+    match write_bytes(dest, &message) {
+        None => Ok(session_keys),
+        Some(e) => Err(e),
+    }
+}
+
+/// Tell a hidden device that we know who it is and trust it: a tag over the challenge made with the final session keys, which need the device's long-term public key
+pub fn hidden_server_second<W: io::Write>(dest: &mut W, session_keys: &SessionKeys, challenge: &[u8]) -> Option<Error> {
+    assert_eq!(challenge.len(), CHALLENGE_BYES);
+
+    let mut message = construct_header(opcodes::HIDDEN_SERVER_SECOND, 1, HANDSHAKE_VERSION);
+    let auth_tag = session_keys.from_server.plain_auth_tag(challenge, 1); // message number = 1
+    message.extend_from_slice(&auth_tag);
+
+    write_bytes(dest, &message)
+}
+
+/// The session keys once a hidden device has said who it is: the anonymous session secret mixed with the key exchange between the device's long-term key and the server's ephemeral key
+pub fn hidden_session_keys(anonymous_keys: &SessionKeys, identity_shared: &symmetric::Digest) -> SessionKeys {
+    let shared = hash_two_things(&anonymous_keys.secret, &identity_shared.as_slice());
+    session_keys_from_shared(&shared.as_slice())
+}
+
+/// The session keys as the device sees them at the end of the full key exchange
+fn device_session_keys(server_long_pk: &PublicKey, server_session_pk: &PublicKey, challenge: &[u8], device_first: u8, long_keypair: Option<&Keypair>, server_long_pk_id: Option<&PublicKeyId>, session_keypair: &Keypair, version: u8, offer: &VersionOffer) -> SessionKeys {
+    // re-derive this so that we don't have to copy it everywhere between parsing and sending
+    let from_server_auth = key_exchange(server_long_pk, &session_keypair.1, &session_keypair.0, true);
 
     // encryption keys
     let encryption_key_shared = key_exchange(&server_session_pk, &session_keypair.1, &session_keypair.0, true);
-    let device_enc_key = hash_two_things(&encryption_key_shared.as_slice(), DEVICE_ENC_KEY_CONSTANT);
-    let server_enc_key = hash_two_things(&encryption_key_shared.as_slice(), SERVER_ENC_KEY_CONSTANT);
 
-    let session_keys = SessionKeys {
-        from_device: symmetric::State::new(&device_enc_key.as_slice(), from_device_auth),
-        from_server: symmetric::State::new(&server_enc_key.as_slice(), from_server_auth),
+    // the other authentication key
+    let from_device_auth = match long_keypair {
+        Some(keypair) => key_exchange(server_session_pk, &keypair.1, &keypair.0, true),
+        None => anonymous_device_auth_key(&encryption_key_shared),
     };
 
+    if version >= TRANSCRIPT_VERSION {
+        let transcript = transcript_hash(device_first, &session_keypair.0, long_keypair.map(|k| &k.0), server_long_pk_id, server_session_pk, server_long_pk, challenge, version, offer);
+        handshake_session_keys(&encryption_key_shared.as_slice(), &from_device_auth.as_slice(), &from_server_auth.as_slice(), Some(&transcript))
+    } else {
+        handshake_session_keys(&encryption_key_shared.as_slice(), &from_device_auth.as_slice(), &from_server_auth.as_slice(), None)
+    }
+}
+
+/// A hash of everything sent during the key exchange: how the device started it and who the device is (unless it is anonymous or hidden), the versions offered and chosen, both ephemeral public keys, the id of the server's long-term key and the challenge.
+/// device_first is the opcode of the packet which started the key exchange. device_long_pk is None unless that packet said who the device is.
+fn transcript_hash(device_first: u8, device_session_pk: &PublicKey, device_long_pk: Option<&PublicKey>, server_long_pk_id: Option<&PublicKeyId>, server_session_pk: &PublicKey, server_long_pk: &PublicKey, challenge: &[u8], version: u8, offer: &VersionOffer) -> symmetric::Digest {
+    let mut transcript = TRANSCRIPT_LABEL.to_vec();
+
+    // every field has a fixed length once the opcode says whether there is a device key id and a requested server key id
+    transcript.push(device_first);
+    match device_long_pk {
+        Some(pk) => transcript.extend_from_slice(&id_of_pk(pk).digest[..]),
+        None => (),
+    };
+    match server_long_pk_id {
+        Some(id) => transcript.extend_from_slice(&id.digest[..]),
+        None => (),
+    };
+    transcript.push(offer.min);
+    transcript.push(offer.max);
+    transcript.extend_from_slice(&device_session_pk[..]);
+    transcript.extend_from_slice(&id_of_pk(server_long_pk).digest[..]);
+    transcript.extend_from_slice(&server_session_pk[..]);
+    transcript.extend_from_slice(challenge);
+    transcript.push(version);
+
+    symmetric::Digest{ digest: sha256::hash(&transcript) }
+}
+
+/// Derive the session keys from the three key exchanges of the full handshake.
+/// From TRANSCRIPT_VERSION every key is also hashed with the transcript so that if anything sent was tampered with the two parties end up with different keys.
+fn handshake_session_keys(encryption_key_shared: &[u8], device_auth_shared: &[u8], server_auth_shared: &[u8], transcript: Option<&symmetric::Digest>) -> SessionKeys {
+    match transcript {
+        Some(transcript) => {
+            let handshake_secret = hash_two_things(encryption_key_shared, &transcript.as_slice());
+            let device_enc_key = hash_two_things(&handshake_secret.as_slice(), DEVICE_ENC_KEY_CONSTANT);
+            let server_enc_key = hash_two_things(&handshake_secret.as_slice(), SERVER_ENC_KEY_CONSTANT);
+            let secret = hash_two_things(&handshake_secret.as_slice(), SECRET_CONSTANT);
+            let device_auth_key = hash_two_things(device_auth_shared, &transcript.as_slice());
+            let server_auth_key = hash_two_things(server_auth_shared, &transcript.as_slice());
+
+            SessionKeys {
+                from_device: symmetric::State::new(&device_enc_key.as_slice(), &device_auth_key.as_slice()),
+                from_server: symmetric::State::new(&server_enc_key.as_slice(), &server_auth_key.as_slice()),
+                secret: secret.digest[..].to_vec(),
+            }
+        },
+        None => {
+            let device_enc_key = hash_two_things(encryption_key_shared, DEVICE_ENC_KEY_CONSTANT);
+            let server_enc_key = hash_two_things(encryption_key_shared, SERVER_ENC_KEY_CONSTANT);
+            let secret = hash_two_things(encryption_key_shared, SECRET_CONSTANT);
+
+            // the authentication keys come straight from the key exchanges with the long-term keys
+            SessionKeys {
+                from_device: symmetric::State::new(&device_enc_key.as_slice(), device_auth_shared),
+                from_server: symmetric::State::new(&server_enc_key.as_slice(), server_auth_shared),
+                secret: secret.digest[..].to_vec(),
+            }
+        },
+    }
+}
+
+/// An anonymous device has no long-term key to authenticate with, so its authentication key comes from the ephemeral key exchange like the encryption keys.
+/// The server still can't be impersonated because its authentication key needs its long-term secret key.
+fn anonymous_device_auth_key(encryption_key_shared: &symmetric::Digest) -> symmetric::Digest {
+    hash_two_things(&encryption_key_shared.as_slice(), DEVICE_AUTH_KEY_CONSTANT)
+}
+
+/// Start a key exchange without a long-term identity. Returns our ephemeral keypair. If server_long_pk_id is not None the server is asked to use the long-term key with that id
+pub fn anonymous_device_first<W: io::Write>(dest: &mut W, server_long_pk_id: Option<&PublicKeyId>, offer: &VersionOffer) -> Result<Keypair, Error> {
+    ephemeral_device_first(dest, identified_device_first_opcode(false, server_long_pk_id.is_some()), server_long_pk_id, offer)
+}
+
+/// Start a key exchange without saying who we are. We only say that in hidden_device_second, once it can be encrypted. Returns our ephemeral keypair.
+/// If server_long_pk_id is not None the server is asked to use the long-term key with that id, which gives away nothing about us.
+pub fn hidden_device_first<W: io::Write>(dest: &mut W, server_long_pk_id: Option<&PublicKeyId>, offer: &VersionOffer) -> Result<Keypair, Error> {
+    ephemeral_device_first(dest, hidden_device_first_opcode(server_long_pk_id.is_some()), server_long_pk_id, offer)
+}
+
+/// A device first packet carrying only the offered versions and our ephemeral public key, then the id of the server key we expect if there is one
+fn ephemeral_device_first<W: io::Write>(dest: &mut W, opcode: u8, server_long_pk_id: Option<&PublicKeyId>, offer: &VersionOffer) -> Result<Keypair, Error> {
+    let mut message = construct_header(opcode, 0, HANDSHAKE_VERSION);
+
+    let keypair = gen_keypair();
+
+    message.push(offer.min);
+    message.push(offer.max);
+    message.extend_from_slice(&keypair.0[..]);
+
+    match server_long_pk_id {
+        Some(id) => message.extend_from_slice(&id.digest[..]),
+        None => (),
+    };
+
+    match write_bytes(dest, &message) {
+        None => Ok(keypair),
+        Some(e) => Err(e),
+    }
+}
+
+/// Send the challenge back to the server, encrypted and authenticated. This shows that we derived the same session keys.
+pub fn challenge_response<W: io::Write>(dest: &mut W, session_keys: &SessionKeys, challenge: &[u8]) -> Option<Error> {
+    assert_eq!(challenge.len(), CHALLENGE_BYES);
+
+    let mut message = construct_header(opcodes::DEVICE_SECOND, 1, HANDSHAKE_VERSION);
+
     // encrypt and authenticate the random challenge for sending to the server
     let mut ciphertext = session_keys.from_device.authenticated_encryption(challenge, 1); // message number = 1
     
     message.append(&mut ciphertext);
 
-    // send message
+    write_bytes(dest, &message)
+}
+
+/// Start a key exchange authenticated by the pre-shared key with this id. Returns our ephemeral keypair.
+pub fn psk_device_first<W: io::Write>(dest: &mut W, psk_id: &[u8], offer: &VersionOffer) -> Result<Keypair, Error> {
+    assert!(psk_id.len() <= MAX_PSK_ID_BYTES);
+
+    let mut message = construct_header(opcodes::PSK_DEVICE_FIRST, 0, HANDSHAKE_VERSION);
+
+    let keypair = gen_keypair();
+
+    message.push(offer.min);
+    message.push(offer.max);
+    message.extend_from_slice(&keypair.0[..]);
+    message.push(psk_id.len() as u8);
+    message.extend_from_slice(psk_id);
+
     match write_bytes(dest, &message) {
-        None => Ok(session_keys),
+        None => Ok(keypair),
         Some(e) => Err(e),
     }
 }
 
-pub fn message<W: io::Write>(dest: &mut W, msg: &[u8], session_keys: &symmetric::State, message_number: u16) -> Option<Error> {
-    assert!(msg.len() <= u16::max_value() as usize);
+/// The answer to psk_device_first. Returns the session keys and the random challenge
+pub fn psk_server_first<W: io::Write>(dest: &mut W, psk: &[u8], psk_id: &[u8], device_session_pk: &PublicKey, version: u8, offer: &VersionOffer) -> Result<(SessionKeys, Vec<u8>), Error> {
+    let mut message = construct_header(opcodes::PSK_SERVER_FIRST, 0, HANDSHAKE_VERSION);
+
+    let (pub_key, sec_key) = gen_keypair();
+    let challenge = randombytes::randombytes(CHALLENGE_BYES);
 
-    let mut message = construct_header(opcodes::MESSAGE, message_number);
+    let transcript = psk_transcript_hash(psk_id, device_session_pk, &pub_key, &challenge, version, offer);
+    let session_keys = psk_session_keys(psk, &key_exchange(device_session_pk, &sec_key, &pub_key, false), &transcript);
 
-    let length = u16_to_bytes(msg.len() as u16);
-    message.extend_from_slice(&length);
+    let mut plaintext = vec!();
+    plaintext.extend_from_slice(&pub_key[..]);
+    plaintext.extend_from_slice(&challenge);
+    plaintext.push(version);
+    plaintext.push(offer.min);
+    plaintext.push(offer.max);
+    let auth_tag = session_keys.from_server.plain_auth_tag(&plaintext, 0); // message number = 0
 
-    let length_auth_tag = session_keys.plain_auth_tag(&length, message_number);
-    message.extend_from_slice(&length_auth_tag);
+    message.extend_from_slice(&auth_tag);
+    message.append(&mut plaintext);
 
-    let mut ciphertext = session_keys.authenticated_encryption(msg, message_number);
+    match write_bytes(dest, &message) {
+        None => Ok((session_keys, challenge)),
+        Some(e) => Err(e),
+    }
+}
+
+/// A hash of everything sent during a key exchange authenticated by a pre-shared key: the id of the key, the versions offered and chosen, both ephemeral public keys and the challenge
+pub fn psk_transcript_hash(psk_id: &[u8], device_session_pk: &PublicKey, server_session_pk: &PublicKey, challenge: &[u8], version: u8, offer: &VersionOffer) -> symmetric::Digest {
+    let mut transcript = TRANSCRIPT_LABEL.to_vec();
+
+    // the id is the only field without a fixed length
+    transcript.push(opcodes::PSK_DEVICE_FIRST);
+    transcript.push(psk_id.len() as u8);
+    transcript.extend_from_slice(psk_id);
+    transcript.push(offer.min);
+    transcript.push(offer.max);
+    transcript.extend_from_slice(&device_session_pk[..]);
+    transcript.extend_from_slice(&server_session_pk[..]);
+    transcript.extend_from_slice(challenge);
+    transcript.push(version);
+
+    symmetric::Digest{ digest: sha256::hash(&transcript) }
+}
+
+/// The session keys for a key exchange authenticated by a pre-shared key. Nobody can derive them without both an ephemeral secret key and the pre-shared key, and they differ if anything in the transcript was changed in transit.
+pub fn psk_session_keys(psk: &[u8], ephemeral_shared: &symmetric::Digest, transcript: &symmetric::Digest) -> SessionKeys {
+    let keyed = hash_two_things(&ephemeral_shared.as_slice(), psk);
+    let shared = hash_two_things(&keyed.as_slice(), &transcript.as_slice());
+    session_keys_from_shared(&shared.digest[..])
+}
+
+pub fn message<W: io::Write>(dest: &mut W, stream: u16, msg: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    data_packet(dest, opcodes::MESSAGE, stream, msg, session_keys, message_number, version)
+}
+
+/// Part of a message which continues in the next data packet for the same stream
+pub fn fragment<W: io::Write>(dest: &mut W, stream: u16, msg: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    data_packet(dest, opcodes::FRAGMENT, stream, msg, session_keys, message_number, version)
+}
+
+/// A message which the receiver should acknowledge
+pub fn tracked_message<W: io::Write>(dest: &mut W, stream: u16, msg: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    data_packet(dest, opcodes::TRACKED_MESSAGE, stream, msg, session_keys, message_number, version)
+}
+
+fn data_packet<W: io::Write>(dest: &mut W, opcode: u8, stream: u16, msg: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    assert!(msg.len() <= MAX_PACKET_DATA);
+
+    let mut message = construct_header(opcode, message_number, version);
+
+    // the stream id and the length are authenticated together
+    let mut fixed_fields = Vec::with_capacity(4);
+    if version >= STREAMS_VERSION {
+        fixed_fields.extend_from_slice(&u16_to_bytes(stream));
+    } else {
+        assert_eq!(stream, DEFAULT_STREAM);
+    }
+    fixed_fields.extend_from_slice(&u16_to_bytes(msg.len() as u16));
+    message.extend_from_slice(&fixed_fields);
+
+    let fixed_fields_auth_tag = session_keys.plain_auth_tag(&fixed_fields, nonce_of(message_number));
+    message.extend_from_slice(&fixed_fields_auth_tag);
+
+    let mut ciphertext = session_keys.authenticated_encryption(msg, nonce_of(message_number));
     message.append(&mut ciphertext);
 
     write_bytes(dest, &message)
 }
 
-/*pub fn ack<W: io::Write>(dest: &mut W, ack_num: u16, session_keys: &symmetric::State, message_number: u16) -> Option<Error> {
-    const_size_encrypted(dest, opcodes::ACK, &u16_to_bytes(ack_num), session_keys, message_number)
-}*/
+pub fn ack<W: io::Write>(dest: &mut W, ack_num: u64, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    if version >= LONG_NUMBERS_VERSION {
+        const_size_encrypted(dest, opcodes::ACK, &u64_to_bytes(ack_num), session_keys, message_number, version)
+    } else {
+        assert!(ack_num <= u16::max_value() as u64);
+        const_size_encrypted(dest, opcodes::ACK, &u16_to_bytes(ack_num as u16), session_keys, message_number, version)
+    }
+}
 
-/*pub fn rekey<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u16) -> Option<Error> {
-    // too lazy to implement this to be that generalised
-    assert_eq!(opcodes::CONST_MSG_LEN, 1);
+pub fn rekey<W: io::Write>(dest: &mut W, session_pk: &PublicKey, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::REKEY, &session_pk[..], session_keys, message_number, version)
+}
+
+/// Derive the session keys to use after both parties have exchanged rekey packets.
+/// The old session secret is mixed in with the new shared secret so that the new keys are only as weak as the stronger of the two.
+pub fn rekey_session_keys(old_keys: &SessionKeys, my_keypair: &Keypair, their_pk: &PublicKey, i_am_device: bool) -> SessionKeys {
+    let shared = key_exchange(their_pk, &my_keypair.1, &my_keypair.0, i_am_device);
+    let chained = hash_two_things(&old_keys.secret, &shared.as_slice());
+    session_keys_from_shared(&chained.digest[..])
+}
 
-    const_size_encrypted(dest, opcodes::REKEY, &[opcodes::REKEY_CONTENTS], session_keys, message_number)
-}*/
+/// Derive all four session keys and the session secret from one shared secret
+fn session_keys_from_shared(shared: &[u8]) -> SessionKeys {
+    let device_enc_key = hash_two_things(shared, DEVICE_ENC_KEY_CONSTANT);
+    let server_enc_key = hash_two_things(shared, SERVER_ENC_KEY_CONSTANT);
+    let device_auth_key = hash_two_things(shared, DEVICE_AUTH_KEY_CONSTANT);
+    let server_auth_key = hash_two_things(shared, SERVER_AUTH_KEY_CONSTANT);
+    let secret = hash_two_things(shared, SECRET_CONSTANT);
 
-pub fn stop<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u16) -> Option<Error> {
+    SessionKeys {
+        from_device: symmetric::State::new(&device_enc_key.as_slice(), &device_auth_key.as_slice()),
+        from_server: symmetric::State::new(&server_enc_key.as_slice(), &server_auth_key.as_slice()),
+        secret: secret.digest[..].to_vec(),
+    }
+}
+
+/// The symmetric state for one direction of the session during a later epoch of 64 bit message numbers
+pub fn epoch_state(session_keys: &SessionKeys, from_device: bool, epoch: u64) -> symmetric::State {
+    let (enc_constant, auth_constant) = if from_device {
+        (DEVICE_ENC_KEY_CONSTANT, DEVICE_AUTH_KEY_CONSTANT)
+    } else {
+        (SERVER_ENC_KEY_CONSTANT, SERVER_AUTH_KEY_CONSTANT)
+    };
+
+    let epoch_bytes = u64_to_bytes(epoch);
+    let epoch_secret = hash_two_things(&session_keys.secret, &epoch_bytes);
+
+    let enc_key = hash_two_things(&epoch_secret.digest[..], enc_constant);
+    let auth_key = hash_two_things(&epoch_secret.digest[..], auth_constant);
+
+    symmetric::State::new(&enc_key.digest[..], &auth_key.digest[..])
+}
+
+/// The secret from which exported keying material and the session id are derived. It is taken from the key exchange so rekeying does not change it.
+pub fn exporter_secret(session_keys: &SessionKeys) -> Vec<u8> {
+    hash_two_things(&session_keys.secret, EXPORTER_CONSTANT).digest[..].to_vec()
+}
+
+/// Keying material for the application, specific to this session, the label and the context.
+/// The label is prefixed by its length so that no two (label, context) pairs hash the same thing.
+pub fn export(exporter_secret: &[u8], label: &[u8], context: &[u8]) -> Vec<u8> {
+    let mut input = u64_to_bytes(label.len() as u64).to_vec();
+    input.extend_from_slice(label);
+    input.extend_from_slice(context);
+
+    let ret = hash_two_things(exporter_secret, &input).digest[..].to_vec();
+    memzero(&mut input);
+    ret
+}
+
+/// A public identifier for the session. Both parties get the same one and nobody can learn the session keys from it.
+pub fn session_id(exporter_secret: &[u8]) -> Vec<u8> {
+    hash_two_things(exporter_secret, SESSION_ID_CONSTANT).digest[..].to_vec()
+}
+
+pub fn stop<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
     // too lazy to implement this to be that generalised
     assert_eq!(opcodes::CONST_MSG_LEN, 1);
 
-    const_size_encrypted(dest, opcodes::STOP, &[opcodes::STOP_CONTENTS], session_keys, message_number)
+    const_size_encrypted(dest, opcodes::STOP, &[opcodes::STOP_CONTENTS], session_keys, message_number, version)
+}
+
+pub fn ping<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::PING, &[opcodes::PING_CONTENTS], session_keys, message_number, version)
+}
+
+pub fn pong<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::PONG, &[opcodes::PONG_CONTENTS], session_keys, message_number, version)
+}
+
+/// Say that we will not send any more data. Only understood from HALF_CLOSE_VERSION
+pub fn end_of_stream<W: io::Write>(dest: &mut W, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::END_OF_STREAM, &[opcodes::END_OF_STREAM_CONTENTS], session_keys, message_number, version)
+}
+
+/// Say that we will not send any more data on this stream
+pub fn close_stream<W: io::Write>(dest: &mut W, stream: u16, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::CLOSE_STREAM, &u16_to_bytes(stream), session_keys, message_number, version)
 }
 
-pub fn error<W: io::Write>(dest: &mut W, message_number: u16) -> Option<Error> {
-    let message = construct_header(opcodes::ERROR, message_number);
+pub fn error<W: io::Write>(dest: &mut W, message_number: u64, version: u8) -> Option<Error> {
+    let message = construct_header(opcodes::ERROR, message_number, version);
     write_bytes(dest, &message)
 }
 
-fn const_size_encrypted<W: io::Write>(dest: &mut W, opcode: u8, contents: &[u8], session_keys: &symmetric::State, message_number: u16) -> Option<Error> {
-    let mut message = construct_header(opcode, message_number);
+/// Refuse a key exchange and say why. Only the device understands this, from REJECT_VERSION
+pub fn reject<W: io::Write>(dest: &mut W, reason: ErrorReason, message_number: u64) -> Option<Error> {
+    let mut message = construct_header(opcodes::REJECT, message_number, HANDSHAKE_VERSION);
+    message.push(reason.to_byte());
+    write_bytes(dest, &message)
+}
+
+/// An error after the key exchange, which the peer can authenticate
+pub fn session_error<W: io::Write>(dest: &mut W, reason: ErrorReason, session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    const_size_encrypted(dest, opcodes::SESSION_ERROR, &[reason.to_byte()], session_keys, message_number, version)
+}
+
+/// Give the device a resumption ticket and the secret which goes with it. Only the server can read the ticket itself.
+pub fn ticket<W: io::Write>(dest: &mut W, resumption_secret: &[u8], ticket: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    if (resumption_secret.len() != RESUMPTION_SECRET_BYTES) || (RESUMPTION_SECRET_BYTES + ticket.len() > MAX_PACKET_DATA) {
+        return Some(Error::BadPacket);
+    }
+
+    let mut contents = resumption_secret.to_vec();
+    contents.extend_from_slice(ticket);
 
-    let mut ciphertext = session_keys.authenticated_encryption(contents, message_number);
+    let mut message = construct_header(opcodes::TICKET, message_number, version);
+
+    // the length is authenticated on its own so that the receiver knows how much to read
+    let length = u16_to_bytes(contents.len() as u16);
+    message.extend_from_slice(&length);
+    message.extend_from_slice(&session_keys.plain_auth_tag(&length, nonce_of(message_number)));
+
+    let mut ciphertext = session_keys.authenticated_encryption(&contents, nonce_of(message_number));
+    memzero(&mut contents);
+    message.append(&mut ciphertext);
+
+    write_bytes(dest, &message)
+}
+
+/// Encrypt what the server needs to remember about a device so that the device can hold it for us.
+/// The ticket is a random id followed by the resumption secret, the device's long-term public key, the id of the server key which the device authenticated and the expiry time (seconds since the UNIX epoch), encrypted under keys derived from the ticket key and the id.
+pub fn seal_ticket(ticket_key: &[u8], resumption_secret: &[u8], device_long_pk: &PublicKey, server_long_pk_id: &PublicKeyId, expiry: u64) -> Vec<u8> {
+    let mut ticket = randombytes::randombytes(TICKET_ID_BYTES);
+
+    let mut plaintext = Vec::with_capacity(RESUMPTION_SECRET_BYTES + PUBLIC_KEY_BYTES + 32 + 8);
+    plaintext.extend_from_slice(resumption_secret);
+    plaintext.extend_from_slice(&device_long_pk[..]);
+    plaintext.extend_from_slice(&server_long_pk_id.digest[..]);
+    plaintext.extend_from_slice(&u64_to_bytes(expiry));
+
+    // every ticket has its own keys so the nonce never needs to change
+    let mut ciphertext = ticket_state(ticket_key, &ticket).authenticated_encryption(&plaintext, 0);
+    memzero(&mut plaintext);
+    ticket.append(&mut ciphertext);
+
+    ticket
+}
+
+/// The keys protecting the ticket with this id
+pub fn ticket_state(ticket_key: &[u8], ticket_id: &[u8]) -> symmetric::State {
+    let ticket_secret = hash_two_things(ticket_key, ticket_id);
+    let enc_key = hash_two_things(&ticket_secret.digest[..], TICKET_ENC_KEY_CONSTANT);
+    let auth_key = hash_two_things(&ticket_secret.digest[..], TICKET_AUTH_KEY_CONSTANT);
+
+    symmetric::State::new(&enc_key.digest[..], &auth_key.digest[..])
+}
+
+/// Authenticates a resume packet, proving that the device holds the resumption secret for the ticket
+pub fn binder_state(resumption_secret: &[u8]) -> symmetric::State {
+    let binder_key = hash_two_things(resumption_secret, BINDER_KEY_CONSTANT);
+    symmetric::State::new(&binder_key.digest[..], &binder_key.digest[..]) // only used for plain authentication
+}
+
+/// The fields of a resume packet which are covered by the binder
+pub fn resume_authenticated_fields(offer: &VersionOffer, device_nonce: &[u8], ticket: &[u8]) -> Vec<u8> {
+    let mut fields = Vec::with_capacity(2 + RESUME_NONCE_BYTES + 2 + ticket.len());
+    fields.push(offer.min);
+    fields.push(offer.max);
+    fields.extend_from_slice(device_nonce);
+    fields.extend_from_slice(&u16_to_bytes(ticket.len() as u16));
+    fields.extend_from_slice(ticket);
+    fields
+}
+
+/// Ask to resume an earlier session instead of doing the full key exchange. Returns the device's random nonce, which is needed to derive the new session keys.
+pub fn resume<W: io::Write>(dest: &mut W, resumption_secret: &[u8], ticket: &[u8], offer: &VersionOffer) -> Result<Vec<u8>, Error> {
+    // the ticket may have been read back from a file
+    if ticket.len() > MAX_PACKET_DATA {
+        return Err(Error::BadPacket);
+    }
+
+    let mut message = construct_header(opcodes::RESUME, 0, HANDSHAKE_VERSION);
+
+    let device_nonce = randombytes::randombytes(RESUME_NONCE_BYTES);
+    let mut fields = resume_authenticated_fields(offer, &device_nonce, ticket);
+    let binder = binder_state(resumption_secret).plain_auth_tag(&fields, 0); // message number = 0
+
+    message.append(&mut fields);
+    message.extend_from_slice(&binder);
+
+    match write_bytes(dest, &message) {
+        None => Ok(device_nonce),
+        Some(e) => Err(e),
+    }
+}
+
+/// The fields of a resume accept packet which are covered by its authentication tag
+pub fn resume_accept_authenticated_fields(device_nonce: &[u8], server_nonce: &[u8], version: u8, offer: &VersionOffer) -> Vec<u8> {
+    let mut fields = Vec::with_capacity(2 * RESUME_NONCE_BYTES + 3);
+    fields.extend_from_slice(device_nonce);
+    fields.extend_from_slice(server_nonce);
+    fields.push(version);
+    fields.push(offer.min);
+    fields.push(offer.max);
+    fields
+}
+
+/// Accept a resume packet. Returns the new session keys.
+/// The packet is authenticated with the new keys, which shows the device that we could open the ticket and that this answer is for its nonce.
+pub fn resume_accept<W: io::Write>(dest: &mut W, resumption_secret: &[u8], ticket: &[u8], device_nonce: &[u8], version: u8, offer: &VersionOffer) -> Result<SessionKeys, Error> {
+    let mut message = construct_header(opcodes::RESUME_ACCEPT, 0, HANDSHAKE_VERSION);
+
+    let server_nonce = randombytes::randombytes(RESUME_NONCE_BYTES);
+    let transcript = resume_transcript_hash(ticket, device_nonce, &server_nonce, version, offer);
+    let session_keys = resumed_session_keys(resumption_secret, &transcript);
+
+    // the device nonce is not sent back. The device already knows it
+    let fields = resume_accept_authenticated_fields(device_nonce, &server_nonce, version, offer);
+    let auth_tag = session_keys.from_server.plain_auth_tag(&fields, 0); // message number = 0
+
+    message.extend_from_slice(&auth_tag);
+    message.extend_from_slice(&fields[RESUME_NONCE_BYTES..]);
+
+    match write_bytes(dest, &message) {
+        None => Ok(session_keys),
+        Some(e) => Err(e),
+    }
+}
+
+/// A hash of everything sent while resuming a session: the versions offered and chosen, the ticket and both nonces
+pub fn resume_transcript_hash(ticket: &[u8], device_nonce: &[u8], server_nonce: &[u8], version: u8, offer: &VersionOffer) -> symmetric::Digest {
+    let mut transcript = TRANSCRIPT_LABEL.to_vec();
+
+    // the ticket is the only field without a fixed length
+    transcript.push(opcodes::RESUME);
+    transcript.push(offer.min);
+    transcript.push(offer.max);
+    transcript.extend_from_slice(device_nonce);
+    transcript.extend_from_slice(&u16_to_bytes(ticket.len() as u16));
+    transcript.extend_from_slice(ticket);
+    transcript.extend_from_slice(server_nonce);
+    transcript.push(version);
+
+    symmetric::Digest{ digest: sha256::hash(&transcript) }
+}
+
+/// The session keys for a resumed session. The transcript holds both nonces, so the keys are fresh even though the resumption secret is not.
+pub fn resumed_session_keys(resumption_secret: &[u8], transcript: &symmetric::Digest) -> SessionKeys {
+    let shared = hash_two_things(resumption_secret, &transcript.as_slice());
+    session_keys_from_shared(&shared.digest[..])
+}
+
+fn const_size_encrypted<W: io::Write>(dest: &mut W, opcode: u8, contents: &[u8], session_keys: &symmetric::State, message_number: u64, version: u8) -> Option<Error> {
+    let mut message = construct_header(opcode, message_number, version);
+
+    let mut ciphertext = session_keys.authenticated_encryption(contents, nonce_of(message_number));
     message.append(&mut ciphertext);
 
     write_bytes(dest, &message)
@@ -188,6 +763,15 @@ fn u16_to_bytes(n: u16) -> [u8; 2] {
     [msb, lsb]
 }
 
+fn u64_to_bytes(n: u64) -> [u8; 8] {
+    let mut ret = [0; 8];
+    for i in 0..8 {
+        ret[i] = (n >> (8 * (7 - i))) as u8;
+    }
+
+    ret
+}
+
 fn write_bytes <W: io::Write>(dest: &mut W, data: &[u8]) -> Option<Error> {
     match dest.write(data) {
         Err(e) => Some(Error::Write(e)),
@@ -199,12 +783,16 @@ fn write_bytes <W: io::Write>(dest: &mut W, data: &[u8]) -> Option<Error> {
     }
 }
 
-fn construct_header(opcode: u8, message_number: u16) -> Vec<u8> {
-    let message_number_bytes = u16_to_bytes(message_number);
-
-    let mut ret = Vec::with_capacity(3);
+fn construct_header(opcode: u8, message_number: u64, version: u8) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(9);
     ret.push(opcode);
-    ret.extend_from_slice(&message_number_bytes);
+
+    if version >= LONG_NUMBERS_VERSION {
+        ret.extend_from_slice(&u64_to_bytes(message_number));
+    } else {
+        assert!(message_number <= u16::max_value() as u64);
+        ret.extend_from_slice(&u16_to_bytes(message_number as u16));
+    }
 
     ret
 }
@@ -14,12 +14,24 @@
     along with project-net.  If not, see http://www.gnu.org/licenses/.*/
 
 pub mod message; 
+use std::cmp;
 use std::io;
 use std::io::Write;
-use std::net::TcpStream;
+use std::net::{TcpStream, SocketAddr};
 use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::collections::VecDeque;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use proj_crypto::symmetric;
-use {Keypair, SessionKeys};
+use proj_crypto::asymmetric::PublicKey;
+use proj_crypto::asymmetric::key_exchange;
+use proj_crypto::asymmetric::key_id;
+use sodiumoxide::randombytes;
+use sodiumoxide::utils::memzero;
+use {Keypair, SessionKeys, ResumptionTicket, fingerprint};
+use revocation::{Revocation, RevocationList};
 
 /// Errors returned by the client or server
 #[derive(Debug)]
@@ -30,66 +42,423 @@ pub enum Error {
     DeviceFirst(message::Error),
     ServerFirst(message::Error),
     DeviceSecond(message::Error),
+    /// only in key exchanges where the device hides its identity
+    ServerSecond(message::Error),
+    Resume(message::Error),
     Sending(message::Error),
     Receiving(message::Error),
     BadMessageN,
+    /// the server presented a different long-term key to the one we know it by. The fingerprints of the known key and of the presented key
+    ServerKeyMismatch(String, String),
+    /// the known servers file could not be written
+    KnownServers(io::Error),
+    /// the server refused the key exchange, for example with ErrorReason::UntrustedIdentity if it does not trust our key. On the server: the application refused to authorize the device
+    Rejected(message::ErrorReason),
+    /// the peer's long-term key is in the revocation list. Its fingerprint and the revocation
+    Revoked(String, Revocation),
+}
+
+/// The session keys are replaced when only this many message numbers are left so that the counter never overflows.
+/// This leaves room for the packets which can still be sent before the peer answers.
+pub const REKEY_MARGIN: u64 = 16;
+
+/// A duplex byte stream which a session can run over.
+/// The protocol itself only needs Read and Write. The other methods have defaults for streams which can't do them, such as in-memory channels.
+pub trait Transport: io::Read + Write {
+    /// Close the stream in both directions
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Give up on reads which block for longer than the timeout, or never if it is None. Needed for blocking_off() and keepalives.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::Other, "this transport does not support read timeouts")),
+        }
+    }
+
+    /// Read data without taking it out of the stream. Needed for keepalives and for noticing the answer to a rekey while writing.
+    fn peek(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "this transport does not support peeking"))
+    }
+
+    /// Does peek() work? Keepalives can't be turned on if it doesn't
+    fn can_peek(&self) -> bool {
+        false
+    }
+
+    /// Does every write become a separate datagram which may be lost, repeated or reordered?
+    fn is_datagram(&self) -> bool {
+        false
+    }
+
+    /// Called when the session has finished reading a packet, even if it could not be parsed. A datagram transport should throw away anything left of the current datagram.
+    fn end_packet(&mut self) {
+    }
+
+    /// The address of the peer, if the transport has one
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn close(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+
+    fn can_peek(&self) -> bool {
+        true
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl Transport for UnixStream {
+    fn close(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    // UnixStream can't peek so general_set_keepalive() refuses to turn keepalives on. Its peer has a path rather than an address
+}
+
+/// The most data sent as one message. Larger writes are sent as several messages. A peer which sends fragments adding up to more than this is closed with ErrorReason::Protocol so that it can't use up our memory
+pub const MAX_MESSAGE_SIZE: usize = 1 << 24;
+
+/// The most received data kept for one stream until the application reads it. A peer which gets further ahead than this is closed with ErrorReason::BufferFull, so an application which sends a lot on a stream that is read slowly should pace itself with tracked writes
+pub const MAX_STREAM_BUFFER: usize = 2 * MAX_MESSAGE_SIZE;
+
+/// The most received data kept for all streams together until the application reads it. A peer which gets further ahead than this is closed with ErrorReason::BufferFull
+pub const MAX_BUFFERED_DATA: usize = 4 * MAX_MESSAGE_SIZE;
+
+/// The most streams either of us may have open at once, counting each stream for whichever of us opened it until that one closes it. A peer which opens more is closed with ErrorReason::Protocol
+pub const MAX_OPEN_STREAMS: usize = 256;
+
+/// How long a read on a shared stream (see stream::Streams) waits for the network before letting the other streams use the session
+pub const STREAM_POLL_INTERVAL_MS: u64 = 10;
+
+/// The most data which is held back while we wait for the peer to answer our rekey packet. Writing more than this blocks until the answer arrives
+pub const MAX_REKEY_BACKLOG: usize = 1 << 20;
+
+/// How long dropping a session waits for the peer to answer our rekey packet so that held back data can still be sent. flush() waits for as long as it takes
+pub const DROP_REKEY_WAIT_MS: u64 = 200;
+
+/// The kinds of packet which carry data written by the application
+#[derive(Clone, Copy)]
+pub enum DataPacket {
+    Fragment,
+    Message,
+    TrackedMessage,
+    EndOfStream,
+    CloseStream,
+}
+
+/// A data packet written while we wait for the peer to answer our rekey packet. It is sent once the new keys are in use
+pub struct HeldPacket {
+    kind: DataPacket,
+    stream: u16,
+    data: Vec<u8>,
+}
+
+/// The number of message numbers behind the newest one for which late packets are still accepted over datagram transports
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// The most data which can be sent in one write over a datagram transport. Small enough that packets should not be fragmented by IP
+pub const MAX_DATAGRAM_DATA: usize = 1200;
+
+/// How long to wait for an answer to a key exchange packet sent over a datagram transport before sending it again
+pub const HANDSHAKE_RETRANSMIT_INTERVAL_MS: u64 = 250;
+
+/// How many times a key exchange packet is sent over a datagram transport before giving up
+pub const HANDSHAKE_ATTEMPTS: usize = 8;
+
+/// Keeps track of which recent message numbers have been received so that datagrams can arrive out of order but can't be replayed
+pub struct ReplayWindow {
+    /// one more than the newest message number received
+    next: u64,
+    /// bit i is set if message number next - 1 - i has been received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// A window which expects next_n next. Anything earlier is treated as already received.
+    pub fn new(next_n: u64) -> ReplayWindow {
+        ReplayWindow { next: next_n, seen: u64::max_value() }
+    }
+
+    /// Could this message number be received now?
+    pub fn acceptable(&self, n: u64) -> bool {
+        if n >= self.next {
+            true
+        } else if self.next - n > REPLAY_WINDOW_SIZE {
+            false
+        } else {
+            (self.seen & (1 << (self.next - 1 - n))) == 0
+        }
+    }
+
+    /// Record that a message number has been received. Only call this after the packet has been authenticated.
+    pub fn mark(&mut self, n: u64) {
+        if n >= self.next {
+            let shift = n - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = n + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - n);
+        }
+    }
+
+    /// The newest message number received (or one less than the first one expected)
+    pub fn newest(&self) -> u64 {
+        if self.next == 0 { 0 } else { self.next - 1 }
+    }
 }
 
 /// state for both the client and server
-pub struct ProtocolState {
-    pub stream: TcpStream,
-    pub long_keypair: Keypair,
-    pub next_send_n: u16,
-    pub next_recv_n: u16,
+pub struct ProtocolState<S: Transport> {
+    pub stream: S,
+    /// None if the key exchange used a pre-shared key or we are an anonymous device
+    pub long_keypair: Option<Keypair>,
+    pub next_send_n: u64,
+    pub next_recv_n: u64,
     pub session_keys: SessionKeys,
     pub send_as_device: bool,
+    /// the protocol version agreed during the key exchange
+    pub version: u8,
+    /// data which has been received but not yet read by the application, for each stream
+    pub read_buffs: HashMap<u16, Vec<u8>>,
+    /// fragments of messages which have not been completely received yet, for each stream
+    pub partial_messages: HashMap<u16, Vec<u8>>,
+    /// the amount of data in read_buffs and partial_messages
+    pub buffered_bytes: usize,
+    /// the id to give the next stream we open. The device uses odd numbers and the server even numbers so that they never pick the same one
+    pub next_stream_id: u32,
+    /// the number of streams we have opened and not closed yet
+    pub open_streams: usize,
+    /// the streams the peer has opened, found when it first uses them
+    pub peer_streams: HashSet<u16>,
+    /// streams the peer has opened which have not been handed to the application yet (oldest first)
+    pub new_peer_streams: VecDeque<u16>,
+    /// the number of streams the peer has opened and not closed yet
+    pub peer_open_streams: usize,
+    /// streams we have closed. Nothing more can be written on them
+    pub closed_streams: HashSet<u16>,
+    /// streams the peer has closed. Nothing more will arrive on them
+    pub peer_closed_streams: HashSet<u16>,
+    /// streams which a stream::Stream is using, so that there is only ever one handle writing on each
+    pub stream_handles: HashSet<u16>,
+    /// message numbers of the sent tracked message packets which the peer has not acknowledged yet (oldest first)
+    pub unacked: VecDeque<u64>,
+    /// the number of tracked message packets sent so far. Used as the handle for tracked writes
+    pub sent_count: u64,
+    /// the number of tracked message packets the peer has acknowledged so far
+    pub acked_count: u64,
+    /// our ephemeral keypair while we wait for the peer to answer our rekey packet. Data written until then is held back.
+    pub rekey_keypair: Option<Keypair>,
+    /// data packets written while waiting for the peer to answer our rekey packet (oldest first)
+    pub held_packets: VecDeque<HeldPacket>,
+    /// the amount of data in held_packets
+    pub held_bytes: usize,
+    /// acknowledgements which could not be sent because we were waiting for a rekey to complete
+    pub deferred_acks: Vec<u64>,
+    /// the read timeout asked for by the application (see blocking_off)
+    pub read_timeout: Option<Duration>,
+    /// ping the peer when nothing has been received from it for this long. None turns keepalives off
+    pub keepalive_interval: Option<Duration>,
+    /// give up on the peer when nothing has been received from it for this long. Only used when keepalives are on
+    pub liveness_timeout: Duration,
+    /// when we last received a packet from the peer
+    pub last_received: Instant,
+    /// when we last sent a ping
+    pub last_ping: Instant,
+    /// replaces the strict message number check over datagram transports. Unused over stream transports
+    pub replay_window: ReplayWindow,
+    /// over datagram transports, our last key exchange packet. It is sent again if the peer repeats the packet it answers
+    pub handshake_reply: Option<Vec<u8>>,
+    /// the long-term public key the peer proved it holds during the key exchange. None if the key exchange used a pre-shared key or the device is anonymous
+    pub peer_long_pk: Option<PublicKey>,
+    /// the id of the pre-shared key both parties proved they hold, if the key exchange used one
+    pub psk_id: Option<Vec<u8>>,
+    /// the newest resumption ticket the server has given us. Only the device receives these
+    pub resumption_ticket: Option<ResumptionTicket>,
+    /// derived from the session secret at the end of the key exchange. Keying material for the application comes from this
+    pub exporter_secret: Vec<u8>,
+    /// we have sent an end of stream packet so no more data can be written
+    pub write_closed: bool,
+    /// the peer has sent an end of stream packet so no more data will arrive once the read buffers are empty
+    pub peer_write_closed: bool,
 }
 
-impl Drop for ProtocolState {
-    fn drop(&mut self) {
-        {
-            let n = self.next_message_number();
+impl<S: Transport> ProtocolState<S> {
+    /// The state for a session which has just finished its key exchange
+    pub fn new(stream: S, long_keypair: Option<Keypair>, peer_long_pk: Option<PublicKey>, session_keys: SessionKeys, send_as_device: bool, version: u8, next_send_n: u64, next_recv_n: u64) -> ProtocolState<S> {
+        let exporter_secret = message::send::exporter_secret(&session_keys);
 
-            let ref session_keys = {
-                if self.send_as_device {
-                    &self.session_keys.from_device
-                } else {
-                    &self.session_keys.from_server
-                }
-            };
+        ProtocolState {
+            stream: stream,
+            long_keypair: long_keypair,
+            next_send_n: next_send_n,
+            next_recv_n: next_recv_n,
+            session_keys: session_keys,
+            send_as_device: send_as_device,
+            version: version,
+            read_buffs: HashMap::new(),
+            partial_messages: HashMap::new(),
+            buffered_bytes: 0,
+            next_stream_id: if send_as_device { 1 } else { 2 },
+            open_streams: 0,
+            peer_streams: HashSet::new(),
+            new_peer_streams: VecDeque::new(),
+            peer_open_streams: 0,
+            closed_streams: HashSet::new(),
+            peer_closed_streams: HashSet::new(),
+            stream_handles: HashSet::new(),
+            unacked: VecDeque::new(),
+            sent_count: 0,
+            acked_count: 0,
+            rekey_keypair: None,
+            held_packets: VecDeque::new(),
+            held_bytes: 0,
+            deferred_acks: Vec::new(),
+            read_timeout: None,
+            keepalive_interval: None,
+            liveness_timeout: Duration::from_secs(0),
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+            replay_window: ReplayWindow::new(next_recv_n),
+            handshake_reply: None,
+            peer_long_pk: peer_long_pk,
+            psk_id: None,
+            resumption_ticket: None,
+            exporter_secret: exporter_secret,
+            write_closed: false,
+            peer_write_closed: false,
+        }
+    }
+}
 
-            message::send::stop(&mut self.stream, session_keys, n);
-        } // stop borrowing self
+impl<S: Transport> Drop for ProtocolState<S> {
+    fn drop(&mut self) {
+        // try not to throw away data the application has written, but don't hang on a peer which has gone or stopped reading
+        if !self.held_packets.is_empty() {
+            self.keepalive_interval = None;
+            let _ = self.finish_rekey_within(Duration::from_millis(DROP_REKEY_WAIT_MS));
+        }
+
+        match self.next_message_number() {
+            Ok(n) => {
+                let session_keys = sending_keys(&self.session_keys, self.send_as_device);
+                message::send::stop(&mut self.stream, session_keys, n, self.version);
+            },
+            Err(_) => (),
+        };
         self.close();
+        memzero(&mut self.exporter_secret);
+    }
+}
+
+/// The symmetric state used to send (or to receive from the other party if send_as_device is negated)
+fn sending_keys(session_keys: &SessionKeys, send_as_device: bool) -> &symmetric::State {
+    if send_as_device {
+        &session_keys.from_device
+    } else {
+        &session_keys.from_server
+    }
+}
+
+/// Does this message number need different keys to the one before it?
+fn starts_epoch(message_number: u64) -> bool {
+    (message_number != 0) && (message::nonce_of(message_number) == 0)
+}
+
+fn message_error_to_io(message_error: message::Error, description: &str) -> io::Error {
+    match message_error {
+        message::Error::Read(ioerror) => ioerror,
+        message::Error::Write(ioerror) => ioerror,
+        _ => io::Error::new(io::ErrorKind::Other, description),
     }
 }
         
-impl ProtocolState {
-    fn next_message_number(&mut self) -> u16 {
-        if self.next_send_n == u16::max_value() {
-            let n = self.next_message_number();
-            send_error(&mut self.stream, n);
-            log("Panicked to prevent the message number from overflowing", LOG_RELEASE);
+impl<S: Transport> ProtocolState<S> {
+    fn next_message_number(&mut self) -> io::Result<u64> {
+        // rekeying before we get within REKEY_MARGIN should stop us from ever getting here
+        if self.next_send_n == message::max_message_number(self.version) {
+            log("Closing the connection to prevent the message number from overflowing", LOG_RELEASE);
             self.close();
-            panic!("Message number is about to overflow");
+            return Err(io::Error::new(io::ErrorKind::Other, "Message number is about to overflow"));
         }
 
         let ret = self.next_send_n;
         self.next_send_n += 1;
-        ret
+
+        if starts_epoch(ret) {
+            let state = message::send::epoch_state(&self.session_keys, self.send_as_device, message::epoch_of(ret));
+            if self.send_as_device {
+                self.session_keys.from_device = state;
+            } else {
+                self.session_keys.from_server = state;
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Make sure that the keys for the next message number we expect to receive are ready
+    fn prepare_recv_keys(&mut self) {
+        if starts_epoch(self.next_recv_n) {
+            let state = message::send::epoch_state(&self.session_keys, !self.send_as_device, message::epoch_of(self.next_recv_n));
+            if self.send_as_device {
+                self.session_keys.from_server = state;
+            } else {
+                self.session_keys.from_device = state;
+            }
+        }
+    }
+
+    /// Tell the peer that something went wrong. Older versions of the protocol can't say why.
+    fn send_error(&mut self, reason: message::ErrorReason) {
+        match self.next_message_number() {
+            Ok(n) => {
+                let result = if self.version >= message::SESSION_ERROR_VERSION {
+                    message::send::session_error(&mut self.stream, reason, sending_keys(&self.session_keys, self.send_as_device), n, self.version)
+                } else {
+                    message::send::error(&mut self.stream, n, self.version)
+                };
+
+                match result {
+                    Some(e) => log(&format!("Error encountered when sending an error packet: {:?}", e), LOG_DEBUG),
+                    None => log("Sent error packet", LOG_DEBUG),
+                }
+            },
+            Err(_) => (),
+        };
     }
 
-    fn check_recv_number(&mut self, num: u16) -> bool {
+    fn check_recv_number(&mut self, num: u64) -> bool {
         if self.next_recv_n != num {
-            let n = self.next_message_number();
-            send_error(&mut self.stream, n);
+            self.send_error(message::ErrorReason::BadMessageNumber);
             log("Received an out of order message number", LOG_DEBUG);
             return false;
         }
         
-        if self.next_recv_n == u16::max_value() {
-            let n = self.next_message_number();
-            send_error(&mut self.stream, n);
+        if self.next_recv_n == message::max_message_number(self.version) {
+            self.send_error(message::ErrorReason::Overflow);
             log("Failing receive message number check because the counter is about to overflow", LOG_RELEASE);
             return false;
         }
@@ -98,131 +467,1404 @@ impl ProtocolState {
         true
     }
 
-    fn close(&mut self) {
-        let _ = self.stream.shutdown(Shutdown::Both);
-        // force the session keys out of scope so they are drop()'ed
-        self.session_keys = SessionKeys {
-            from_device: symmetric::State::new(&[0; 32], &[0; 32]),
-            from_server: symmetric::State::new(&[0; 32], &[0; 32]),
+    /// Record an acknowledgement from the peer. Acknowledgements arrive in the same order as the messages they are for.
+    fn receive_ack(&mut self, acked_n: u64) -> bool {
+        match self.unacked.pop_front() {
+            Some(expected) if expected == acked_n => {
+                self.acked_count += 1;
+                true
+            },
+            _ => {
+                log(&format!("Received an unexpected acknowledgement for message number {}", acked_n), LOG_DEBUG);
+                false
+            },
+        }
+    }
+
+    fn send_ack(&mut self, acked_n: u64) {
+        let result = match self.rekey_if_needed() {
+            Ok(()) => {
+                if self.rekey_keypair.is_some() {
+                    self.deferred_acks.push(acked_n);
+                    return;
+                }
+
+                match self.next_message_number() {
+                    Ok(n) => {
+                        let session_keys = sending_keys(&self.session_keys, self.send_as_device);
+                        match message::send::ack(&mut self.stream, acked_n, session_keys, n, self.version) {
+                            None => Ok(()),
+                            Some(e) => Err(message_error_to_io(e, "error sending an acknowledgement")),
+                        }
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+            Err(e) => Err(e),
         };
+
+        match result {
+            Ok(()) => log("Sent an acknowledgement", LOG_DEBUG),
+            // the stream has probably died. Let the next read or write report it
+            Err(e) => log(&format!("Error sending an acknowledgement: {:?}", e), LOG_DEBUG),
+        }
     }
-}
 
-/// Read for both the server and client
-pub fn general_read(state: &mut ProtocolState, buf: &mut Vec<u8>) -> io::Result<usize> {
-    let m = {
-        let ref symmetric_state = {
-        if !state.send_as_device {
-                &state.session_keys.from_device
-            } else {
-                &state.session_keys.from_server
-            }
+    /// Put the last piece of a message (and any fragments received before it) into the stream's read buffer. Returns the length of the whole message.
+    fn complete_message(&mut self, stream: u16, mut last_piece: Vec<u8>) -> usize {
+        let read_buff = self.read_buffs.entry(stream).or_insert(Vec::new());
+        let len = match self.partial_messages.remove(&stream) {
+            Some(mut fragments) => {
+                let len = fragments.len() + last_piece.len();
+                read_buff.append(&mut fragments);
+                len
+            },
+            None => last_piece.len(),
         };
+        read_buff.append(&mut last_piece);
+        self.buffered_bytes += len;
+        len
+    }
+
+    /// How much of an incomplete message has been received on a stream so far
+    fn partial_message_len(&self, stream: u16) -> usize {
+        self.partial_messages.get(&stream).map_or(0, |m| m.len())
+    }
+
+    /// Pick an id for a new stream
+    fn open_stream(&mut self) -> io::Result<u16> {
+        if self.version < message::STREAMS_VERSION {
+            return Err(io::Error::new(io::ErrorKind::Other, "the peer does not support streams"));
+        }
+
+        if self.next_stream_id > (u16::max_value() as u32) {
+            return Err(io::Error::new(io::ErrorKind::Other, "no more stream ids are available"));
+        }
+
+        // the peer would close the session
+        if self.open_streams >= MAX_OPEN_STREAMS {
+            return Err(io::Error::new(io::ErrorKind::Other, "too many streams are open"));
+        }
+
+        let id = self.next_stream_id as u16;
+        self.next_stream_id += 2;
+        self.open_streams += 1;
+        Ok(id)
+    }
+
+    /// Is this one of the stream ids we pick? The device opens odd ids and the server even ones
+    fn opened_by_us(&self, stream: u16) -> bool {
+        (stream != message::DEFAULT_STREAM) && ((stream % 2 == 1) == self.send_as_device)
+    }
+
+    /// May the peer send on this stream? It can't use one of our ids before we have, or send anything on a stream after closing it
+    fn peer_may_send(&self, stream: u16) -> bool {
+        if self.peer_closed_streams.contains(&stream) {
+            return false;
+        }
+
+        !self.opened_by_us(stream) || ((stream as u32) < self.next_stream_id)
+    }
+
+    /// Has one of us opened this stream? The default stream is always open
+    fn stream_exists(&self, stream: u16) -> bool {
+        if stream == message::DEFAULT_STREAM {
+            true
+        } else if self.opened_by_us(stream) {
+            (stream as u32) < self.next_stream_id
+        } else {
+            self.peer_streams.contains(&stream)
+        }
+    }
+
+    /// May we write on this stream? One of us must have opened it and we must not have closed it
+    fn may_write(&self, stream: u16) -> bool {
+        !self.closed_streams.contains(&stream) && self.stream_exists(stream)
+    }
+
+    /// Is this a stream the peer is using for the first time?
+    fn is_new_peer_stream(&self, stream: u16) -> bool {
+        (stream != message::DEFAULT_STREAM) && !self.opened_by_us(stream) && !self.peer_streams.contains(&stream)
+    }
+
+    /// Remember a stream the first time the peer uses it, so that the application can find it
+    fn note_peer_stream(&mut self, stream: u16) {
+        if self.is_new_peer_stream(stream) {
+            self.peer_streams.insert(stream);
+            self.new_peer_streams.push_back(stream);
+            self.peer_open_streams += 1;
+        }
+    }
+
+    /// How much has been received on a stream and not read yet, including incomplete messages
+    fn buffered_len(&self, stream: u16) -> usize {
+        self.read_buffs.get(&stream).map_or(0, |b| b.len()) + self.partial_message_len(stream)
+    }
+
+    /// Throw away the buffers of a stream which both of us have closed once everything on it has been read. The id stays closed
+    fn forget_stream(&mut self, stream: u16) {
+        if self.closed_streams.contains(&stream) && self.peer_closed_streams.contains(&stream) && (self.buffered_len(stream) == 0) {
+            self.read_buffs.remove(&stream);
+            self.partial_messages.remove(&stream);
+        }
+    }
+
+    /// Copy as much of a stream's buffered data into buf as fits. Returns None if nothing is buffered
+    fn take_buffered(&mut self, stream: u16, buf: &mut [u8]) -> Option<usize> {
+        let num_elements = match self.read_buffs.get_mut(&stream) {
+            Some(ref mut read_buff) if !read_buff.is_empty() => {
+                let num_elements = if buf.len() > read_buff.len() {
+                    read_buff.len()
+                } else {
+                    buf.len()
+                };
 
-        let m = match message::receive::general(&mut state.stream, symmetric_state) {
-            Ok(m) => m,
-            Err(message_error) => {
-                match message_error {
-                    message::Error::Read(ioerror) => return Err(ioerror),
-                    _ => return Err(io::Error::new(io::ErrorKind::Other, "error receiving the message")),
+                for (i, byte) in read_buff.drain(0..num_elements).enumerate() {
+                    buf[i] = byte;
                 }
-            }
+
+                num_elements
+            },
+            _ => return None,
         };
-        m
-    }; // some messing with scope so that state is no-longer borrowed by symmetric_state
 
-    if !state.check_recv_number(m.number) {
-        return Err(io::Error::new(io::ErrorKind::Other, "received the wrong message number"));
+        self.buffered_bytes -= num_elements;
+        self.forget_stream(stream);
+        Some(num_elements)
     }
 
-    match m.content {
-        message::MessageContent::Message(mut v) => {
-            buf.append(&mut v);
-            log("Received a message packet", LOG_DEBUG);
-            return Ok(v.len());
-        },
-        message::MessageContent::Error => {
-            state.close();
-            log("Received error packet", LOG_RELEASE);
-            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Received error packet"));
-        },
-        message::MessageContent::Stop => {
-            state.close();
-            log("Received a stop packet. Closing the connection.", LOG_DEBUG);
-            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Recived stop packet"));
-        },
-        _ => {
-            log("Received unimplemented message!", LOG_RELEASE);
-            return Ok(0);
-        },
+    /// Has the application's read timeout passed since started?
+    fn read_timed_out(&self, started: Instant) -> bool {
+        self.read_timeout.map_or(false, |t| started.elapsed() >= t)
     }
-}
 
-/// Write for both server and client
-pub fn general_write(state: &mut ProtocolState, buf: &[u8]) -> io::Result<usize> {
-    if buf.len() > (u16::max_value() as usize) {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "The buffer was too long for a single message packet. Splitting it is not yet implemented. Please keep to buf.len() <= u16::max_value()"));
+    /// Ask the peer to show that it is still there
+    fn send_ping(&mut self) -> io::Result<()> {
+        self.last_ping = Instant::now();
+
+        // nothing else may be sent until the peer answers our rekey packet. That answer will do just as well as a pong
+        if self.rekey_keypair.is_some() {
+            return Ok(());
+        }
+
+        let n = match self.next_message_number() {
+            Ok(n) => n,
+            Err(e) => return Err(e),
+        };
+
+        match message::send::ping(&mut self.stream, sending_keys(&self.session_keys, self.send_as_device), n, self.version) {
+            None => {
+                log("Sent a ping", LOG_DEBUG);
+                Ok(())
+            },
+            Some(e) => Err(message_error_to_io(e, "error sending a ping")),
+        }
+    }
+
+    fn send_pong(&mut self) {
+        // the peer will hear from us when our rekey completes
+        if self.rekey_keypair.is_some() {
+            return;
+        }
+
+        let result = match self.next_message_number() {
+            Ok(n) => match message::send::pong(&mut self.stream, sending_keys(&self.session_keys, self.send_as_device), n, self.version) {
+                None => Ok(()),
+                Some(e) => Err(message_error_to_io(e, "error sending a pong")),
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => log("Sent a pong", LOG_DEBUG),
+            // the stream has probably died. Let the next read or write report it
+            Err(e) => log(&format!("Error sending a pong: {:?}", e), LOG_DEBUG),
+        }
     }
 
-    let message_n = state.next_message_number();
-    let ref symmetric_state = {
-        if state.send_as_device {
-            &state.session_keys.from_device
+    /// Ping the peer if keepalives are on and it has been quiet for the keepalive interval, unless we have pinged it within the interval already.
+    /// Called while writing as well as while reading so that a connection the application only writes to is not forgotten by NAT boxes
+    fn keepalive_if_due(&mut self) -> io::Result<()> {
+        let interval = match self.keepalive_interval {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        if (now.duration_since(self.last_received) >= interval) && (now.duration_since(self.last_ping) >= interval) {
+            self.send_ping()
         } else {
-            &state.session_keys.from_server
+            Ok(())
         }
-    };
+    }
 
-    match message::send::message(&mut state.stream, buf, symmetric_state, message_n) {
-        None => (),
-        Some(error) => {
-            match error {
-                message::Error::Write(ioerror) => return Err(ioerror),
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "error sending the message")),
+    /// Has the peer been quiet for longer than the liveness timeout? Only meaningful while keepalives are on and we are reading, because its pongs are only noticed by reads
+    fn peer_gone(&self) -> bool {
+        self.keepalive_interval.is_some() && (self.last_received.elapsed() >= self.liveness_timeout)
+    }
+
+    /// Block until the start of a packet arrives, pinging the peer when it has been quiet for too long and giving up on it after the liveness timeout.
+    /// Does nothing if keepalives are off. The application's read timeout is counted from started.
+    fn wait_for_packet(&mut self, started: Instant) -> io::Result<()> {
+        let interval = match self.keepalive_interval {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        loop {
+            let now = Instant::now();
+            let quiet_for = now.duration_since(self.last_received);
+
+            if quiet_for >= self.liveness_timeout {
+                // the application may not have read for a while, in which case the peer's answer could be waiting
+                match self.packet_waiting(Duration::from_millis(1)) {
+                    Ok(true) => return Ok(()),
+                    Ok(false) => (),
+                    Err(e) => return Err(e),
+                };
+
+                log("The peer stopped responding. Closing the connection.", LOG_RELEASE);
+                self.close();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the peer stopped responding"));
+            }
+
+            let since_ping = now.duration_since(self.last_ping);
+            if (quiet_for >= interval) && (since_ping >= interval) {
+                match self.send_ping() {
+                    Ok(()) => (),
+                    Err(e) => return Err(e),
+                };
+                continue;
+            }
+
+            // sleep until the next ping is due or the peer runs out of time
+            let mut wait = self.liveness_timeout - quiet_for;
+            let until_ping = if quiet_for >= interval {
+                interval - since_ping
+            } else {
+                interval - quiet_for
+            };
+            if until_ping < wait {
+                wait = until_ping;
+            }
+
+            // the application may not want to wait as long as that
+            match self.read_timeout {
+                Some(t) => {
+                    let waited = now.duration_since(started);
+                    if waited >= t {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+                    }
+                    if t - waited < wait {
+                        wait = t - waited;
+                    }
+                },
+                None => (),
+            };
+
+            // a zero timeout is not allowed
+            if wait < Duration::from_millis(1) {
+                wait = Duration::from_millis(1);
+            }
+
+            let result = match self.stream.set_read_timeout(Some(wait)) {
+                Ok(()) => self.stream.peek(&mut [0; 1]),
+                Err(e) => Err(e),
+            };
+
+            match self.stream.set_read_timeout(self.read_timeout) {
+                Ok(()) => (),
+                Err(e) => return Err(e),
+            };
+
+            match result {
+                // if the stream has closed the read will find out
+                Ok(_) => return Ok(()),
+                Err(ref e) if (e.kind() == io::ErrorKind::WouldBlock) || (e.kind() == io::ErrorKind::TimedOut) || (e.kind() == io::ErrorKind::Interrupted) => (),
+                Err(e) => return Err(e),
             }
         }
     }
 
-    log("Message sent successfully", LOG_DEBUG);
-    return Ok(buf.len());
-}
+    /// Begin replacing the session keys if we are running out of message numbers
+    fn rekey_if_needed(&mut self) -> io::Result<()> {
+        // a lost rekey packet would stop the session forever. Instead the connection is closed when the message numbers run out
+        if self.stream.is_datagram() {
+            return Ok(());
+        }
 
-/// Log level guaranteed to be printed on debug builds
-pub const LOG_DEBUG: u8 = 100;
+        if (self.next_send_n >= message::max_message_number(self.version) - REKEY_MARGIN) && self.rekey_keypair.is_none() {
+            self.start_rekey()
+        } else {
+            Ok(())
+        }
+    }
 
-/// Log level guaranteed to be printed on release builds
-pub const LOG_RELEASE: u8 = 10;
+    /// Send our half of a new key exchange. The new keys are put into use when the peer's rekey packet arrives.
+    fn start_rekey(&mut self) -> io::Result<()> {
+        let n = match self.next_message_number() {
+            Ok(n) => n,
+            Err(e) => return Err(e),
+        };
 
-const MIN_INCLUDED_LOG_LEVEL: u8 = LOG_DEBUG;
+        let keypair = key_exchange::gen_keypair();
 
-/// 0 = highest log level
-pub fn log(msg: &str, level: u8) {
-    if level <= MIN_INCLUDED_LOG_LEVEL {
-        match io::stderr().write(&format!("{}\n",msg).as_bytes()) {
-            Err(e) => panic!("Error writing to the log: {}", e),
-            Ok(_) => (),
+        match message::send::rekey(&mut self.stream, &keypair.0, sending_keys(&self.session_keys, self.send_as_device), n, self.version) {
+            None => (),
+            Some(e) => return Err(message_error_to_io(e, "error sending a rekey packet")),
+        };
+
+        log("Sent a rekey packet", LOG_DEBUG);
+        self.rekey_keypair = Some(keypair);
+        Ok(())
+    }
+
+    /// Send one packet of application data, or hold it back if we are waiting for the peer to answer our rekey packet
+    fn send_data_packet(&mut self, kind: DataPacket, stream: u16, data: &[u8]) -> io::Result<()> {
+        if self.rekey_keypair.is_some() {
+            self.held_bytes += data.len();
+            self.held_packets.push_back(HeldPacket { kind: kind, stream: stream, data: data.to_vec() });
+            return Ok(());
         }
+
+        let message_n = match self.next_message_number() {
+            Ok(n) => n,
+            Err(e) => return Err(e),
+        };
+
+        let result = {
+            let symmetric_state = sending_keys(&self.session_keys, self.send_as_device);
+            match kind {
+                DataPacket::Fragment => message::send::fragment(&mut self.stream, stream, data, symmetric_state, message_n, self.version),
+                DataPacket::Message => message::send::message(&mut self.stream, stream, data, symmetric_state, message_n, self.version),
+                DataPacket::TrackedMessage => message::send::tracked_message(&mut self.stream, stream, data, symmetric_state, message_n, self.version),
+                DataPacket::EndOfStream => message::send::end_of_stream(&mut self.stream, symmetric_state, message_n, self.version),
+                DataPacket::CloseStream => message::send::close_stream(&mut self.stream, stream, symmetric_state, message_n, self.version),
+            }
+        };
+
+        match result {
+            None => (),
+            Some(error) => return Err(message_error_to_io(error, "error sending the message")),
+        };
+
+        // the acknowledgement comes for the last packet, once the peer has the whole message
+        match kind {
+            DataPacket::TrackedMessage => self.unacked.push_back(message_n),
+            _ => (),
+        };
+
+        Ok(())
     }
-}
 
-/// Send an error message
-pub fn send_error(dest: &mut TcpStream, message_number: u16) -> bool {
-    let ret = match message::send::error(dest, message_number) {
-        Some(e) => {log(&format!("Error encountered when sending an error packet: {:?}", e), LOG_DEBUG); false},
-        None => {log("Sent error packet", LOG_DEBUG); true },
-    };
+    /// Wait up to wait for the start of a packet, without taking it out of the transport. A closed transport counts as a packet so that the next read finds out.
+    /// Fails if the transport can't time out reads or peek.
+    fn packet_waiting(&mut self, wait: Duration) -> io::Result<bool> {
+        let arrived = match self.stream.set_read_timeout(Some(wait)) {
+            Ok(()) => self.stream.peek(&mut [0; 1]),
+            Err(e) => Err(e),
+        };
 
-    ret
+        match self.stream.set_read_timeout(self.read_timeout) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        match arrived {
+            Ok(_) => Ok(true),
+            Err(ref e) if (e.kind() == io::ErrorKind::WouldBlock) || (e.kind() == io::ErrorKind::TimedOut) || (e.kind() == io::ErrorKind::Interrupted) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle the next packet if it arrives within wait. Transports which can't wait for a limited time block until it arrives.
+    /// Returns what receive_packet() does, or None if nothing arrived.
+    fn poll_packet(&mut self, wait: Duration, started: Instant) -> io::Result<Option<(u16, usize)>> {
+        match self.keepalive_if_due() {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        match self.packet_waiting(wait) {
+            Ok(false) if self.peer_gone() => {
+                log("The peer stopped responding. Closing the connection.", LOG_RELEASE);
+                self.close();
+                Err(io::Error::new(io::ErrorKind::TimedOut, "the peer stopped responding"))
+            },
+            Ok(false) => Ok(None),
+            // either it is there or we can't tell without reading it
+            _ => receive_packet(self, started),
+        }
+    }
+
+    /// Handle any packets which have already arrived, without blocking, in case the peer's answer to our rekey packet is among them.
+    /// Transports which can't time out reads or peek are left until the application next reads.
+    fn poll_rekey(&mut self) -> io::Result<()> {
+        while self.rekey_keypair.is_some() {
+            match self.packet_waiting(Duration::from_millis(1)) {
+                Ok(true) => (),
+                _ => return Ok(()),
+            };
+
+            match receive_packet(self, Instant::now()) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Handle a rekey packet from the peer. This is either the answer to our rekey packet or the peer starting a rekey.
+    fn receive_rekey(&mut self, their_pk: &PublicKey) -> io::Result<()> {
+        let keypair = match self.rekey_keypair.take() {
+            Some(k) => k,
+            None => {
+                // answer using the old keys
+                let n = match self.next_message_number() {
+                    Ok(n) => n,
+                    Err(e) => return Err(e),
+                };
+
+                let keypair = key_exchange::gen_keypair();
+
+                match message::send::rekey(&mut self.stream, &keypair.0, sending_keys(&self.session_keys, self.send_as_device), n, self.version) {
+                    None => (),
+                    Some(e) => return Err(message_error_to_io(e, "error answering a rekey packet")),
+                };
+
+                keypair
+            },
+        };
+
+        // everything after the rekey packets uses the new keys
+        self.session_keys = message::send::rekey_session_keys(&self.session_keys, &keypair, their_pk, self.send_as_device);
+        self.next_send_n = 0;
+        self.next_recv_n = 0;
+        self.replay_window = ReplayWindow::new(0);
+        log("Session keys replaced", LOG_DEBUG);
+
+        let deferred_acks: Vec<u64> = self.deferred_acks.drain(..).collect();
+        for acked_n in deferred_acks {
+            self.send_ack(acked_n);
+        }
+
+        let held_packets: Vec<HeldPacket> = self.held_packets.drain(..).collect();
+        self.held_bytes = 0;
+        for packet in held_packets {
+            match self.send_data_packet(packet.kind, packet.stream, &packet.data) {
+                Ok(()) => (),
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Block until any rekey we started has completed. Data received in the mean time is buffered. Only needed when we can't hold anything else back.
+    fn finish_rekey(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        while self.rekey_keypair.is_some() {
+            match receive_packet(self, started) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like finish_rekey() but gives up once wait has passed. Transports which can't time out reads don't wait at all
+    fn finish_rekey_within(&mut self, wait: Duration) -> io::Result<()> {
+        self.read_timeout = Some(wait);
+        match self.stream.set_read_timeout(self.read_timeout) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        let started = Instant::now();
+        while self.rekey_keypair.is_some() {
+            if self.read_timed_out(started) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the peer did not answer our rekey packet"));
+            }
+
+            match receive_packet(self, started) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let _ = self.stream.close();
+        // force the session keys out of scope so they are drop()'ed
+        self.session_keys = SessionKeys {
+            from_device: symmetric::State::new(&[0; 32], &[0; 32]),
+            from_server: symmetric::State::new(&[0; 32], &[0; 32]),
+            secret: Vec::new(),
+        };
+        self.rekey_keypair = None;
+        self.held_packets.clear();
+        self.held_bytes = 0;
+    }
+}
+
+/// Receive the next packet from a stream transport, where packets must arrive in order
+fn receive_stream_packet<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<Option<message::Message>> {
+    state.prepare_recv_keys();
+
+    let m = match message::receive::general(&mut state.stream, sending_keys(&state.session_keys, !state.send_as_device), state.version) {
+        Ok(m) => m,
+        Err(message_error) => {
+            // there is no way to find the start of the next packet after one of these
+            let reason = match message_error {
+                message::Error::Crypto => Some(message::ErrorReason::AuthFailure),
+                message::Error::BadPacket | message::Error::InvalidOpcode => Some(message::ErrorReason::Protocol),
+                _ => None,
+            };
+
+            match reason {
+                Some(r) => {
+                    state.send_error(r);
+                    state.close();
+                },
+                None => (),
+            };
+
+            return Err(message_error_to_io(message_error, "error receiving the message"));
+        },
+    };
+
+    // anyone can send an unauthenticated error so don't let one close the session
+    match m.content {
+        message::MessageContent::Error if state.version >= message::SESSION_ERROR_VERSION => {
+            log("Ignoring an unauthenticated error packet", LOG_RELEASE);
+            return Ok(None);
+        },
+        _ => (),
+    };
+
+    if !state.check_recv_number(m.number) {
+        return Err(io::Error::new(io::ErrorKind::Other, "received the wrong message number"));
+    }
+
+    Ok(Some(m))
+}
+
+/// Receive the next packet from a datagram transport. Returns None if the packet should be ignored because it is late, repeated or not authentic.
+fn receive_datagram<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<Option<message::Message>> {
+    // anyone can send us a datagram, so one which is too short to have a header is dropped like any other bad packet. receive_packet() ends it
+    let (opcode, n) = match message::receive::header(&mut state.stream, state.version) {
+        Ok(h) => h,
+        Err(message::Error::Read(e)) => return Err(e),
+        Err(e) => {
+            log(&format!("Dropping a packet with a bad header: {:?}", e), LOG_DEBUG);
+            return Ok(None);
+        },
+    };
+
+    // the peer didn't get our last key exchange packet
+    if message::repeats_handshake(opcode, state.send_as_device) {
+        match state.handshake_reply {
+            Some(ref packet) => {
+                log("The peer repeated its key exchange packet. Sending ours again.", LOG_DEBUG);
+                let _ = state.stream.write(packet);
+            },
+            None => (),
+        };
+        return Ok(None);
+    }
+
+    let acceptable = state.replay_window.acceptable(n) && (n != message::max_message_number(state.version));
+    let newest = state.replay_window.newest();
+
+    if !acceptable {
+        log(&format!("Dropping a late or repeated packet with message number {}", n), LOG_DEBUG);
+        return Ok(None);
+    }
+
+    // the keys for an earlier epoch have been thrown away
+    let epoch = message::epoch_of(n);
+    if epoch < message::epoch_of(newest) {
+        log("Dropping a packet from an earlier epoch", LOG_DEBUG);
+        return Ok(None);
+    }
+
+    let new_epoch_keys = if epoch > message::epoch_of(newest) {
+        Some(message::send::epoch_state(&state.session_keys, !state.send_as_device, epoch))
+    } else {
+        None
+    };
+
+    let result = match new_epoch_keys {
+        Some(ref keys) => message::receive::body(&mut state.stream, opcode, n, keys, state.version),
+        None => message::receive::body(&mut state.stream, opcode, n, sending_keys(&state.session_keys, !state.send_as_device), state.version),
+    };
+
+    let m = match result {
+        Ok(m) => m,
+        Err(message::Error::Read(e)) => return Err(e),
+        Err(e) => {
+            log(&format!("Dropping a bad packet: {:?}", e), LOG_DEBUG);
+            return Ok(None);
+        },
+    };
+
+    // anyone can send one of these
+    match m.content {
+        message::MessageContent::Error => {
+            log("Ignoring an unauthenticated error packet", LOG_RELEASE);
+            return Ok(None);
+        },
+        _ => (),
+    };
+
+    match new_epoch_keys {
+        Some(keys) => {
+            if state.send_as_device {
+                state.session_keys.from_server = keys;
+            } else {
+                state.session_keys.from_device = keys;
+            }
+        },
+        None => (),
+    };
+
+    state.replay_window.mark(n);
+
+    // the peer has our last key exchange packet
+    state.handshake_reply = None;
+
+    Ok(Some(m))
+}
+
+/// Receive and handle a single packet. Returns the stream and the number of bytes added to its read buffer if this was a message packet.
+/// started is when the caller began waiting, so that pongs don't keep resetting the application's read timeout.
+fn receive_packet<S: Transport>(state: &mut ProtocolState<S>, started: Instant) -> io::Result<Option<(u16, usize)>> {
+    match state.wait_for_packet(started) {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    let m = if state.stream.is_datagram() {
+        let result = receive_datagram(state);
+        state.stream.end_packet();
+        match result {
+            Ok(Some(m)) => m,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    } else {
+        match receive_stream_packet(state) {
+            Ok(Some(m)) => m,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    };
+
+    state.last_received = Instant::now();
+
+    match m.content {
+        message::MessageContent::Message(..) | message::MessageContent::TrackedMessage(..) | message::MessageContent::Fragment(..) | message::MessageContent::EndOfStream if state.peer_write_closed => {
+            state.send_error(message::ErrorReason::Protocol);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::InvalidData, "received data after the peer finished sending"))
+        },
+        message::MessageContent::CloseStream(message::DEFAULT_STREAM) => {
+            state.send_error(message::ErrorReason::Protocol);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::InvalidData, "the peer tried to close the default stream"))
+        },
+        message::MessageContent::Message(stream, _) | message::MessageContent::TrackedMessage(stream, _) | message::MessageContent::Fragment(stream, _) | message::MessageContent::CloseStream(stream) if !state.peer_may_send(stream) => {
+            state.send_error(message::ErrorReason::Protocol);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::InvalidData, "the peer used a stream which it had closed or not opened"))
+        },
+        message::MessageContent::Message(stream, ref v) | message::MessageContent::TrackedMessage(stream, ref v) | message::MessageContent::Fragment(stream, ref v) if state.partial_message_len(stream) + v.len() > MAX_MESSAGE_SIZE => {
+            state.send_error(message::ErrorReason::Protocol);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::InvalidData, "the peer sent a message which is too long"))
+        },
+        message::MessageContent::Message(stream, ref v) | message::MessageContent::TrackedMessage(stream, ref v) | message::MessageContent::Fragment(stream, ref v) if state.buffered_len(stream) + v.len() > MAX_STREAM_BUFFER => {
+            log(&format!("The peer sent more on stream {} than we keep for the application", stream), LOG_RELEASE);
+            state.send_error(message::ErrorReason::BufferFull);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::Other, "the peer sent more on a stream than we keep for the application"))
+        },
+        message::MessageContent::Message(_, ref v) | message::MessageContent::TrackedMessage(_, ref v) | message::MessageContent::Fragment(_, ref v) if state.buffered_bytes + v.len() > MAX_BUFFERED_DATA => {
+            log("The peer sent more than we keep for the application", LOG_RELEASE);
+            state.send_error(message::ErrorReason::BufferFull);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::Other, "the peer sent more than we keep for the application"))
+        },
+        message::MessageContent::Message(stream, _) | message::MessageContent::TrackedMessage(stream, _) | message::MessageContent::Fragment(stream, _) if state.is_new_peer_stream(stream) && (state.peer_open_streams >= MAX_OPEN_STREAMS) => {
+            log("The peer opened too many streams", LOG_RELEASE);
+            state.send_error(message::ErrorReason::Protocol);
+            state.close();
+            Err(io::Error::new(io::ErrorKind::InvalidData, "the peer opened too many streams"))
+        },
+        message::MessageContent::Message(stream, v) => {
+            log("Received a message packet", LOG_DEBUG);
+            state.note_peer_stream(stream);
+            Ok(Some((stream, state.complete_message(stream, v))))
+        },
+        message::MessageContent::TrackedMessage(stream, v) => {
+            state.send_ack(m.number);
+            log("Received a tracked message packet", LOG_DEBUG);
+            state.note_peer_stream(stream);
+            Ok(Some((stream, state.complete_message(stream, v))))
+        },
+        message::MessageContent::Fragment(stream, mut v) => {
+            state.note_peer_stream(stream);
+            state.buffered_bytes += v.len();
+            state.partial_messages.entry(stream).or_insert(Vec::new()).append(&mut v);
+            log("Received a fragment", LOG_DEBUG);
+            Ok(None)
+        },
+        message::MessageContent::CloseStream(stream) => {
+            log("The peer closed a stream", LOG_DEBUG);
+            state.note_peer_stream(stream);
+            state.peer_closed_streams.insert(stream);
+            if !state.opened_by_us(stream) {
+                state.peer_open_streams -= 1;
+            }
+            // the rest of a message which was cut short will never arrive
+            let cut_short = state.partial_message_len(stream);
+            state.buffered_bytes -= cut_short;
+            state.partial_messages.remove(&stream);
+            state.forget_stream(stream);
+            // wakes up a reader waiting on the stream
+            Ok(Some((stream, 0)))
+        },
+        message::MessageContent::Ack(acked_n) => {
+            if !state.receive_ack(acked_n) {
+                state.send_error(message::ErrorReason::Protocol);
+                state.close();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "received an acknowledgement for a message which was not sent"));
+            }
+            log("Received an acknowledgement", LOG_DEBUG);
+            Ok(None)
+        },
+        message::MessageContent::ReKey(their_pk) => {
+            log("Received a rekey packet", LOG_DEBUG);
+            match state.receive_rekey(&their_pk) {
+                Ok(()) => Ok(None),
+                Err(e) => {
+                    state.close();
+                    Err(e)
+                },
+            }
+        },
+        message::MessageContent::Ping => {
+            log("Received a ping", LOG_DEBUG);
+            state.send_pong();
+            Ok(None)
+        },
+        message::MessageContent::Pong => {
+            log("Received a pong", LOG_DEBUG);
+            Ok(None)
+        },
+        message::MessageContent::Ticket(secret, ticket) => {
+            let server_long_pk = match state.peer_long_pk {
+                Some(ref pk) if state.send_as_device => pk.clone(),
+                _ => {
+                    state.send_error(message::ErrorReason::Protocol);
+                    state.close();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "received a resumption ticket which we could not use"));
+                },
+            };
+            log("Received a resumption ticket", LOG_DEBUG);
+            state.resumption_ticket = Some(ResumptionTicket { secret: secret, ticket: ticket, server_long_pk: server_long_pk });
+            Ok(None)
+        },
+        message::MessageContent::Error => {
+            state.close();
+            log("Received error packet", LOG_RELEASE);
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Received error packet"))
+        },
+        message::MessageContent::SessionError(reason) => {
+            state.close();
+            log(&format!("Received error packet: {}", reason), LOG_RELEASE);
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason))
+        },
+        message::MessageContent::Stop => {
+            state.close();
+            log("Received a stop packet. Closing the connection.", LOG_DEBUG);
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Recived stop packet"))
+        },
+        message::MessageContent::EndOfStream => {
+            log("Received an end of stream packet", LOG_DEBUG);
+            state.peer_write_closed = true;
+            // wakes up a reader waiting on the default stream. Readers of other streams notice peer_write_closed
+            Ok(Some((message::DEFAULT_STREAM, 0)))
+        },
+        _ => {
+            log("Received unimplemented message!", LOG_RELEASE);
+            Ok(None)
+        },
+    }
+}
+
+/// Read for both the server and client. Received data is appended to the read buffer for its stream. Returns the stream and the amount of data received.
+pub fn general_read<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<(u16, usize)> {
+    let started = Instant::now();
+    loop {
+        match receive_packet(state, started) {
+            Ok(Some(n)) => return Ok(n),
+            Ok(None) => (), // control packets carry no data for the caller so keep reading
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write for both server and client
+pub fn general_write<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &[u8]) -> io::Result<usize> {
+    send_data(state, stream, buf, false)
+}
+
+/// Write for both server and client, returning a handle which can be passed to general_wait_for_ack
+pub fn general_write_tracked<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &[u8]) -> io::Result<u64> {
+    match send_data(state, stream, buf, true) {
+        Ok(_) => Ok(state.sent_count),
+        Err(e) => Err(e),
+    }
+}
+
+fn send_data<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &[u8], tracked: bool) -> io::Result<usize> {
+    let num_packets = match general_prepare_write(state, stream, buf, tracked) {
+        Ok(n) => n,
+        Err(e) => return Err(e),
+    };
+
+    for i in 0..num_packets {
+        match general_write_packet(state, stream, buf, i, num_packets, tracked) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+    }
+
+    log("Message sent successfully", LOG_DEBUG);
+    return Ok(buf.len());
+}
+
+/// Check that buf can be written on stream and work out how many data packets it needs
+pub fn general_prepare_write<S: Transport>(state: &ProtocolState<S>, stream: u16, buf: &[u8], tracked: bool) -> io::Result<usize> {
+    if state.write_closed {
+        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "writing has been shut down"));
+    }
+
+    if (stream != message::DEFAULT_STREAM) && (state.version < message::STREAMS_VERSION) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the peer does not support streams"));
+    }
+
+    if !state.may_write(stream) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "there is no such stream or it has been closed"));
+    }
+
+    // lost datagrams are not sent again so nobody would ever acknowledge them or finish reassembling them
+    if state.stream.is_datagram() {
+        if tracked {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tracked writes are not supported over datagram transports"));
+        }
+
+        if buf.len() > MAX_DATAGRAM_DATA {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too much data for one datagram"));
+        }
+    }
+
+    // an empty write still sends one (empty) message
+    if buf.is_empty() {
+        return Ok(1);
+    }
+
+    // the peer would refuse to put back together a message bigger than MAX_MESSAGE_SIZE so the data is split into messages of that size
+    let last_message_len = buf.len() % MAX_MESSAGE_SIZE;
+    Ok((buf.len() / MAX_MESSAGE_SIZE) * packets_per_message() + (last_message_len + message::send::MAX_PACKET_DATA - 1) / message::send::MAX_PACKET_DATA)
+}
+
+/// The number of data packets in a message of MAX_MESSAGE_SIZE
+fn packets_per_message() -> usize {
+    (MAX_MESSAGE_SIZE + message::send::MAX_PACKET_DATA - 1) / message::send::MAX_PACKET_DATA
+}
+
+/// Send packet i of the num_packets packets which general_prepare_write() said buf needs.
+/// The packets of one write must be sent in order, but packets for other streams may be sent in between.
+pub fn general_write_packet<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &[u8], i: usize, num_packets: usize, tracked: bool) -> io::Result<()> {
+    // packet i is packet i % packets_per_message() of the message it belongs to
+    let message_start = (i / packets_per_message()) * MAX_MESSAGE_SIZE;
+    let message_end = cmp::min(message_start + MAX_MESSAGE_SIZE, buf.len());
+    let start = message_start + (i % packets_per_message()) * message::send::MAX_PACKET_DATA;
+    let end = cmp::min(start + message::send::MAX_PACKET_DATA, message_end);
+    let last_packet = i == (num_packets - 1);
+
+    match state.rekey_if_needed() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    match state.keepalive_if_due() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    // the peer may have answered our rekey packet already. Too much held back data would use up our memory so then we wait for it
+    if state.rekey_keypair.is_some() {
+        match state.poll_rekey() {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        if state.held_bytes >= MAX_REKEY_BACKLOG {
+            match state.finish_rekey() {
+                Ok(()) => (),
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    // only the last message of a tracked write is acknowledged
+    let kind = if end != message_end {
+        DataPacket::Fragment
+    } else if tracked && last_packet {
+        DataPacket::TrackedMessage
+    } else {
+        DataPacket::Message
+    };
+
+    match state.send_data_packet(kind, stream, &buf[start..end]) {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    if tracked && last_packet {
+        state.sent_count += 1;
+    }
+
+    Ok(())
+}
+
+/// Block until the peer has acknowledged the tracked write with the given handle. Any data received in the mean time is kept in state.read_buffs.
+pub fn general_wait_for_ack<S: Transport>(state: &mut ProtocolState<S>, handle: u64) -> io::Result<()> {
+    if handle > state.sent_count {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no message has been sent with that handle"));
+    }
+
+    let started = Instant::now();
+    while state.acked_count < handle {
+        match receive_packet(state, started) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Like general_wait_for_ack() but only waits up to wait for the network. Returns whether the write has been acknowledged yet.
+pub fn general_poll_ack<S: Transport>(state: &mut ProtocolState<S>, handle: u64, started: Instant, wait: Duration) -> io::Result<bool> {
+    if handle > state.sent_count {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no message has been sent with that handle"));
+    }
+
+    if state.acked_count >= handle {
+        return Ok(true);
+    }
+
+    if state.read_timed_out(started) {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+    }
+
+    match state.poll_packet(wait, started) {
+        Ok(_) => Ok(state.acked_count >= handle),
+        Err(e) => Err(e),
+    }
+}
+
+/// Send anything held back by a rekey we started, blocking until the peer answers it, then flush the transport
+pub fn general_flush<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<()> {
+    match state.finish_rekey() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    state.stream.flush()
+}
+
+/// Start replacing the session keys now rather than waiting for the message numbers to run out. The new keys are put into use when the peer's answer is received.
+pub fn general_rekey<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<()> {
+    if state.stream.is_datagram() {
+        return Err(io::Error::new(io::ErrorKind::Other, "rekeying is not supported over datagram transports"));
+    }
+
+    if state.rekey_keypair.is_some() {
+        return Ok(());
+    }
+
+    state.start_rekey()
+}
+
+/// Tell the peer that we will not send any more data. We can still read, and the peer can still write, until the session is dropped.
+/// Writes fail from now on. Fails if the peer is too old to understand this or the transport is a datagram one, because the packet could be lost.
+pub fn general_shutdown_write<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<()> {
+    if state.version < message::HALF_CLOSE_VERSION {
+        return Err(io::Error::new(io::ErrorKind::Other, "the peer does not support half-closing the session"));
+    }
+
+    if state.stream.is_datagram() {
+        return Err(io::Error::new(io::ErrorKind::Other, "half-closing is not supported over datagram transports"));
+    }
+
+    if state.write_closed {
+        return Ok(());
+    }
+
+    match state.rekey_if_needed() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    match state.send_data_packet(DataPacket::EndOfStream, message::DEFAULT_STREAM, &[]) {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    state.write_closed = true;
+    log("Sent an end of stream packet", LOG_DEBUG);
+    Ok(())
+}
+
+/// Copy buffered data for a stream out of its read buffer, reading from the network first if there is nothing buffered.
+/// Data which arrives for other streams in the mean time is buffered for them.
+pub fn general_read_into<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        // data may already have been buffered while waiting for something else
+        match state.take_buffered(stream, buf) {
+            Some(n) => return Ok(n),
+            None => (),
+        };
+
+        if state.peer_write_closed || state.peer_closed_streams.contains(&stream) {
+            return Ok(0);
+        }
+
+        match general_read(state) {
+            // an empty message still wakes up the reader
+            Ok((s, 0)) if s == stream => return Ok(0),
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+/// Like general_read_into() but only waits up to wait for the network, returning None if nothing arrived for this stream in that time. The application's read timeout is counted from started.
+/// This lets several streams take turns to read from one session.
+pub fn general_poll_read_into<S: Transport>(state: &mut ProtocolState<S>, stream: u16, buf: &mut [u8], started: Instant, wait: Duration) -> io::Result<Option<usize>> {
+    match state.take_buffered(stream, buf) {
+        Some(n) => return Ok(Some(n)),
+        None => (),
+    };
+
+    if state.peer_write_closed || state.peer_closed_streams.contains(&stream) {
+        return Ok(Some(0));
+    }
+
+    if state.read_timed_out(started) {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+    }
+
+    match state.poll_packet(wait, started) {
+        // an empty message still wakes up the reader
+        Ok(Some((s, 0))) if s == stream => Ok(Some(0)),
+        Ok(_) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Open a new stream to the peer
+pub fn general_open_stream<S: Transport>(state: &mut ProtocolState<S>) -> io::Result<u16> {
+    state.open_stream()
+}
+
+/// Reserve a stream for one stream::Stream handle. Fails if neither of us has opened the stream or there is already a handle for it
+pub fn general_claim_stream<S: Transport>(state: &mut ProtocolState<S>, stream: u16) -> io::Result<()> {
+    if !state.stream_exists(stream) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "there is no such stream"));
+    }
+
+    if !state.stream_handles.insert(stream) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the stream is already in use"));
+    }
+
+    Ok(())
+}
+
+/// The next stream the peer has opened which the application has not been given yet. Waits up to wait for the network if there is none, returning None if none was opened in that time.
+pub fn general_poll_accept_stream<S: Transport>(state: &mut ProtocolState<S>, started: Instant, wait: Duration) -> io::Result<Option<u16>> {
+    match state.new_peer_streams.pop_front() {
+        Some(id) => return Ok(Some(id)),
+        None => (),
+    };
+
+    if state.peer_write_closed {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the peer has finished sending"));
+    }
+
+    if state.read_timed_out(started) {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+    }
+
+    match state.poll_packet(wait, started) {
+        Ok(_) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tell the peer that we will not write on this stream again. Its reads on the stream return 0 once it has read everything sent before, and it can still write on the stream until it closes it too.
+/// The default stream is closed with general_shutdown_write() instead. Fails over datagram transports, where the packet could be lost.
+pub fn general_close_stream<S: Transport>(state: &mut ProtocolState<S>, stream: u16) -> io::Result<()> {
+    if stream == message::DEFAULT_STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the default stream can only be closed by shutting down writing"));
+    }
+
+    if state.stream.is_datagram() {
+        return Err(io::Error::new(io::ErrorKind::Other, "closing a stream is not supported over datagram transports"));
+    }
+
+    if state.closed_streams.contains(&stream) {
+        return Ok(());
+    }
+
+    if !state.may_write(stream) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "there is no such stream"));
+    }
+
+    match state.rekey_if_needed() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    match state.send_data_packet(DataPacket::CloseStream, stream, &[]) {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    state.closed_streams.insert(stream);
+    if state.opened_by_us(stream) {
+        state.open_streams -= 1;
+    }
+    state.forget_stream(stream);
+    log("Closed a stream", LOG_DEBUG);
+    Ok(())
+}
+
+/// Turn keepalives on or off. While reading or writing, the peer is pinged after it has been quiet for interval. While reading, the connection is closed if it stays quiet for liveness_timeout.
+pub fn general_set_keepalive<S: Transport>(state: &mut ProtocolState<S>, interval: Option<Duration>, liveness_timeout: Duration) -> io::Result<()> {
+    if interval.is_some() && (state.version < message::KEEPALIVE_VERSION) {
+        return Err(io::Error::new(io::ErrorKind::Other, "the peer does not support keepalives"));
+    }
+
+    // every read would fail waiting for the next packet
+    if interval.is_some() && !state.stream.can_peek() {
+        return Err(io::Error::new(io::ErrorKind::Other, "this transport does not support keepalives"));
+    }
+
+    state.keepalive_interval = interval;
+    state.liveness_timeout = liveness_timeout;
+    // don't count time from before keepalives were turned on against the peer
+    state.last_received = Instant::now();
+    state.last_ping = state.last_received;
+    Ok(())
+}
+
+/// Give the device a ticket which it can use to resume the session until lifetime has passed. The ticket is sealed with ticket_key.
+pub fn general_issue_ticket<S: Transport>(state: &mut ProtocolState<S>, ticket_key: &[u8], lifetime: Duration) -> io::Result<()> {
+    if state.version < message::RESUMPTION_VERSION {
+        return Err(io::Error::new(io::ErrorKind::Other, "the peer does not support resumption tickets"));
+    }
+
+    // the ticket says which device it belongs to by its long-term key
+    let device_long_pk = match state.peer_long_pk {
+        Some(ref pk) => pk.clone(),
+        None => return Err(io::Error::new(io::ErrorKind::Other, "the device has no long-term key to issue a resumption ticket for")),
+    };
+
+    // and which of our keys the device authenticated, so that a resumed session goes on with the same one
+    let server_long_pk_id = match state.long_keypair {
+        Some(ref keypair) => key_id::id_of_pk(&keypair.0),
+        None => return Err(io::Error::new(io::ErrorKind::Other, "we have no long-term key to issue a resumption ticket with")),
+    };
+
+    match state.rekey_if_needed() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    match state.finish_rekey() {
+        Ok(()) => (),
+        Err(e) => return Err(e),
+    };
+
+    let n = match state.next_message_number() {
+        Ok(n) => n,
+        Err(e) => return Err(e),
+    };
+
+    let expiry = unix_time().saturating_add(lifetime.as_secs());
+    let mut secret = randombytes::randombytes(message::RESUMPTION_SECRET_BYTES);
+    let ticket = message::send::seal_ticket(ticket_key, &secret, &device_long_pk, &server_long_pk_id, expiry);
+
+    let result = message::send::ticket(&mut state.stream, &secret, &ticket, sending_keys(&state.session_keys, state.send_as_device), n, state.version);
+    memzero(&mut secret);
+
+    match result {
+        None => {
+            log("Sent a resumption ticket", LOG_DEBUG);
+            Ok(())
+        },
+        Some(e) => Err(message_error_to_io(e, "error sending a resumption ticket")),
+    }
+}
+
+/// The number of seconds since the UNIX epoch. Used for ticket expiry times
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Tell the peer why the session is over and close it
+pub fn general_close_with_error<S: Transport>(state: &mut ProtocolState<S>, reason: message::ErrorReason) {
+    state.send_error(reason);
+    state.close();
+}
+
+/// Give up on reads after blocking for the timeout (or never if None)
+pub fn general_set_read_timeout<S: Transport>(state: &mut ProtocolState<S>, timeout: Option<Duration>) {
+    state.read_timeout = timeout;
+    state.stream.set_read_timeout(timeout).unwrap();
+}
+
+/// Log level guaranteed to be printed on debug builds
+pub const LOG_DEBUG: u8 = 100;
+
+/// Log level guaranteed to be printed on release builds
+pub const LOG_RELEASE: u8 = 10;
+
+const MIN_INCLUDED_LOG_LEVEL: u8 = LOG_DEBUG;
+
+/// 0 = highest log level
+pub fn log(msg: &str, level: u8) {
+    if level <= MIN_INCLUDED_LOG_LEVEL {
+        match io::stderr().write(&format!("{}\n",msg).as_bytes()) {
+            Err(e) => panic!("Error writing to the log: {}", e),
+            Ok(_) => (),
+        }
+    }
+}
+
+/// Wait for the answer to a key exchange packet which has already been sent.
+/// Over datagram transports the packet is sent again if no answer arrives in time, and anything which isn't a valid answer is ignored.
+pub fn await_handshake_reply<S, T, F>(stream: &mut S, sent_packet: &[u8], mut receive: F) -> Result<T, message::Error>
+    where S: Transport, F: FnMut(&mut S) -> Result<T, message::Error> {
+    if !stream.is_datagram() {
+        let result = receive(stream);
+        stream.end_packet();
+        return result;
+    }
+
+    match stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_RETRANSMIT_INTERVAL_MS))) {
+        Ok(()) => (),
+        Err(e) => return Err(message::Error::Read(e)),
+    };
+
+    let mut result = Err(message::Error::Read(io::Error::new(io::ErrorKind::TimedOut, "no answer to the key exchange")));
+
+    'attempts: for attempt in 0..HANDSHAKE_ATTEMPTS {
+        if attempt > 0 {
+            log("No answer to our key exchange packet. Sending it again.", LOG_DEBUG);
+            match stream.write(sent_packet) {
+                Ok(_) => (),
+                Err(e) => {
+                    result = Err(message::Error::Write(e));
+                    break 'attempts;
+                },
+            };
+        }
+
+        loop {
+            let received = receive(stream);
+            stream.end_packet();
+
+            match received {
+                Ok(m) => {
+                    result = Ok(m);
+                    break 'attempts;
+                },
+                // nothing arrived in time
+                Err(message::Error::Read(ref e)) if (e.kind() == io::ErrorKind::WouldBlock) || (e.kind() == io::ErrorKind::TimedOut) || (e.kind() == io::ErrorKind::ConnectionRefused) => break,
+                Err(message::Error::Read(e)) => {
+                    result = Err(message::Error::Read(e));
+                    break 'attempts;
+                },
+                Err(e) => log(&format!("Ignoring a bad packet during the key exchange: {:?}", e), LOG_DEBUG),
+            };
+        }
+    }
+
+    match stream.set_read_timeout(None) {
+        Ok(()) => result,
+        Err(e) => Err(message::Error::Read(e)),
+    }
+}
+
+/// Send an error message during the key exchange
+pub fn send_error<W: Write>(dest: &mut W, message_number: u64) -> bool {
+    let ret = match message::send::error(dest, message_number, message::HANDSHAKE_VERSION) {
+        Some(e) => {log(&format!("Error encountered when sending an error packet: {:?}", e), LOG_DEBUG); false},
+        None => {log("Sent error packet", LOG_DEBUG); true },
+    };
+
+    ret
+}
+
+/// Refuse a key exchange, saying why if the device speaks a version which can be told. version is the one chosen from the device's offer
+pub fn send_rejection<W: Write>(dest: &mut W, version: u8, reason: message::ErrorReason) -> bool {
+    if version < message::REJECT_VERSION {
+        return send_error(dest, 0);
+    }
+
+    match message::send::reject(dest, reason, 0) {
+        Some(e) => {log(&format!("Error encountered when sending a rejection packet: {:?}", e), LOG_DEBUG); false},
+        None => {log(&format!("Rejected the key exchange: {}", reason), LOG_DEBUG); true },
+    }
+}
+
+/// Fail if the peer's long-term key has been revoked. Anything else is left to the trusted public keys
+pub fn check_revocation(revocations: Option<&RevocationList>, peer_long_pk: &PublicKey) -> Result<(), Error> {
+    let revocation = match revocations {
+        Some(list) => match list.check(peer_long_pk) {
+            Some(r) => r.clone(),
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let peer = fingerprint(peer_long_pk);
+    log(&format!("The peer's key {} has been revoked: {}", peer, revocation.reason), LOG_RELEASE);
+    Err(Error::Revoked(peer, revocation))
 }
 
 /// Check that a message number looks correct
-pub fn check_message_n(next_n: &mut u16, m: &message::Message) -> bool {
+pub fn check_message_n(next_n: &mut u64, m: &message::Message) -> bool {
     if m.number != *next_n {
         log(&format!("Expected message number = {}. Received message number {}. Aborting.", *next_n, m.number), LOG_DEBUG);
         return false;
     }
 
-    if *next_n == u16::max_value() {
+    if *next_n == message::max_message_number(message::HANDSHAKE_VERSION) {
         log("next_n is going to overflow!", LOG_RELEASE);
         return false;
     }
@@ -0,0 +1,352 @@
+//! Datagram (UDP) transport
+//!
+//! Every packet is sent as its own datagram. The session accepts packets which arrive out of order (within a replay window) and quietly drops packets which are lost, repeated or don't authenticate, so the application sees a lossy, unordered channel.
+//! Packets in the key exchange are sent again if they seem to have been lost.
+//! A server accepts devices from a DatagramListener, which shares one socket between all of them by sorting datagrams by the address they came from.
+//!
+//! Over datagrams, tracked writes and rekeying are not supported and each write must fit in one packet (MAX_DATAGRAM_DATA bytes).
+
+/*  This file is part of project-net.
+    project-net is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-net is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-net.  If not, see http://www.gnu.org/licenses/.*/
+
+use std::io;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use common::Transport;
+
+pub use common::MAX_DATAGRAM_DATA;
+
+/// The largest datagram we can receive
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// How many datagrams are kept for a peer which is not reading them, or how many new peers may wait to be accepted. Anything more is dropped
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// A UDP socket shared by every peer which a DatagramListener has accepted. Datagrams are sorted into a queue for each peer by the address they came from.
+struct Demux {
+    socket: UdpSocket,
+    queues: Mutex<Queues>,
+    /// signalled when a datagram has been queued or when nobody is receiving from the socket any more
+    changed: Condvar,
+}
+
+struct Queues {
+    /// datagrams which have arrived for each peer and have not been read yet
+    peers: HashMap<SocketAddr, VecDeque<Vec<u8>>>,
+    /// peers which have sent datagrams but have not been accepted yet
+    new_peers: VecDeque<SocketAddr>,
+    /// true while a thread is blocked receiving from the socket on everybody's behalf
+    receiving: bool,
+}
+
+/// Who is waiting for a datagram from the shared socket
+#[derive(Clone, Copy)]
+enum Waiter {
+    Peer(SocketAddr),
+    Listener,
+}
+
+impl Demux {
+    /// Take what the waiter is waiting for out of the queues, if it has arrived
+    fn take(queues: &mut Queues, waiter: Waiter) -> Option<(SocketAddr, Option<Vec<u8>>)> {
+        match waiter {
+            Waiter::Peer(addr) => match queues.peers.get_mut(&addr) {
+                Some(queue) => queue.pop_front().map(|d| (addr, Some(d))),
+                None => None,
+            },
+            Waiter::Listener => queues.new_peers.pop_front().map(|addr| (addr, None)),
+        }
+    }
+
+    /// Put a datagram into the queue for the peer it came from. Datagrams from peers we don't know yet are kept for the listener to accept.
+    fn sort(queues: &mut Queues, from: SocketAddr, datagram: Vec<u8>) {
+        if !queues.peers.contains_key(&from) {
+            if queues.new_peers.len() >= MAX_QUEUED_DATAGRAMS {
+                return;
+            }
+            queues.peers.insert(from, VecDeque::new());
+            queues.new_peers.push_back(from);
+        }
+
+        let queue = queues.peers.get_mut(&from).unwrap(); // inserted above if it was missing
+        if queue.len() < MAX_QUEUED_DATAGRAMS {
+            queue.push_back(datagram);
+        }
+    }
+
+    /// Block until what the waiter is waiting for arrives or the timeout runs out. Whichever waiter is blocked on the socket sorts what arrives for the others.
+    fn wait(&self, waiter: Waiter, timeout: Option<Duration>) -> io::Result<(SocketAddr, Option<Vec<u8>>)> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut queues = self.queues.lock().unwrap();
+
+        loop {
+            match Demux::take(&mut queues, waiter) {
+                Some(found) => return Ok(found),
+                None => (),
+            };
+
+            let remaining = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out waiting for a datagram"));
+                    }
+                    Some(d - now)
+                },
+                None => None,
+            };
+
+            if queues.receiving {
+                // someone else is receiving from the socket and will wake us when they have sorted what arrived
+                queues = match remaining {
+                    Some(r) => self.changed.wait_timeout(queues, r).unwrap().0,
+                    None => self.changed.wait(queues).unwrap(),
+                };
+                continue;
+            }
+
+            queues.receiving = true;
+            drop(queues);
+
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            let received = match self.socket.set_read_timeout(remaining) {
+                Ok(()) => self.socket.recv_from(&mut buffer),
+                Err(e) => Err(e),
+            };
+
+            queues = self.queues.lock().unwrap();
+            queues.receiving = false;
+            self.changed.notify_all();
+
+            match received {
+                Ok((len, from)) => {
+                    buffer.truncate(len);
+                    Demux::sort(&mut queues, from, buffer);
+                },
+                Err(ref e) if (e.kind() == io::ErrorKind::WouldBlock) || (e.kind() == io::ErrorKind::TimedOut) || (e.kind() == io::ErrorKind::Interrupted) => (),
+                // an ICMP error caused by something we sent to one peer must not stop the others from receiving
+                Err(ref e) if (e.kind() == io::ErrorKind::ConnectionRefused) || (e.kind() == io::ErrorKind::ConnectionReset) => (),
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    /// Stop sorting datagrams for a peer which has gone away. If it sends any more it looks like a new peer
+    fn forget(&self, peer: SocketAddr) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.peers.remove(&peer);
+    }
+}
+
+/// A bound UDP socket which accepts datagram sessions from any number of devices. Every device gets its own Datagram which shares the socket.
+#[derive(Clone)]
+pub struct DatagramListener {
+    demux: Arc<Demux>,
+}
+
+impl DatagramListener {
+    /// Accept devices on a socket which is already bound
+    pub fn new(socket: UdpSocket) -> DatagramListener {
+        DatagramListener {
+            demux: Arc::new(Demux {
+                socket: socket,
+                queues: Mutex::new(Queues { peers: HashMap::new(), new_peers: VecDeque::new(), receiving: false }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Bind to local_addr and accept devices on it
+    pub fn bind<A: ToSocketAddrs>(local_addr: A) -> io::Result<DatagramListener> {
+        match UdpSocket::bind(local_addr) {
+            Ok(s) => Ok(DatagramListener::new(s)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait for a datagram from a peer which we aren't already talking to. Its datagrams are left to be read from the result.
+    pub fn accept(&self) -> io::Result<Datagram> {
+        match self.demux.wait(Waiter::Listener, None) {
+            Ok((peer, _)) => Ok(Datagram::with_inner(Inner::Shared(self.demux.clone(), peer))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.demux.socket.local_addr()
+    }
+}
+
+enum Inner {
+    /// a socket of our own, connected to the peer
+    Connected(UdpSocket),
+    /// a peer of a DatagramListener
+    Shared(Arc<Demux>, SocketAddr),
+}
+
+/// A UDP socket talking to one peer
+pub struct Datagram {
+    inner: Inner,
+    /// the datagram currently being read
+    buffer: Vec<u8>,
+    /// how much of buffer has been read
+    pos: usize,
+    /// false once end_packet() has been called for the datagram in buffer
+    in_packet: bool,
+    /// the read timeout for a peer of a DatagramListener. The shared socket can't hold one for every peer
+    timeout: Option<Duration>,
+}
+
+impl Datagram {
+    /// Use a socket which is already connected to the peer
+    pub fn new(socket: UdpSocket) -> Datagram {
+        Datagram::with_inner(Inner::Connected(socket))
+    }
+
+    fn with_inner(inner: Inner) -> Datagram {
+        Datagram {
+            inner: inner,
+            buffer: Vec::new(),
+            pos: 0,
+            in_packet: false,
+            timeout: None,
+        }
+    }
+
+    /// Bind to local_addr and connect to the peer at remote_addr
+    pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(local_addr: A, remote_addr: B) -> io::Result<Datagram> {
+        let socket = match UdpSocket::bind(local_addr) {
+            Ok(s) => s,
+            Err(e) => return Err(e),
+        };
+
+        match socket.connect(remote_addr) {
+            Ok(()) => Ok(Datagram::new(socket)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Make sure that the current datagram is in buffer, waiting for the next one if end_packet() has been called
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        if self.in_packet {
+            return Ok(());
+        }
+
+        match self.inner {
+            Inner::Connected(ref socket) => {
+                self.buffer.resize(MAX_DATAGRAM_SIZE, 0);
+                let len = match socket.recv(&mut self.buffer) {
+                    Ok(len) => len,
+                    Err(e) => return Err(e),
+                };
+                self.buffer.truncate(len);
+            },
+            Inner::Shared(ref demux, peer) => {
+                self.buffer = match demux.wait(Waiter::Peer(peer), self.timeout) {
+                    Ok((_, Some(datagram))) => datagram,
+                    Ok((_, None)) => Vec::new(), // only the listener waits for new peers
+                    Err(e) => return Err(e),
+                };
+            },
+        };
+
+        self.pos = 0;
+        self.in_packet = true;
+        Ok(())
+    }
+}
+
+impl Drop for Datagram {
+    fn drop(&mut self) {
+        match self.inner {
+            Inner::Shared(ref demux, peer) => demux.forget(peer),
+            Inner::Connected(_) => (),
+        }
+    }
+}
+
+/// Reads only ever return data from one datagram. Once it is used up reads return 0 until end_packet() is called.
+impl io::Read for Datagram {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.fill_buffer() {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        let remaining = &self.buffer[self.pos..];
+        let n = if buf.len() < remaining.len() { buf.len() } else { remaining.len() };
+        buf[0..n].copy_from_slice(&remaining[0..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Each write is sent as one datagram
+impl io::Write for Datagram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner {
+            Inner::Connected(ref socket) => socket.send(buf),
+            Inner::Shared(ref demux, peer) => demux.socket.send_to(buf, peer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Datagram {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.inner {
+            Inner::Connected(ref socket) => socket.set_read_timeout(timeout),
+            Inner::Shared(..) => {
+                self.timeout = timeout;
+                Ok(())
+            },
+        }
+    }
+
+    /// Waits for the next datagram if end_packet() has been called, without taking anything out of it
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.fill_buffer() {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        let remaining = &self.buffer[self.pos..];
+        let n = if buf.len() < remaining.len() { buf.len() } else { remaining.len() };
+        buf[0..n].copy_from_slice(&remaining[0..n]);
+        Ok(n)
+    }
+
+    fn can_peek(&self) -> bool {
+        true
+    }
+
+    fn is_datagram(&self) -> bool {
+        true
+    }
+
+    fn end_packet(&mut self) {
+        self.in_packet = false;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.inner {
+            Inner::Connected(ref socket) => socket.peer_addr().ok(),
+            Inner::Shared(_, peer) => Some(peer),
+        }
+    }
+}
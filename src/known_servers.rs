@@ -0,0 +1,126 @@
+//! Remembering servers' long-term public keys for trust on first use
+
+/*  This file is part of project-net.
+    project-net is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-net is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-net.  If not, see http://www.gnu.org/licenses/.*/
+
+use proj_crypto::asymmetric::*;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use {to_utf8_hex, from_utf8_hex};
+
+/// Separates the server's name from its key on each line of the file. The key is written like in .pub files
+const KEY_PREFIX: &'static str = " PK: ";
+
+/// The servers we have connected to before and the long-term public key each one used.
+/// The file has one line per server: its name (e.g. the socket address) then its key written like in .pub files.
+pub struct KnownServers {
+    path: PathBuf,
+    keys: HashMap<String, PublicKey>,
+}
+
+impl KnownServers {
+    /// Read the known servers file. If it does not exist yet it is created when the first server is recorded.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<KnownServers> {
+        let mut keys = HashMap::new();
+
+        let mut contents = String::new();
+        match fs::File::open(&path) {
+            Ok(mut f) => match f.read_to_string(&mut contents) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        };
+
+        for line in contents.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            match parse_line(line) {
+                Some((name, pk)) => { keys.insert(name, pk); },
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line in the known servers file: '{}'", line))),
+            };
+        }
+
+        Ok(KnownServers{ path: path.as_ref().to_path_buf(), keys: keys })
+    }
+
+    /// The key recorded for the server with this name
+    pub fn get(&self, name: &str) -> Option<&PublicKey> {
+        self.keys.get(name)
+    }
+
+    /// Record the key of a server we have not seen before. It is written to the file straight away.
+    /// Fails if the server already has a key: that has to be removed from the file by hand.
+    pub fn insert(&mut self, name: &str, pk: PublicKey) -> io::Result<()> {
+        if self.keys.contains_key(name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already has a known key", name)));
+        }
+
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "server names can't be empty or contain whitespace"));
+        }
+
+        let option = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600) // rw-------
+            .open(&self.path);
+
+        let mut file = match option {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        let mut line = Vec::new();
+        line.extend_from_slice(name.as_bytes());
+        line.extend_from_slice(KEY_PREFIX.as_bytes());
+        line.append(&mut to_utf8_hex(&pk[..]));
+        line.push(b'\n');
+
+        match file.write_all(&line) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        };
+
+        self.keys.insert(String::from(name), pk);
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, PublicKey)> {
+    let mut parts = line.splitn(2, KEY_PREFIX);
+    let name = match parts.next() {
+        Some(n) if !n.is_empty() => n,
+        _ => return None,
+    };
+    let key_hex = match parts.next() {
+        Some(k) => k,
+        None => return None,
+    };
+
+    let key = match from_utf8_hex(key_hex) {
+        Some(k) => k,
+        None => return None,
+    };
+
+    match public_key_from_slice(&key) {
+        Some(pk) => Some((String::from(name), pk)),
+        None => None,
+    }
+}
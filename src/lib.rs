@@ -38,14 +38,21 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::collections::HashMap;
 use std::io::Write;
 use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
 use std::path::Path;
 use std::fmt::Display;
+use sodiumoxide::utils::memzero;
 
 mod common;
 pub mod server;
 pub mod client;
+pub mod stream;
+pub mod datagram;
+pub mod known_servers;
+pub mod revocation;
+pub mod trust;
+
+pub use common::message::ErrorReason;
+pub use common::Transport;
 
 /// Simple tuple of a public key and a secret key
 pub type Keypair = (PublicKey, SecretKey);
@@ -56,7 +63,98 @@ pub struct SessionKeys {
     pub from_device: symmetric::State,
     /// symmetric state for use with message to be sent or received from the server
     pub from_server: symmetric::State,
+    /// secret shared by both parties from which any further keys for this session are derived
+    secret: Vec<u8>,
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        memzero(&mut self.secret);
+    }
+}
+
+/// Lets a device skip the full key exchange the next time it connects to the same server. See client::resume().
+/// Anyone holding the ticket can resume a session as the device until it expires, so keep it as carefully as the device's secret key.
+#[derive(Clone)]
+pub struct ResumptionTicket {
+    /// shared with the server, which finds it again inside the ticket
+    secret: Vec<u8>,
+    /// opaque to the device
+    ticket: Vec<u8>,
+    server_long_pk: PublicKey,
+}
+
+impl ResumptionTicket {
+    /// The long-term public key of the server which issued the ticket
+    pub fn server_long_pk(&self) -> &PublicKey {
+        &self.server_long_pk
+    }
+
+    /// Serialise the ticket so that it can be kept across restarts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(PUBLIC_KEY_BYTES + self.secret.len() + self.ticket.len());
+        ret.extend_from_slice(&self.server_long_pk[..]);
+        ret.extend_from_slice(&self.secret);
+        ret.extend_from_slice(&self.ticket);
+        ret
+    }
+
+    /// Read a ticket written by to_bytes()
+    pub fn from_bytes(bytes: &[u8]) -> Option<ResumptionTicket> {
+        let secret_bytes = common::message::RESUMPTION_SECRET_BYTES;
+        if bytes.len() <= PUBLIC_KEY_BYTES + secret_bytes {
+            return None;
+        }
+
+        let (pk_bytes, the_rest) = bytes.split_at(PUBLIC_KEY_BYTES);
+        let (secret, ticket) = the_rest.split_at(secret_bytes);
+
+        match public_key_from_slice(pk_bytes) {
+            None => None,
+            Some(pk) => Some(ResumptionTicket { secret: secret.to_vec(), ticket: ticket.to_vec(), server_long_pk: pk }),
+        }
+    }
+}
+
+impl Drop for ResumptionTicket {
+    fn drop(&mut self) {
+        memzero(&mut self.secret);
+    }
+}
+
+/// A secret which the device and the server were both given ahead of time, used instead of long-term keypairs. See client::start_psk() and server::do_psk_key_exchange().
+/// The id says which secret the device holds. It is sent in the clear so it should not give away anything about the device which ought to be kept private.
+#[derive(Clone)]
+pub struct PreSharedKey {
+    id: Vec<u8>,
+    key: Vec<u8>,
+}
+
+/// The shortest pre-shared key which will be accepted
+pub const MIN_PSK_BYTES: usize = 32;
+
+impl PreSharedKey {
+    /// None if the id is empty or too long, or if the key is shorter than MIN_PSK_BYTES
+    pub fn new(id: &[u8], key: &[u8]) -> Option<PreSharedKey> {
+        if id.is_empty() || (id.len() > common::message::MAX_PSK_ID_BYTES) || (key.len() < MIN_PSK_BYTES) {
+            None
+        } else {
+            Some(PreSharedKey { id: id.to_vec(), key: key.to_vec() })
+        }
+    }
+
+    /// The id the device sends so that the server knows which key to use
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+}
+
+impl Drop for PreSharedKey {
+    fn drop(&mut self) {
+        memzero(&mut self.key);
+    }
 }
+
 fn to_utf8_hex<'a>(bytes: &[u8]) -> Vec<u8> {
     let strings: Vec<String> = bytes.into_iter()
         .map(|b| format!("{:02X}", b))
@@ -67,6 +165,33 @@ fn to_utf8_hex<'a>(bytes: &[u8]) -> Vec<u8> {
     ret
 }
 
+/// The inverse of to_utf8_hex(). None unless every byte is two hex digits
+fn from_utf8_hex(hex: &str) -> Option<Vec<u8>> {
+    from_hex(hex, ' ')
+}
+
+/// Bytes written as pairs of hex digits with separator between them, as in to_utf8_hex() or fingerprint(). None unless every byte is two hex digits
+fn from_hex(hex: &str, separator: char) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for byte in hex.split(separator) {
+        match u8::from_str_radix(byte, 16) {
+            Ok(b) if byte.len() == 2 => bytes.push(b),
+            _ => return None,
+        };
+    }
+
+    Some(bytes)
+}
+
+/// A fingerprint of a public key for showing to people: the id of the key in hex
+pub fn fingerprint(pk: &PublicKey) -> String {
+    let strings: Vec<String> = key_id::id_of_pk(pk).digest[..].iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+
+    strings.join(":")
+}
+
 /// Generate a keypair and put it into the specified file
 /// This is not memory tidy. It would be difficult to clear the memory properly here and I don't think it matters too much because this doesn't connect to the network
 pub fn key_gen_to_file<P: AsRef<Path> + Display + Clone>(file_path: P) where String: std::convert::From<P> {
@@ -111,134 +236,115 @@ pub fn key_gen_to_file<P: AsRef<Path> + Display + Clone>(file_path: P) where Str
     let _ = pub_file.write(b"\n").unwrap();
 }
 
-fn hex_char_to_num(c: char) -> u8 {
-    match c {
-        '0' => 0,
-        '1' => 1,
-        '2' => 2,
-        '3' => 3,
-        '4' => 4,
-        '5' => 5,
-        '6' => 6,
-        '7' => 7,
-        '8' => 8,
-        '9' => 9,
-        'A' => 10,
-        'B' => 11,
-        'C' => 12,
-        'D' => 13,
-        'E' => 14,
-        'F' => 15,
-        _ => panic!("{} is not a hexadecimal digit", c),
-    }
-}
-
-fn hex_to_byte(hex: Vec<char>) -> u8 {
-    assert_eq!(hex.len(), 2);
+/// The key on the line of contents which starts with prefix (e.g. "PK"). None if there is no such line or the key is not hex
+fn get_key_from_file(contents: &str, prefix: &str) -> Option<Vec<u8>> {
+    let prefix = String::from(prefix) + ": ";
 
-    hex_char_to_num(hex[1]) | (hex_char_to_num(hex[0]) << 4)
-}
-
-/// returns a file so as to give it back to the caller (it was borrowed to get here)
-fn get_key_from_file(mut file: fs::File, prefix: &str) -> Option<(fs::File, Vec<u8>)> {
-    let prefix_expected = String::from(prefix) + ": ";
-    let mut prefix_read_bytes: [u8; 4] = [0; 4]; // e.g. "PK: "
-
-    match file.read(&mut prefix_read_bytes) {
-        Ok(_) => (),
-        Err(_) => return None,
-    };
-
-    if prefix_read_bytes != prefix_expected.as_bytes() {
-        if prefix_read_bytes != [10, 0, 0, 0]  { // 10 (denary) is linefeed in ascii
-            panic!("The prefix read (as bytes) was {:?}, we expected {:?} ('{}'). Is the file malformed?", prefix_read_bytes, prefix_expected.as_bytes(), prefix_expected);
-        } else { // we just got a linefeed so there is nothing to read
-            return None;
-        }
+    match contents.lines().find(|line| line.starts_with(&prefix)) {
+        Some(line) => from_utf8_hex(&line[prefix.len()..]),
+        None => None,
     }
+}
 
-    let mut key_hex_bytes: [u8; 64+31] = [0; 64+31]; // 64 characters and 31 spaces
-
-    match file.read(&mut key_hex_bytes) {
-        Ok(_) => (),
-        Err(e) => panic!("Error reading file: {}", e),
+/// Reads a keypair from a file written by key_gen_to_file()
+pub fn get_keypair<P: AsRef<Path> + Display + Clone>(my_keypair_path: P) -> Keypair {
+    let mut contents = String::new();
+    match fs::File::open(my_keypair_path.clone()) {
+        Ok(mut f) => match f.read_to_string(&mut contents) {
+            Ok(_) => (),
+            Err(e) => panic!("Error reading file '{}': {}", my_keypair_path, e),
+        },
+        Err(e) => panic!("Error opening file '{}': {}", my_keypair_path, e),
     };
 
-    let mut key_hex_vec = Vec::new();
-    key_hex_vec.extend_from_slice(&key_hex_bytes);
-    
-    let key_hex: Vec<char> = String::from_utf8(key_hex_vec).unwrap().chars().collect();
-
-    // split the hex string into pairs of of hex digits (bytes)
-    let key: Vec<u8> = key_hex.split(|c| *c == ' ')
-        .map(|x| x.to_vec())
-        .map(|x| hex_to_byte(x))
-        .collect();
-
+    let my_pk = get_key_from_file(&contents, "PK").and_then(|bytes| public_key_from_slice(&bytes));
+    let my_sk = get_key_from_file(&contents, "SK").and_then(|bytes| secret_key_from_slice(&bytes));
 
-    Some((file, key))
-}
-
-fn open_or_panic<P: AsRef<Path> + Display + Clone>(path: P) -> fs::File {
-    match fs::File::open(path.clone()) {
-        Ok(f) => f,
-        Err(e) => panic!("Error opening file '{}': {}", path, e),
+    match (my_pk, my_sk) {
+        (Some(pk), Some(sk)) => (pk, sk),
+        _ => panic!("'{}' does not hold a keypair. Is the file malformed?", my_keypair_path),
     }
 }
 
-/// Reads keys from a file
+/// Reads keys from a file. The trusted public keys come back as a map, which can be used as a trust::TrustStore.
+/// See trust::FileTrustStore for trusted keys which can be read again while they are in use.
 pub fn get_keys<P1: AsRef<Path> + Display + Clone, P2: AsRef<Path> + Display + Clone>(my_keypair_path: P1, their_pk_path: P2) -> (HashMap<key_id::PublicKeyId, PublicKey>, Keypair) {
-    let my_keypair_file = open_or_panic(my_keypair_path);
-    let mut pk_file = open_or_panic(their_pk_path);
-
-    // get my keypair
-    let (mut my_keypair_file, pk_bytes) = get_key_from_file(my_keypair_file, "PK").unwrap();
+    let keypair = get_keypair(my_keypair_path);
 
-    // seek to the start of SK
-    my_keypair_file.seek(SeekFrom::Start(4+64+31+1)).unwrap(); // 4 byte prefix + 64 bytes of hex + 31 spaces + newline
-    let (_, sk_bytes) = get_key_from_file(my_keypair_file, "SK").unwrap();
-
-    let my_pk = public_key_from_slice(&pk_bytes).unwrap();
-    let my_sk = secret_key_from_slice(&sk_bytes).unwrap();
-
-    // get the trusted public keys
-    let mut pks = HashMap::new();
-    loop {
-        let result = get_key_from_file(pk_file, "PK");
-
-        if result.is_none() {
-            break;
-        }
-
-        // else
-        let (pk_file_tmp, pk_bytes) = result.unwrap();
-        pk_file = pk_file_tmp; // got to love the borrow checker
-        let pk = public_key_from_slice(&pk_bytes).unwrap();
-        let pk_id = key_id::id_of_pk(&pk);
-        pks.insert(pk_id, pk);
-    }
+    let pks = match trust::read_public_keys(their_pk_path.clone()) {
+        Ok(pks) => pks,
+        Err(e) => panic!("Error reading public keys from '{}': {}", their_pk_path, e),
+    };
 
-    (pks, (my_pk, my_sk))
+    (pks, keypair)
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
     use std::io;
-    use std::net::TcpStream;
+    use std::net::{TcpStream, TcpListener};
     extern crate sodiumoxide;
     extern crate proj_crypto;
     use std::thread;
+    use std::sync::mpsc;
+    use std::sync::Arc;
     use std::time::Duration;
     use std::collections::HashMap;
     use proj_crypto::asymmetric::{key_id, PublicKey};
     use super::*;
+    use server::ServerConfig;
+    use client::ClientConfig;
+    use trust::TrustStore;
 
     const MESSAGE_SIZE: usize = 256;
     const NUM_CLIENTS: usize = 10;
 
+    /// Panic unless the result is an error matching the pattern, then evaluate to the expression after `=>` (if any)
+    macro_rules! expect_err {
+        ($result:expr, $pattern:pat) => { expect_err!($result, $pattern => ()) };
+        ($result:expr, $pattern:pat => $then:expr) => {
+            match $result {
+                Err($pattern) => $then,
+                Err(e) => panic!("wrong error: {:?}", e),
+                Ok(_) => panic!("expected an error matching {}", stringify!($pattern)),
+            }
+        };
+    }
+
+    /// A keypair each for a server and a device, and trusted public keys holding both
+    fn trusted_keypairs() -> (Keypair, Keypair, HashMap<key_id::PublicKeyId, PublicKey>) {
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(key_id::id_of_pk(&server_keypair.0), server_keypair.0.clone());
+        trusted_pks.insert(key_id::id_of_pk(&client_keypair.0), client_keypair.0.clone());
+
+        (server_keypair, client_keypair, trusted_pks)
+    }
+
+    /// Listen on whichever port the operating system picks, so that tests running at the same time never fight over one. Returns the address to connect to
+    fn listen_on_free_port() -> (TcpListener, String) {
+        let listener = server::listen("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    /// Do the key exchange with the first device to connect and hand the session to serve in a new thread. Returns the address to connect to
+    fn spawn_server<F, R>(server_keypair: Keypair, trusted_pks: &HashMap<key_id::PublicKeyId, PublicKey>, serve: F) -> (String, thread::JoinHandle<R>)
+        where F: FnOnce(server::Server) -> R + Send + 'static, R: Send + 'static {
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let server = server::do_key_exchange(listener.incoming().next().unwrap(), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            serve(server)
+        });
+        (addr, server_thread)
+    }
+
     fn server_handle_connection(stream: io::Result<TcpStream>, keypair: Keypair, trusted_pks: HashMap<key_id::PublicKeyId, PublicKey>) {
-        let mut server = server::do_key_exchange(stream, &keypair, &trusted_pks).unwrap();
+        let mut server = server::do_key_exchange(stream, &ServerConfig::new(keypair), &trusted_pks).unwrap();
         server.blocking_on(); 
 
         let mut buf: [u8; MESSAGE_SIZE] = [0; MESSAGE_SIZE];
@@ -269,7 +375,7 @@ mod test {
     }
         
     fn client_thread(keypair: Keypair, trusted_pks: HashMap<key_id::PublicKeyId, PublicKey>) {
-        let mut client = client::start("127.0.0.1:1024", keypair, &trusted_pks).unwrap();
+        let mut client = client::start("127.0.0.1:1024", keypair, &trusted_pks, &ClientConfig::new()).unwrap();
         client.blocking_on();
 
         let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
@@ -312,5 +418,1420 @@ mod test {
         }
         // note that we won't notice panics in server threads. This is because we can't join them because the listening thread never stops waiting for more connections. Each client checks that the server responds to connections correctly so I don't think this is too bad.
     }
-}
 
+    #[test]
+    fn acknowledged_write() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            let mut buf = [0 as u8; MESSAGE_SIZE];
+            let n = server.read(&mut buf).unwrap();
+            server.write(&buf[0..n]).unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+
+        let handle = client.write_tracked(&client_msg).unwrap();
+        client.wait_for_ack(handle).unwrap();
+        assert!(client.is_acknowledged(handle));
+
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        assert!(&recv_buf[0..MESSAGE_SIZE] == client_msg.as_slice());
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn rekey() {
+        // enough messages that 16 bit message numbers would overflow (or, with 64 bit message numbers, to move into the next epoch)
+        const NUM_MESSAGES: usize = 70000;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            let mut buf = [0 as u8; MESSAGE_SIZE];
+            let mut total = 0;
+            while total < NUM_MESSAGES {
+                total += server.read(&mut buf).unwrap();
+            }
+            server.write(b"done").unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.rekey().unwrap();
+
+        for i in 0..NUM_MESSAGES {
+            client.write(&[i as u8]).unwrap();
+        }
+
+        let mut recv_buf = [0 as u8; 4];
+        assert_eq!(client.read(&mut recv_buf).unwrap(), 4);
+        assert_eq!(&recv_buf, b"done");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn rekey_while_peer_only_writes() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        // the server doesn't read (and so doesn't answer the rekey) until the client's write has returned
+        let (written_tx, written_rx) = mpsc::channel();
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, move |mut server| {
+            server.write(b"first").unwrap();
+            written_rx.recv().unwrap();
+
+            let mut buf = [0 as u8; 5];
+            assert_eq!(server.read(&mut buf).unwrap(), 5);
+            assert_eq!(&buf, b"hello");
+            server.write(b"done").unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.rekey().unwrap();
+        client.write(b"hello").unwrap();
+        written_tx.send(()).unwrap();
+        // hello is only sent once the server answers
+        client.flush().unwrap();
+
+        let mut recv_buf = [0 as u8; 5];
+        assert_eq!(client.read(&mut recv_buf).unwrap(), 5);
+        assert_eq!(&recv_buf, b"first");
+        assert_eq!(client.read(&mut recv_buf).unwrap(), 4);
+        assert_eq!(&recv_buf[0..4], b"done");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn drop_during_rekey() {
+        use std::time::Instant;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        // the server never reads so it never answers the rekey
+        let (dropped_tx, dropped_rx) = mpsc::channel();
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, move |_server| {
+            dropped_rx.recv().unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.rekey().unwrap();
+        client.write(b"hello").unwrap();
+
+        let start = Instant::now();
+        drop(client);
+        assert!(start.elapsed() < Duration::from_millis(10 * common::DROP_REKEY_WAIT_MS));
+        dropped_tx.send(()).unwrap();
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn large_write() {
+        // needs several fragments
+        const LARGE_MESSAGE_SIZE: usize = 200000;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            let mut buf = vec![0 as u8; LARGE_MESSAGE_SIZE];
+            // the whole message should be reassembled before read() sees any of it
+            assert_eq!(server.read(&mut buf).unwrap(), LARGE_MESSAGE_SIZE);
+            server.write(&buf).unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(LARGE_MESSAGE_SIZE);
+
+        let handle = client.write_tracked(&client_msg).unwrap();
+        client.wait_for_ack(handle).unwrap();
+
+        let mut recv_buf = vec![0 as u8; LARGE_MESSAGE_SIZE];
+        let mut total = 0;
+        while total < LARGE_MESSAGE_SIZE {
+            total += client.read(&mut recv_buf[total..]).unwrap();
+        }
+        assert!(recv_buf == client_msg);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn huge_write() {
+        use sodiumoxide::crypto::hash::sha256;
+
+        // too much for one message, so it is sent as several
+        const HUGE_WRITE_SIZE: usize = 40 << 20;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            let mut buf = vec![0 as u8; HUGE_WRITE_SIZE];
+            server.read_exact(&mut buf).unwrap();
+            server.write(&sha256::hash(&buf)[..]).unwrap();
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(HUGE_WRITE_SIZE);
+
+        // the whole write still gets one handle
+        let handle = client.write_tracked(&client_msg).unwrap();
+        assert_eq!(handle, 1);
+        client.wait_for_ack(handle).unwrap();
+
+        let mut digest = [0 as u8; sha256::DIGESTBYTES];
+        client.read_exact(&mut digest).unwrap();
+        assert_eq!(&digest[..], &sha256::hash(&client_msg)[..]);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn streams() {
+        const BULK: usize = 1 << 20;
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |server| {
+            let streams = server.into_streams();
+
+            // the client is still sending the bulk data but the command can be read without waiting for it.
+            // The first stream opened by the device is always 1
+            let mut command = streams.accept().unwrap();
+            assert_eq!(command.id(), 1);
+            let mut buf = [0 as u8; 7];
+            assert_eq!(command.read(&mut buf).unwrap(), 7);
+            assert_eq!(&buf, b"command");
+            command.write(b"done").unwrap();
+
+            let mut default = streams.default_stream().unwrap();
+            let mut bulk = vec![0 as u8; BULK];
+            let mut total = 0;
+            while total < bulk.len() {
+                total += default.read(&mut bulk[total..]).unwrap();
+            }
+            assert!(bulk.iter().all(|&b| b == 0xAB));
+
+            // the client closed the command stream once it had the answer
+            assert_eq!(command.read(&mut buf).unwrap(), 0);
+            command.close().unwrap();
+
+            let mut status = streams.accept().unwrap();
+            assert_eq!(status.read(&mut buf).unwrap(), 6);
+            assert_eq!(&buf[0..6], b"status");
+            default.write(b"uploaded").unwrap();
+        });
+
+        let client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        assert_eq!(client.protocol_version(), common::message::PROTOCOL_VERSION);
+        let streams = client.into_streams();
+
+        // only one handle per stream, and only for streams which have been opened
+        let mut default = streams.default_stream().unwrap();
+        assert_eq!(streams.default_stream().err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(streams.stream(2).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(default.close().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut command = streams.open().unwrap();
+        assert_eq!(command.id(), 1);
+
+        let bulk_thread = thread::spawn(move || {
+            default.write(&vec![0xAB as u8; BULK]).unwrap();
+            default
+        });
+        command.write(b"command").unwrap();
+
+        let mut buf = [0 as u8; 8];
+        assert_eq!(command.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[0..4], b"done");
+        command.close().unwrap();
+        assert_eq!(command.write(b"more").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(command.read(&mut buf).unwrap(), 0);
+
+        let mut default = bulk_thread.join().unwrap();
+        let mut status = streams.open().unwrap();
+        assert!(status.id() != command.id());
+        status.write(b"status").unwrap();
+        assert_eq!(default.read(&mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"uploaded");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn keepalive() {
+        use std::time::Instant;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            server.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(300)).unwrap();
+            server.blocking_off(600);
+
+            // the client is reading so it answers our pings. We should run into our own read timeout instead of deciding the client is dead
+            let mut buf = [0 as u8; 1];
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+            server.write(b"x").unwrap();
+
+            // now the client has gone quiet
+            let start = Instant::now();
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(start.elapsed() < Duration::from_millis(600));
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let mut buf = [0 as u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 1);
+
+        // stop reading without closing the connection
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn keepalive_while_writing() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            server.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(300)).unwrap();
+            server.blocking_off(100);
+
+            // only write for longer than the liveness timeout. The writes ping the client, which answers because it is reading
+            for _ in 0..12 {
+                server.write(b"x").unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            // the client's answers are waiting so it has not been given up on
+            let mut buf = [0 as u8; 1];
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        });
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let mut buf = [0 as u8; 1];
+        for _ in 0..12 {
+            assert_eq!(client.read(&mut buf).unwrap(), 1);
+        }
+
+        server_thread.join().unwrap();
+    }
+
+    /// One end of an in-memory duplex channel
+    struct Pipe {
+        incoming: mpsc::Receiver<Vec<u8>>,
+        outgoing: mpsc::Sender<Vec<u8>>,
+        buffer: Vec<u8>,
+    }
+
+    fn pipe_pair() -> (Pipe, Pipe) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+
+        (Pipe{ incoming: a_rx, outgoing: b_tx, buffer: Vec::new() },
+         Pipe{ incoming: b_rx, outgoing: a_tx, buffer: Vec::new() })
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buffer.is_empty() {
+                match self.incoming.recv() {
+                    Ok(data) => self.buffer = data,
+                    Err(_) => return Ok(0), // the other end has gone away
+                }
+            }
+
+            let n = if buf.len() < self.buffer.len() { buf.len() } else { self.buffer.len() };
+            for (i, byte) in self.buffer.drain(0..n).enumerate() {
+                buf[i] = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.outgoing.send(buf.to_vec()) {
+                Ok(()) => Ok(buf.len()),
+                Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "the other end has gone away")),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Pipe {}
+
+    /// Both ends of a session over an in-memory channel, without a key exchange. Lets tests send what a client or server never would
+    fn session_pair() -> (common::ProtocolState<Pipe>, common::ProtocolState<Pipe>) {
+        let secret = sodiumoxide::randombytes::randombytes(32);
+        let transcript = proj_crypto::symmetric::Digest{ digest: sodiumoxide::crypto::hash::sha256::hash(b"session_pair") };
+        let (device_end, server_end) = pipe_pair();
+
+        let device_keys = common::message::send::resumed_session_keys(&secret, &transcript);
+        let server_keys = common::message::send::resumed_session_keys(&secret, &transcript);
+        (common::ProtocolState::new(device_end, None, None, device_keys, true, common::message::PROTOCOL_VERSION, 0, 0),
+         common::ProtocolState::new(server_end, None, None, server_keys, false, common::message::PROTOCOL_VERSION, 0, 0))
+    }
+
+    #[test]
+    fn many_streams() {
+        const STREAM_DATA: usize = 1 << 20;
+        let data = vec![0 as u8; STREAM_DATA];
+
+        // the application never reads the streams, so the data sent on all of them together runs into the limit
+        let (mut device, mut server) = session_pair();
+        let num_streams = common::MAX_BUFFERED_DATA / STREAM_DATA + 1;
+        for _ in 0..num_streams {
+            let id = common::general_open_stream(&mut device).unwrap();
+            common::general_write(&mut device, id, &data).unwrap();
+        }
+
+        for _ in 0..(num_streams - 1) {
+            common::general_read(&mut server).unwrap();
+        }
+        assert_eq!(common::general_read(&mut server).unwrap_err().kind(), io::ErrorKind::Other);
+        assert_eq!(common::general_read(&mut device).unwrap_err().to_string(), ErrorReason::BufferFull.to_string());
+
+        // a device can only open so many streams
+        let (mut device, mut server) = session_pair();
+        for _ in 0..common::MAX_OPEN_STREAMS {
+            let id = common::general_open_stream(&mut device).unwrap();
+            common::general_write(&mut device, id, b"x").unwrap();
+        }
+        assert!(common::general_open_stream(&mut device).is_err());
+
+        for _ in 0..common::MAX_OPEN_STREAMS {
+            common::general_read(&mut server).unwrap();
+        }
+
+        // and the server won't let it get around that
+        device.open_streams = 0;
+        let id = common::general_open_stream(&mut device).unwrap();
+        common::general_write(&mut device, id, b"x").unwrap();
+        assert_eq!(common::general_read(&mut server).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(common::general_read(&mut device).unwrap_err().to_string(), ErrorReason::Protocol.to_string());
+    }
+
+    #[test]
+    fn generic_transport() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (client_end, server_end) = pipe_pair();
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut server = server::do_key_exchange(Ok(server_end), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            let mut buf = [0 as u8; 32];
+            let n = server.read(&mut buf).unwrap();
+            server.write(&buf[0..n]).unwrap();
+        });
+
+        let mut client = client::start_on(client_end, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(32);
+        client.write(&client_msg).unwrap();
+
+        let mut buf = [0 as u8; 32];
+        assert_eq!(client.read(&mut buf).unwrap(), 32);
+        assert_eq!(&buf[..], &client_msg[..]);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn peer_identity() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (client_end, server_end) = pipe_pair();
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_pk = server_keypair.0.clone();
+        let client_pk = client_keypair.0.clone();
+        let server_thread = thread::spawn(move || {
+            let server = server::do_key_exchange(Ok(server_end), &ServerConfig::new(server_keypair.clone()), &server_trusted_pks).unwrap();
+
+            // both ends know who they are talking to
+            assert!(server.peer_long_pk() == Some(&client_pk));
+            assert_eq!(server.peer_key_id(), Some(key_id::id_of_pk(&client_pk)));
+            assert_eq!(server.key_id(), Some(key_id::id_of_pk(&server_keypair.0)));
+        });
+
+        let client_pk = client_keypair.0.clone();
+        let client = client::start_on(client_end, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        assert!(client.peer_long_pk() == Some(&server_pk));
+        assert_eq!(client.peer_key_id(), Some(key_id::id_of_pk(&server_pk)));
+        assert_eq!(client.key_id(), Some(key_id::id_of_pk(&client_pk)));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn exported_keying_material() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (client_end, server_end) = pipe_pair();
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut server = server::do_key_exchange(Ok(server_end), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            let before = (server.session_id(), server.export_keying_material(b"approval", b"context"));
+
+            // the client rekeys before sending this
+            let mut buf = [0 as u8; 32];
+            let n = server.read(&mut buf).unwrap();
+            server.write(&buf[0..n]).unwrap();
+
+            assert_eq!(before, (server.session_id(), server.export_keying_material(b"approval", b"context")));
+            before
+        });
+
+        let mut client = client::start_on(client_end, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let session_id = client.session_id();
+        let exported = client.export_keying_material(b"approval", b"context");
+
+        assert_eq!(exported.len(), 32);
+        assert!(exported != client.export_keying_material(b"approva", b"lcontext"));
+        assert!(exported != client.export_keying_material(b"approval", b"other context"));
+        assert!(exported != session_id);
+
+        client.rekey().unwrap();
+        client.write(b"hello").unwrap();
+        let mut buf = [0 as u8; 32];
+        assert_eq!(client.read(&mut buf).unwrap(), 5);
+
+        assert_eq!(server_thread.join().unwrap(), (session_id, exported));
+    }
+
+    #[test]
+    fn session_error_reaches_peer() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        // the client talks to the server through a man in the middle, the server talks straight back
+        let (client_tx, relay_rx) = mpsc::channel::<Vec<u8>>();
+        let (relay_tx, server_rx) = mpsc::channel();
+        let (server_tx, client_rx) = mpsc::channel();
+        let client_end = Pipe{ incoming: client_rx, outgoing: client_tx, buffer: Vec::new() };
+        let server_end = Pipe{ incoming: server_rx, outgoing: server_tx, buffer: Vec::new() };
+
+        // the device's two key exchange packets get through untouched. Its first data packet does not
+        thread::spawn(move || {
+            for (i, mut packet) in relay_rx.iter().enumerate() {
+                if i == 2 {
+                    let last = packet.len() - 1;
+                    packet[last] ^= 1;
+                }
+
+                if relay_tx.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut server = server::do_key_exchange(Ok(server_end), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            let mut buf = [0 as u8; 32];
+            assert!(server.read(&mut buf).is_err());
+        });
+
+        let mut client = client::start_on(client_end, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(b"hello").unwrap();
+
+        // the server says why it ended the session, and the man in the middle could not have forged that
+        let mut buf = [0 as u8; 32];
+        let error = client.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(error.get_ref().unwrap().downcast_ref::<ErrorReason>(), Some(&ErrorReason::AuthFailure));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn unix_socket() {
+        const SOCKET_PATH: &'static str = "/tmp/proj_net_unix_socket_test";
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        // left over from an earlier run
+        let _ = fs::remove_file(SOCKET_PATH);
+
+        let listener = server::listen_unix(SOCKET_PATH).unwrap();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut server = server::do_key_exchange(listener.incoming().next().unwrap(), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            let mut buf = [0 as u8; 32];
+            let n = server.read(&mut buf).unwrap();
+            server.write(&buf[0..n]).unwrap();
+        });
+
+        let mut client = client::start_unix(SOCKET_PATH, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+
+        // Unix sockets can't peek so keepalives would break every read
+        assert!(client.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(300)).is_err());
+        client.set_keepalive(None, Duration::from_millis(300)).unwrap();
+
+        let client_msg = sodiumoxide::randombytes::randombytes(32);
+        client.write(&client_msg).unwrap();
+
+        let mut buf = [0 as u8; 32];
+        assert_eq!(client.read(&mut buf).unwrap(), 32);
+        assert_eq!(&buf[..], &client_msg[..]);
+
+        server_thread.join().unwrap();
+        let _ = fs::remove_file(SOCKET_PATH);
+    }
+
+    #[test]
+    fn unix_socket_untrusted_client() {
+        const SOCKET_PATH: &'static str = "/tmp/proj_net_unix_socket_untrusted_test";
+
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+
+        // the server does not know the client's key
+        let mut server_trusted_pks = HashMap::new();
+        server_trusted_pks.insert(key_id::id_of_pk(&server_keypair.0), server_keypair.0.clone());
+        let mut client_trusted_pks = server_trusted_pks.clone();
+        client_trusted_pks.insert(key_id::id_of_pk(&client_keypair.0), client_keypair.0.clone());
+
+        let _ = fs::remove_file(SOCKET_PATH);
+
+        let listener = server::listen_unix(SOCKET_PATH).unwrap();
+        let server_thread = thread::spawn(move || {
+            assert!(server::do_key_exchange(listener.incoming().next().unwrap(), &ServerConfig::new(server_keypair), &server_trusted_pks).is_err());
+        });
+
+        assert!(client::start_unix(SOCKET_PATH, client_keypair, &client_trusted_pks, &ClientConfig::new()).is_err());
+
+        server_thread.join().unwrap();
+        let _ = fs::remove_file(SOCKET_PATH);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = common::ReplayWindow::new(2);
+
+        // already received
+        assert!(!window.acceptable(0));
+        assert!(!window.acceptable(1));
+
+        // out of order
+        assert!(window.acceptable(5));
+        window.mark(5);
+        assert!(window.acceptable(3));
+        window.mark(3);
+        assert!(!window.acceptable(3));
+        assert!(!window.acceptable(5));
+        assert!(window.acceptable(2));
+        assert!(window.acceptable(4));
+
+        // too far behind
+        window.mark(5 + common::REPLAY_WINDOW_SIZE);
+        assert!(!window.acceptable(4));
+        assert!(window.acceptable(6));
+        assert_eq!(window.newest(), 5 + common::REPLAY_WINDOW_SIZE);
+    }
+
+    #[test]
+    fn udp() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let listener = server::listen_udp("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            // two devices share the socket
+            let mut handlers = Vec::new();
+            for _ in 0..2 {
+                let incoming = server::accept_udp(&listener);
+                let server_keypair = server_keypair.clone();
+                let server_trusted_pks = server_trusted_pks.clone();
+                handlers.push(thread::spawn(move || {
+                    let mut server = server::do_key_exchange(incoming, &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+                    let mut buf = [0 as u8; 32];
+                    let n = server.read(&mut buf).unwrap();
+                    server.write(&buf[0..n]).unwrap();
+                }));
+            }
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+        });
+
+        let mut client = client::start_udp(addr, client_keypair.clone(), &trusted_pks, &ClientConfig::new()).unwrap();
+        let mut other_client = client::start_udp(addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(32);
+        let other_msg = sodiumoxide::randombytes::randombytes(32);
+        other_client.write(&other_msg).unwrap();
+        client.write(&client_msg).unwrap();
+
+        let mut buf = [0 as u8; 32];
+        assert_eq!(client.read(&mut buf).unwrap(), 32);
+        assert_eq!(&buf[..], &client_msg[..]);
+        assert_eq!(other_client.read(&mut buf).unwrap(), 32);
+        assert_eq!(&buf[..], &other_msg[..]);
+
+        // lost datagrams are not sent again
+        assert!(client.write_tracked(b"tracked").is_err());
+        assert!(client.write(&vec![0; datagram::MAX_DATAGRAM_DATA + 1]).is_err());
+
+        server_thread.join().unwrap();
+    }
+
+    /// One end of an in-memory datagram channel which loses some of the datagrams written to it
+    struct LossyPipe {
+        incoming: mpsc::Receiver<Vec<u8>>,
+        outgoing: mpsc::Sender<Vec<u8>>,
+        buffer: Vec<u8>,
+        in_packet: bool,
+        timeout: Option<Duration>,
+        writes: usize,
+        /// which writes to lose, counting from 1
+        lost_writes: Vec<usize>,
+    }
+
+    fn lossy_pipe_pair(a_loses: Vec<usize>, b_loses: Vec<usize>) -> (LossyPipe, LossyPipe) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+
+        (LossyPipe{ incoming: a_rx, outgoing: b_tx, buffer: Vec::new(), in_packet: false, timeout: None, writes: 0, lost_writes: a_loses },
+         LossyPipe{ incoming: b_rx, outgoing: a_tx, buffer: Vec::new(), in_packet: false, timeout: None, writes: 0, lost_writes: b_loses })
+    }
+
+    impl Read for LossyPipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.in_packet {
+                let received = match self.timeout {
+                    Some(t) => self.incoming.recv_timeout(t).map_err(|_| io::Error::new(io::ErrorKind::WouldBlock, "timed out")),
+                    None => self.incoming.recv().map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the other end has gone away")),
+                };
+
+                match received {
+                    Ok(data) => self.buffer = data,
+                    Err(e) => return Err(e),
+                };
+                self.in_packet = true;
+            }
+
+            let n = if buf.len() < self.buffer.len() { buf.len() } else { self.buffer.len() };
+            for (i, byte) in self.buffer.drain(0..n).enumerate() {
+                buf[i] = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for LossyPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if !self.lost_writes.contains(&self.writes) {
+                let _ = self.outgoing.send(buf.to_vec());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for LossyPipe {
+        fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn is_datagram(&self) -> bool {
+            true
+        }
+
+        fn end_packet(&mut self) {
+            self.buffer.clear();
+            self.in_packet = false;
+        }
+    }
+
+    #[test]
+    fn lost_handshake_packets() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        // the client loses the first device_first and the first device_second
+        let (client_end, server_end) = lossy_pipe_pair(vec![1, 3], vec![]);
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut server = server::do_key_exchange(Ok(server_end), &ServerConfig::new(server_keypair), &server_trusted_pks).unwrap();
+            server.write(b"welcome").unwrap();
+        });
+
+        let mut client = client::start_on(client_end, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+
+        // the server has to send server_first again before it gets our device_second
+        let mut buf = [0 as u8; 7];
+        assert_eq!(client.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf, b"welcome");
+
+        server_thread.join().unwrap();
+    }
+
+    fn echo_once<S: Transport, D>(server: &mut server::Server<S, D>) {
+        let mut buf = [0 as u8; MESSAGE_SIZE];
+        let n = server.read(&mut buf).unwrap();
+        server.write(&buf[0..n]).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let server_pk = server_keypair.0.clone();
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let ticket_key = server::TicketKey::new();
+            let new_ticket_key = server::TicketKey::new();
+            let mut config = ServerConfig::new(server_keypair);
+            config.ticket_key = Some(&ticket_key);
+            let mut incoming = listener.incoming();
+
+            // the full key exchange. Then give the device a ticket
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            server.issue_ticket(&ticket_key, Duration::from_secs(60)).unwrap();
+            echo_once(&mut server);
+
+            // the device comes back with the ticket
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            echo_once(&mut server);
+
+            // after the ticket key is replaced the device has to do the full key exchange again
+            config.ticket_key = Some(&new_ticket_key);
+            assert!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).is_err());
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            echo_once(&mut server);
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+
+        let ticket = {
+            let mut client = client::start(&addr, client_keypair.clone(), &trusted_pks, &ClientConfig::new()).unwrap();
+            assert!(client.resumption_ticket().is_none());
+            client.write(&client_msg).unwrap();
+            assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+            client.resumption_ticket().unwrap().clone()
+        };
+        assert!(ticket.server_long_pk() == &server_pk);
+
+        // tickets can be saved and loaded again
+        let ticket = ResumptionTicket::from_bytes(&ticket.to_bytes()).unwrap();
+
+        for _ in 0..2 {
+            let mut client = client::resume(&addr, client_keypair.clone(), &trusted_pks, &ticket, &ClientConfig::new()).unwrap();
+            assert_eq!(client.protocol_version(), common::message::PROTOCOL_VERSION);
+            client.write(&client_msg).unwrap();
+            assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+            assert!(&recv_buf[0..MESSAGE_SIZE] == client_msg.as_slice());
+        }
+
+        // the ticket is no good once its server is not trusted any more. Nothing is sent
+        let untrusted: HashMap<key_id::PublicKeyId, PublicKey> = HashMap::new();
+        expect_err!(client::resume_on(pipe_pair().0, client_keypair.clone(), &untrusted, &ticket, &ClientConfig::new()), common::Error::Resume(common::message::Error::PubKeyId));
+
+        // nor once it is revoked
+        let mut revocations = revocation::RevocationList::new();
+        revocations.revoke(key_id::id_of_pk(&server_pk), None, "");
+        let mut config = ClientConfig::new();
+        config.revocations = Some(&revocations);
+        expect_err!(client::resume_on(pipe_pair().0, client_keypair, &trusted_pks, &ticket, &config), common::Error::Revoked(..));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn lost_resume_accept() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (client_end, server_end) = lossy_pipe_pair(vec![], vec![]);
+        // the server loses its first resume accept
+        let (resume_client_end, resume_server_end) = lossy_pipe_pair(vec![], vec![1]);
+
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let ticket_key = server::TicketKey::new();
+            let mut config = ServerConfig::new(server_keypair);
+            config.ticket_key = Some(&ticket_key);
+            {
+                let mut server = server::do_key_exchange(Ok(server_end), &config, &server_trusted_pks).unwrap();
+                server.issue_ticket(&ticket_key, Duration::from_secs(60)).unwrap();
+                server.write(b"welcome").unwrap();
+            }
+
+            let mut server = server::do_key_exchange(Ok(resume_server_end), &config, &server_trusted_pks).unwrap();
+            // the device repeats its resume packet, which we answer while waiting for data
+            echo_once(&mut server);
+        });
+
+        let mut buf = [0 as u8; 7];
+        let ticket = {
+            let mut client = client::start_on(client_end, client_keypair.clone(), &trusted_pks, &ClientConfig::new()).unwrap();
+            assert_eq!(client.read(&mut buf).unwrap(), 7);
+            client.resumption_ticket().unwrap().clone()
+        };
+
+        let mut client = client::resume_on(resume_client_end, client_keypair, &trusted_pks, &ticket, &ClientConfig::new()).unwrap();
+        client.write(b"resumed").unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf, b"resumed");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn psk() {
+        let psk = PreSharedKey::new(b"sensor 12", &sodiumoxide::randombytes::randombytes(32)).unwrap();
+        let wrong_psk = PreSharedKey::new(b"sensor 12", &sodiumoxide::randombytes::randombytes(32)).unwrap();
+        let unknown_psk = PreSharedKey::new(b"sensor 13", &sodiumoxide::randombytes::randombytes(32)).unwrap();
+        assert!(PreSharedKey::new(b"short", &[0; MIN_PSK_BYTES - 1]).is_none());
+
+        let mut psks = HashMap::new();
+        psks.insert(psk.id().to_vec(), psk.clone());
+
+        let (listener, addr) = listen_on_free_port();
+        let server_thread = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+
+            let mut server = server::do_psk_key_exchange(incoming.next().unwrap(), &psks).unwrap();
+            assert_eq!(server.psk_id(), Some(&b"sensor 12"[..]));
+            // tickets are tied to the device's long-term key
+            assert!(server.issue_ticket(&server::TicketKey::new(), Duration::from_secs(60)).is_err());
+            echo_once(&mut server);
+
+            assert!(server::do_psk_key_exchange(incoming.next().unwrap(), &psks).is_err());
+            expect_err!(server::do_psk_key_exchange(incoming.next().unwrap(), &psks), common::Error::DeviceFirst(common::message::Error::PskId));
+        });
+
+        let mut client = client::start_psk(&addr, &psk).unwrap();
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        client.write(&client_msg).unwrap();
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        assert!(&recv_buf[0..MESSAGE_SIZE] == client_msg.as_slice());
+        drop(client);
+
+        // the device can't tell an unknown id from a wrong key
+        for bad_psk in &[wrong_psk, unknown_psk] {
+            expect_err!(client::start_psk(&addr, bad_psk), common::Error::ServerFirst(common::message::Error::Crypto));
+        }
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn anonymous_client() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut config = ServerConfig::new(server_keypair);
+            config.allow_anonymous = true;
+            let mut incoming = listener.incoming();
+
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            assert!(server.peer_is_anonymous());
+            assert!(server.peer_key_id().is_none());
+            echo_once(&mut server);
+
+            // devices with an identity can still use the same server
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            assert!(!server.peer_is_anonymous());
+            echo_once(&mut server);
+
+            // ordinary servers turn anonymous devices away
+            config.allow_anonymous = false;
+            assert!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).is_err());
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+
+        let mut client = client::start_anonymous(&addr, &trusted_pks, &ClientConfig::new()).unwrap();
+        assert!(client.key_id().is_none());
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        assert!(&recv_buf[0..MESSAGE_SIZE] == client_msg.as_slice());
+        drop(client);
+
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        assert!(client::start_anonymous(&addr, &trusted_pks, &ClientConfig::new()).is_err());
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn hidden_identity() {
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+        let stranger_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_id = key_id::id_of_pk(&client_keypair.0);
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let config = ServerConfig::new(server_keypair);
+            let mut incoming = listener.incoming();
+
+            // the server still finds out who the device is
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            assert_eq!(server.peer_key_id(), Some(client_id));
+            echo_once(&mut server);
+
+            // devices it doesn't trust are still turned away
+            assert!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).is_err());
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+
+        let mut client = client::start_hidden(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        assert_eq!(client.protocol_version(), common::message::PROTOCOL_VERSION);
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        assert!(&recv_buf[0..MESSAGE_SIZE] == client_msg.as_slice());
+        drop(client);
+
+        // and told why, even though the server only finds out who they are from their last packet
+        expect_err!(client::start_hidden(&addr, stranger_keypair, &trusted_pks, &ClientConfig::new()), common::Error::Rejected(ErrorReason::UntrustedIdentity));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        const KNOWN_SERVERS_PATH: &'static str = "/tmp/proj_net_known_servers_test";
+        let _ = fs::remove_file(KNOWN_SERVERS_PATH);
+
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let impostor_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let server_pk = server_keypair.0.clone();
+        let impostor_pk = impostor_keypair.0.clone();
+
+        // the server knows the client but the client knows nothing about the server
+        let mut server_trusted_pks = HashMap::new();
+        server_trusted_pks.insert(key_id::id_of_pk(&client_keypair.0), client_keypair.0.clone());
+
+        let (listener, addr) = listen_on_free_port();
+        let server_thread = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+
+            let config = ServerConfig::new(server_keypair);
+            for _ in 0..2 {
+                let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+                echo_once(&mut server);
+            }
+
+            // the client won't finish the key exchange with a different key
+            assert!(server::do_key_exchange(incoming.next().unwrap(), &ServerConfig::new(impostor_keypair), &server_trusted_pks).is_err());
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+
+        // first use records the key
+        let mut known_servers = known_servers::KnownServers::open(KNOWN_SERVERS_PATH).unwrap();
+        assert!(known_servers.get(&addr).is_none());
+        let mut client = client::start_tofu(&addr, client_keypair.clone(), &mut known_servers, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+        assert!(known_servers.get(&addr) == Some(&server_pk));
+
+        // later connections use the key from the file
+        let mut known_servers = known_servers::KnownServers::open(KNOWN_SERVERS_PATH).unwrap();
+        assert!(known_servers.get(&addr) == Some(&server_pk));
+        let mut client = client::start_tofu(&addr, client_keypair.clone(), &mut known_servers, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        expect_err!(client::start_tofu(&addr, client_keypair, &mut known_servers, &ClientConfig::new()), common::Error::ServerKeyMismatch(expected, presented) => {
+            assert_eq!(expected, fingerprint(&server_pk));
+            assert_eq!(presented, fingerprint(&impostor_pk));
+        });
+
+        server_thread.join().unwrap();
+        fs::remove_file(KNOWN_SERVERS_PATH).unwrap();
+    }
+
+    #[test]
+    fn untrusted_device() {
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+
+        // the client trusts the server but not the other way around
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(key_id::id_of_pk(&server_keypair.0), server_keypair.0.clone());
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            expect_err!(server::do_key_exchange(listener.incoming().next().unwrap(), &ServerConfig::new(server_keypair), &server_trusted_pks), common::Error::DeviceFirst(common::message::Error::PubKeyId));
+        });
+
+        expect_err!(client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()), common::Error::Rejected(ErrorReason::UntrustedIdentity));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn revoked_keys() {
+        const REVOCATIONS_PATH: &'static str = "/tmp/proj_net_revocations_test";
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+        let server_pk = server_keypair.0.clone();
+
+        // the client is revoked now and the server only in the future
+        let contents = format!("# revoked keys\n{} stolen from the van\n\n{} @{} rotating\n", fingerprint(&client_keypair.0), fingerprint(&server_pk), u64::max_value());
+        let mut file = fs::File::create(REVOCATIONS_PATH).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        drop(file);
+        let revocations = revocation::RevocationList::open(REVOCATIONS_PATH).unwrap();
+        fs::remove_file(REVOCATIONS_PATH).unwrap();
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_revocations = revocations.clone();
+        let server_thread = thread::spawn(move || {
+            let mut config = ServerConfig::new(server_keypair);
+            config.revocations = Some(&server_revocations);
+            let mut incoming = listener.incoming();
+
+            expect_err!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks), common::Error::Revoked(_, revocation) => assert_eq!(revocation.reason, "stolen from the van"));
+
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            echo_once(&mut server);
+
+            assert!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).is_err());
+        });
+
+        // revoked devices look just like untrusted ones
+        expect_err!(client::start(&addr, client_keypair.clone(), &trusted_pks, &ClientConfig::new()), common::Error::Rejected(ErrorReason::UntrustedIdentity));
+
+        // the server's revocation hasn't started yet
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        {
+            let mut config = ClientConfig::new();
+            config.revocations = Some(&revocations);
+            let mut client = client::start(&addr, client_keypair.clone(), &trusted_pks, &config).unwrap();
+            client.write(&client_msg).unwrap();
+            assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        }
+
+        let mut revocations = revocations;
+        revocations.revoke(key_id::id_of_pk(&server_pk), None, "");
+        let mut config = ClientConfig::new();
+        config.revocations = Some(&revocations);
+        expect_err!(client::start(&addr, client_keypair, &trusted_pks, &config), common::Error::Revoked(revoked, _) => assert_eq!(revoked, fingerprint(&server_pk)));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn server_key_rotation() {
+        let old_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let new_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let unknown_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let (old_pk, new_pk) = (old_keypair.0.clone(), new_keypair.0.clone());
+
+        let mut server_trusted_pks = HashMap::new();
+        server_trusted_pks.insert(key_id::id_of_pk(&client_keypair.0), client_keypair.0.clone());
+
+        let (listener, addr) = listen_on_free_port();
+        let new_id = key_id::id_of_pk(&new_pk);
+        let server_new_id = new_id.clone();
+        let server_thread = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+            let ticket_key = server::TicketKey::new();
+            let mut config = ServerConfig::new(old_keypair);
+            config.add_keypair(new_keypair);
+            config.ticket_key = Some(&ticket_key);
+
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            echo_once(&mut server);
+
+            // hidden and resumed sessions go on with the key the device asked for too
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+            assert!(server.key_id() == Some(server_new_id.clone()));
+            server.issue_ticket(&ticket_key, Duration::from_secs(60)).unwrap();
+            echo_once(&mut server);
+
+            for _ in 0..2 {
+                let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks).unwrap();
+                assert!(server.key_id() == Some(server_new_id.clone()));
+                echo_once(&mut server);
+            }
+
+            for _ in 0..2 {
+                expect_err!(server::do_key_exchange(incoming.next().unwrap(), &config, &server_trusted_pks), common::Error::DeviceFirst(common::message::Error::ServerKeyId));
+            }
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+
+        // devices which have not been told about the new key get the old one
+        let mut old_trusted_pks = HashMap::new();
+        old_trusted_pks.insert(key_id::id_of_pk(&old_pk), old_pk.clone());
+        let mut client = client::start(&addr, client_keypair.clone(), &old_trusted_pks, &ClientConfig::new()).unwrap();
+        assert!(client.peer_long_pk() == Some(&old_pk));
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        let mut new_trusted_pks = HashMap::new();
+        new_trusted_pks.insert(new_id.clone(), new_pk.clone());
+        new_trusted_pks.insert(key_id::id_of_pk(&unknown_keypair.0), unknown_keypair.0.clone());
+        let mut config = ClientConfig::new();
+        config.server_long_pk_id = Some(new_id);
+        let ticket = {
+            let mut client = client::start(&addr, client_keypair.clone(), &new_trusted_pks, &config).unwrap();
+            assert!(client.peer_long_pk() == Some(&new_pk));
+            client.write(&client_msg).unwrap();
+            assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+            client.resumption_ticket().unwrap().clone()
+        };
+
+        let mut client = client::start_hidden(&addr, client_keypair.clone(), &new_trusted_pks, &config).unwrap();
+        assert!(client.peer_long_pk() == Some(&new_pk));
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        let mut client = client::resume(&addr, client_keypair.clone(), &new_trusted_pks, &ticket, &config).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        config.server_long_pk_id = Some(key_id::id_of_pk(&unknown_keypair.0));
+        expect_err!(client::start(&addr, client_keypair.clone(), &new_trusted_pks, &config), common::Error::Rejected(ErrorReason::UnknownServerKey));
+        expect_err!(client::start_hidden(&addr, client_keypair, &new_trusted_pks, &config), common::Error::Rejected(ErrorReason::UnknownServerKey));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn key_files() {
+        const KEYPAIR_PATH: &'static str = "/tmp/proj_net_key_files_test";
+
+        key_gen_to_file(KEYPAIR_PATH);
+        let (pks, keypair) = get_keys(KEYPAIR_PATH, String::from(KEYPAIR_PATH) + ".pub");
+        assert!(pks.lookup(&key_id::id_of_pk(&keypair.0)) == Some(keypair.0.clone()));
+        assert_eq!(pks.len(), 1);
+
+        // the same keypair comes back every time
+        let again = get_keypair(KEYPAIR_PATH);
+        assert!(again.0 == keypair.0);
+        assert_eq!(&again.1[..], &keypair.1[..]);
+
+        // fingerprints are hex too
+        let printed = fingerprint(&keypair.0);
+        assert_eq!(from_hex(&printed, ':').unwrap(), key_id::id_of_pk(&keypair.0).digest[..].to_vec());
+        assert!(from_hex("0G", ':').is_none());
+
+        fs::remove_file(KEYPAIR_PATH).unwrap();
+        fs::remove_file(String::from(KEYPAIR_PATH) + ".pub").unwrap();
+    }
+
+    #[test]
+    fn trust_store_updates() {
+        const PUBLIC_KEYS_PATH: &'static str = "/tmp/proj_net_trust_store_test";
+
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let client_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let other_pk = proj_crypto::asymmetric::key_exchange::gen_keypair().0;
+        let (server_id, client_id) = (key_id::id_of_pk(&server_keypair.0), key_id::id_of_pk(&client_keypair.0));
+
+        // the client reads the server's key from a file, which it only gets added to later
+        let mut file = fs::File::create(PUBLIC_KEYS_PATH).unwrap();
+        file.write_all(&[b"PK: ", to_utf8_hex(&other_pk[..]).as_slice(), b"\n"].concat()).unwrap();
+        let client_trusted_pks = trust::FileTrustStore::open(PUBLIC_KEYS_PATH).unwrap();
+        let client_changes = client_trusted_pks.watch();
+        assert!(client_trusted_pks.lookup(&server_id).is_none());
+
+        file.write_all(&[b"PK: ", to_utf8_hex(&server_keypair.0[..]).as_slice(), b"\n"].concat()).unwrap();
+        drop(file);
+        client_trusted_pks.reload().unwrap();
+        assert_eq!(client_changes.try_recv().unwrap(), trust::TrustChange::Added(server_id));
+        assert_eq!(client_trusted_pks.keys().len(), 2);
+        fs::remove_file(PUBLIC_KEYS_PATH).unwrap();
+
+        // the server's keys are changed from this thread while it runs
+        let server_trusted_pks = Arc::new(trust::MemoryTrustStore::new());
+        assert!(server_trusted_pks.insert(client_keypair.0.clone()));
+        let server_changes = server_trusted_pks.watch();
+
+        let (listener, addr) = listen_on_free_port();
+        let server_store = server_trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+
+            let config = ServerConfig::new(server_keypair);
+            let mut server = server::do_key_exchange(incoming.next().unwrap(), &config, &*server_store).unwrap();
+            echo_once(&mut server);
+
+            expect_err!(server::do_key_exchange(incoming.next().unwrap(), &config, &*server_store), common::Error::DeviceFirst(common::message::Error::PubKeyId));
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        let mut client = client::start(&addr, client_keypair.clone(), &client_trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        assert!(server_trusted_pks.remove(&client_id).is_some());
+        assert_eq!(server_changes.try_recv().unwrap(), trust::TrustChange::Removed(client_id));
+
+        expect_err!(client::start(&addr, client_keypair, &client_trusted_pks, &ClientConfig::new()), common::Error::Rejected(ErrorReason::UntrustedIdentity));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn authorization() {
+        let server_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let tenant_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let stranger_keypair = proj_crypto::asymmetric::key_exchange::gen_keypair();
+        let tenant_id = key_id::id_of_pk(&tenant_keypair.0);
+
+        // both devices are trusted but only one is authorized
+        let mut trusted_pks = HashMap::new();
+        trusted_pks.insert(key_id::id_of_pk(&server_keypair.0), server_keypair.0.clone());
+        trusted_pks.insert(tenant_id.clone(), tenant_keypair.0.clone());
+        trusted_pks.insert(key_id::id_of_pk(&stranger_keypair.0), stranger_keypair.0.clone());
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let server_thread = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+            let authorize = |id: Option<&key_id::PublicKeyId>, addr: Option<std::net::SocketAddr>| {
+                assert!(addr.unwrap().ip().is_loopback());
+                match id {
+                    Some(id) if *id == tenant_id => server::Authorization::Accept(String::from("acme")),
+                    Some(_) => server::Authorization::Reject(ErrorReason::Policy),
+                    None => server::Authorization::Accept(String::from("guest")),
+                }
+            };
+
+            let mut config = ServerConfig::new(server_keypair);
+            config.allow_anonymous = true;
+
+            let mut server = server::do_key_exchange_with_authorization(incoming.next().unwrap(), &config, &server_trusted_pks, &authorize).unwrap();
+            assert_eq!(server.app_data(), "acme");
+            echo_once(&mut server);
+
+            let mut server = server::do_key_exchange_with_authorization(incoming.next().unwrap(), &config, &server_trusted_pks, &authorize).unwrap();
+            assert_eq!(server.app_data(), "guest");
+            echo_once(&mut server);
+
+            // the stranger is refused whether or not it hides who it is
+            for _ in 0..2 {
+                expect_err!(server::do_key_exchange_with_authorization(incoming.next().unwrap(), &config, &server_trusted_pks, &authorize), common::Error::Rejected(ErrorReason::Policy));
+            }
+        });
+
+        let client_msg = sodiumoxide::randombytes::randombytes(MESSAGE_SIZE);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        let mut client = client::start(&addr, tenant_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        let mut client = client::start_anonymous(&addr, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(&client_msg).unwrap();
+        assert_eq!(client.read(&mut recv_buf).unwrap(), MESSAGE_SIZE);
+        drop(client);
+
+        // the key exchange itself works, so the stranger only finds out when it reads
+        let mut client = client::start(&addr, stranger_keypair.clone(), &trusted_pks, &ClientConfig::new()).unwrap();
+        let error = client.read(&mut recv_buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(error.get_ref().unwrap().downcast_ref::<ErrorReason>(), Some(&ErrorReason::Policy));
+
+        let mut client = client::start_hidden(&addr, stranger_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        let error = client.read(&mut recv_buf).unwrap_err();
+        assert_eq!(error.get_ref().unwrap().downcast_ref::<ErrorReason>(), Some(&ErrorReason::Policy));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn authorization_needs_proof() {
+        let (server_keypair, tenant_keypair, trusted_pks) = trusted_keypairs();
+
+        let (listener, addr) = listen_on_free_port();
+        let server_trusted_pks = trusted_pks.clone();
+        let (asked, asked_about) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let authorize = |id: Option<&key_id::PublicKeyId>, _: Option<std::net::SocketAddr>| {
+                asked.send(id.cloned()).unwrap();
+                server::Authorization::Accept(())
+            };
+
+            let config = ServerConfig::new(server_keypair);
+            expect_err!(server::do_key_exchange_with_authorization(listener.incoming().next().unwrap(), &config, &server_trusted_pks, &authorize), common::Error::DeviceSecond(_));
+        });
+
+        // the impostor says it is the tenant but does not have the tenant's secret key
+        let impostor_keypair = (tenant_keypair.0.clone(), proj_crypto::asymmetric::key_exchange::gen_keypair().1);
+        let mut recv_buf = [0 as u8; MESSAGE_SIZE];
+        match client::start(&addr, impostor_keypair, &trusted_pks, &ClientConfig::new()) {
+            Ok(mut client) => assert!(client.read(&mut recv_buf).is_err()),
+            Err(_) => (),
+        };
+
+        // the application is never asked about a device which has not proven who it is
+        server_thread.join().unwrap();
+        assert!(asked_about.try_recv().is_err());
+    }
+
+    #[test]
+    fn shutdown_write() {
+        use sodiumoxide::crypto::hash::sha256;
+
+        // the server does not know how much is coming so it reads until the client says it has finished
+        const UPLOAD_SIZE: usize = 100000;
+
+        let (server_keypair, client_keypair, trusted_pks) = trusted_keypairs();
+
+        let (addr, server_thread) = spawn_server(server_keypair, &trusted_pks, |mut server| {
+            let mut upload = Vec::new();
+            assert_eq!(server.read_to_end(&mut upload).unwrap(), UPLOAD_SIZE);
+
+            // the client is still listening
+            server.write(&sha256::hash(&upload)[..]).unwrap();
+            server.shutdown_write().unwrap();
+            assert_eq!(server.write(b"too late").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        });
+
+        let upload = sodiumoxide::randombytes::randombytes(UPLOAD_SIZE);
+        let mut client = client::start(&addr, client_keypair, &trusted_pks, &ClientConfig::new()).unwrap();
+        client.write(&upload).unwrap();
+        client.shutdown_write().unwrap();
+        assert_eq!(client.write(b"too late").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        let mut digest = Vec::new();
+        client.read_to_end(&mut digest).unwrap();
+        assert_eq!(&digest[..], &sha256::hash(&upload)[..]);
+
+        server_thread.join().unwrap();
+    }
+}
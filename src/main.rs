@@ -32,9 +32,10 @@ fn print_usage(executable_name: &str, opts: &Options) -> ! {
     println!("The cryptography used has not been reviewed by any experts. You should not use it for anything serious.\n");
     
     let brief1 = format!("To generate keys: {} --keygen OUTPUT_FILE\n", executable_name);
-    let brief2 = format!("To run a server or client: {} --{{server, client}} MY_KEYPAIR --public-key PUBLIC_KEY_FILE [--socket IPADDR:PORT]", executable_name);
+    let brief2 = format!("To run a server or client: {} --{{server, client}} MY_KEYPAIR --public-key PUBLIC_KEY_FILE [--revocations REVOCATION_FILE] [--socket IPADDR:PORT]\n", executable_name);
+    let brief3 = format!("To run a client which trusts servers on first use: {} --client MY_KEYPAIR --known-servers KNOWN_SERVERS_FILE [--revocations REVOCATION_FILE] [--socket IPADDR:PORT]", executable_name);
 
-    print!("{}", opts.usage(&(brief1+&brief2)));
+    print!("{}", opts.usage(&(brief1+&brief2+&brief3)));
     process::exit(1)
 }
 
@@ -60,6 +61,12 @@ fn main() {
     // required for client and server mode
     opts.optopt("k", "public-key", "The trusted public keys", "PUBLIC_KEY_FILE");
 
+    // alternative to public-key for client mode
+    opts.optopt("", "known-servers", "Trust servers on first use, remembering their public keys in KNOWN_SERVERS_FILE", "KNOWN_SERVERS_FILE");
+
+    // optional for client and server modes
+    opts.optopt("r", "revocations", "Refuse peers whose keys are revoked in REVOCATION_FILE, even if they are trusted", "REVOCATION_FILE");
+
     // optional for client and server modes
     opts.optopt("s", "socket", &format!("The socket to listen on (server) or to connect to (client). The default is {}.", DEFAULT_SOCKET_ADDR), "IPADDR:PORT");
 
@@ -81,12 +88,33 @@ fn main() {
         }
     
 
-    // server and client modes require the public key of the target to be specified
-    if (matches.opt_present("server") | matches.opt_present("client")) & !matches.opt_present("public-key") {
+    // server and client modes require the public key of the target to be specified, unless the client is trusting servers on first use
+    if matches.opt_present("known-servers") {
+        if !matches.opt_present("client") | matches.opt_present("public-key") {
+            println!("--known-servers only goes with --client, instead of --public-key\n");
+            print_usage(&executable_name, &opts);
+        }
+    } else if (matches.opt_present("server") | matches.opt_present("client")) & !matches.opt_present("public-key") {
         println!("Server and client modes require a public key to be specified.\n");
         print_usage(&executable_name, &opts);
     }
 
+    if matches.opt_present("revocations") & !(matches.opt_present("server") | matches.opt_present("client")) {
+        println!("--revocations only goes with --server or --client\n");
+        print_usage(&executable_name, &opts);
+    }
+
+    let revocations = match matches.opt_str("revocations") {
+        Some(path) => match revocation::RevocationList::open(&path) {
+            Err(e) => {
+                println!("Failed to read the revocation file: {}\n", e);
+                print_usage(&executable_name, &opts);
+            },
+            Ok(r) => Some(r),
+        },
+        None => None,
+    };
+
     // do specified operation
     
     if matches.opt_present("keygen") {
@@ -99,22 +127,35 @@ fn main() {
    
     if matches.opt_present("server") {
         if matches.opt_present("socket") {
-            return server(&matches.opt_str("server").unwrap(), &matches.opt_str("public-key").unwrap(), &matches.opt_str("socket").unwrap());
+            return server(&matches.opt_str("server").unwrap(), &matches.opt_str("public-key").unwrap(), revocations.as_ref(), &matches.opt_str("socket").unwrap());
         } else {
-            return server(&matches.opt_str("server").unwrap(), &matches.opt_str("public-key").unwrap(), DEFAULT_SOCKET_ADDR);
+            return server(&matches.opt_str("server").unwrap(), &matches.opt_str("public-key").unwrap(), revocations.as_ref(), DEFAULT_SOCKET_ADDR);
         }
     }
 
+    if matches.opt_present("known-servers") {
+        let mut known_servers = match known_servers::KnownServers::open(&matches.opt_str("known-servers").unwrap()) {
+            Err(e) => {
+                println!("Failed to read the known servers file: {}\n", e);
+                print_usage(&executable_name, &opts);
+            },
+            Ok(k) => k,
+        };
+
+        let socket = matches.opt_str("socket").unwrap_or(String::from(DEFAULT_SOCKET_ADDR));
+        return tofu_client(&matches.opt_str("client").unwrap(), &mut known_servers, revocations.as_ref(), &socket);
+    }
+
     if matches.opt_present("client") {
         if matches.opt_present("socket") {
-            return client(&matches.opt_str("client").unwrap(), &matches.opt_str("public-key").unwrap(), &matches.opt_str("socket").unwrap());
+            return client(&matches.opt_str("client").unwrap(), &matches.opt_str("public-key").unwrap(), revocations.as_ref(), &matches.opt_str("socket").unwrap());
         } else {
-            return client(&matches.opt_str("client").unwrap(), &matches.opt_str("public-key").unwrap(), DEFAULT_SOCKET_ADDR);
+            return client(&matches.opt_str("client").unwrap(), &matches.opt_str("public-key").unwrap(), revocations.as_ref(), DEFAULT_SOCKET_ADDR);
         }
     }
 }
 
-fn server(my_keypair_path: &str, pk_path: &str, socket: &str) {
+fn server(my_keypair_path: &str, pk_path: &str, revocations: Option<&revocation::RevocationList>, socket: &str) {
     let listener = match server::listen(socket) {
         Err(e) => panic!("Server failed to start with error {:?}", e),
         Ok(l) => l,
@@ -122,17 +163,22 @@ fn server(my_keypair_path: &str, pk_path: &str, socket: &str) {
 
     let (pks, keypair) = get_keys(my_keypair_path, pk_path);
 
-    let mut server = server::do_key_exchange(listener.incoming().next().unwrap(), &keypair, &pks).unwrap();
+    let mut config = server::ServerConfig::new(keypair);
+    config.revocations = revocations;
+
+    let mut server = server::do_key_exchange(listener.incoming().next().unwrap(), &config, &pks).unwrap();
 
     server.blocking_off(1);
 
     interactive(&mut server);
 }
 
-fn client(my_keypair_path: &str, pk_path: &str, socket: &str) {
+fn client(my_keypair_path: &str, pk_path: &str, revocations: Option<&revocation::RevocationList>, socket: &str) {
     let (pks, keypair) = get_keys(my_keypair_path, pk_path);
+    let mut config = client::ClientConfig::new();
+    config.revocations = revocations;
     
-    let mut client = match client::start(socket, keypair, &pks) {
+    let mut client = match client::start(socket, keypair, &pks, &config) {
         Err(e) => panic!("Client failed to start with error {:?}", e),
         Ok(c) => c,
     };
@@ -141,6 +187,20 @@ fn client(my_keypair_path: &str, pk_path: &str, socket: &str) {
     interactive(&mut client);
 }       
 
+fn tofu_client(my_keypair_path: &str, known_servers: &mut known_servers::KnownServers, revocations: Option<&revocation::RevocationList>, socket: &str) {
+    let keypair = get_keypair(my_keypair_path);
+    let mut config = client::ClientConfig::new();
+    config.revocations = revocations;
+
+    let mut client = match client::start_tofu(socket, keypair, known_servers, &config) {
+        Err(e) => panic!("Client failed to start with error {:?}", e),
+        Ok(c) => c,
+    };
+    client.blocking_off(1);
+
+    interactive(&mut client);
+}
+
 fn interactive<T: Read + Write>(channel: &mut T) -> ! {
     let mut recv_buf = [0 as u8; 128];
     loop {
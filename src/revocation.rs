@@ -0,0 +1,132 @@
+//! Refusing long-term keys which have been revoked, even while they are still trusted
+
+/*  This file is part of project-net.
+    project-net is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-net is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-net.  If not, see http://www.gnu.org/licenses/.*/
+
+use proj_crypto::asymmetric::*;
+use proj_crypto::asymmetric::key_id::PublicKeyId;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use common::unix_time;
+use from_hex;
+
+/// Marks the time a key is revoked from in the revocation file
+const SINCE_PREFIX: char = '@';
+
+/// Why and when a key was revoked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revocation {
+    /// unix time from which the key is refused. None if it is refused straight away
+    pub since: Option<u64>,
+    /// free text explaining the revocation, for the logs. Empty if none was given
+    pub reason: String,
+}
+
+/// The ids of long-term public keys which must not be accepted, whatever the trusted public keys say.
+/// The file has one line per key: its fingerprint (see fingerprint()), optionally '@' followed by the unix time it is revoked from, then optionally a reason.
+/// For example "AB:CD:...:EF @1500000000 stolen from the van". Blank lines and lines starting with '#' are ignored.
+#[derive(Clone)]
+pub struct RevocationList {
+    revoked: HashMap<PublicKeyId, Revocation>,
+}
+
+impl RevocationList {
+    /// A list which does not revoke anything yet
+    pub fn new() -> RevocationList {
+        RevocationList { revoked: HashMap::new() }
+    }
+
+    /// Read a revocation file. Unlike the known servers file it has to exist: a missing list should not quietly let revoked keys in.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RevocationList> {
+        let mut contents = String::new();
+        match fs::File::open(&path) {
+            Ok(mut f) => match f.read_to_string(&mut contents) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut list = RevocationList::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_line(line) {
+                Some((id, revocation)) => { list.revoked.insert(id, revocation); },
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line in the revocation file: '{}'", line))),
+            };
+        }
+
+        Ok(list)
+    }
+
+    /// Revoke the key with this id from since (or straight away if it is None)
+    pub fn revoke(&mut self, id: PublicKeyId, since: Option<u64>, reason: &str) {
+        self.revoked.insert(id, Revocation { since: since, reason: String::from(reason) });
+    }
+
+    /// The revocation of this key if it is revoked by now
+    pub fn check(&self, pk: &PublicKey) -> Option<&Revocation> {
+        match self.revoked.get(&key_id::id_of_pk(pk)) {
+            Some(r) => match r.since {
+                Some(t) if t > unix_time() => None,
+                _ => Some(r),
+            },
+            None => None,
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PublicKeyId, Revocation)> {
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let id = match parts.next() {
+        Some(f) => match parse_fingerprint(f) {
+            Some(id) => id,
+            None => return None,
+        },
+        None => return None,
+    };
+    let mut rest = parts.next().unwrap_or("").trim();
+
+    let since = if rest.starts_with(SINCE_PREFIX) {
+        let mut parts = rest[1..].splitn(2, char::is_whitespace);
+        let time = match parts.next().map(|t| t.parse::<u64>()) {
+            Some(Ok(t)) => t,
+            _ => return None,
+        };
+        rest = parts.next().unwrap_or("").trim();
+        Some(time)
+    } else {
+        None
+    };
+
+    Some((id, Revocation { since: since, reason: String::from(rest) }))
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Option<PublicKeyId> {
+    let digest = match from_hex(fingerprint, ':') {
+        Some(d) => d,
+        None => return None,
+    };
+
+    match sha256::Digest::from_slice(&digest) {
+        Some(d) => Some(PublicKeyId { digest: d }),
+        None => None,
+    }
+}
@@ -14,19 +14,71 @@
 
 extern crate sodiumoxide;
 use super::common::*;
-use super::common::message::{receive, send, MessageContent};
+use super::common::message::{receive, send, MessageContent, VersionOffer};
 use std::io;
 use std::time::Duration;
-use std::net::Shutdown;
-use std::net::{TcpStream, TcpListener};
+use std::net::{TcpStream, TcpListener, SocketAddr, ToSocketAddrs};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
 use std::collections::HashMap;
 use proj_crypto::asymmetric::*;
-use Keypair;
+use sodiumoxide::randombytes;
+use sodiumoxide::utils::memzero;
+use {Keypair, PreSharedKey, MIN_PSK_BYTES};
+use stream;
+use datagram::{Datagram, DatagramListener};
+use revocation::RevocationList;
+use trust::TrustStore;
 
-/// Structure containing state information for the server
-pub struct Server {
-    state: ProtocolState,
-    read_buff: Vec<u8>,
+/// Structure containing state information for the server. D is whatever the application attached to the session when it authorized the device (see do_key_exchange_with_authorization())
+pub struct Server<S: Transport = TcpStream, D = ()> {
+    state: ProtocolState<S>,
+    app_data: D,
+}
+
+/// What the application decided about a device once the key exchange had shown who it is
+pub enum Authorization<D> {
+    /// let the device in, attaching this to the Server. For example the device's roles
+    Accept(D),
+    /// close the session, telling the device why
+    Reject(message::ErrorReason),
+}
+
+/// The number of bytes in a ticket key
+pub const TICKET_KEY_BYTES: usize = 32;
+
+/// The key which resumption tickets are sealed with. Tickets can only be used with a server holding the same key so share it between servers behind one address.
+/// Replacing the key makes every ticket sealed with the old one useless.
+pub struct TicketKey {
+    key: Vec<u8>,
+}
+
+impl TicketKey {
+    /// A new random key
+    pub fn new() -> TicketKey {
+        sodiumoxide::init();
+        TicketKey { key: randombytes::randombytes(TICKET_KEY_BYTES) }
+    }
+
+    /// A key saved from as_bytes() earlier. None if it is the wrong length.
+    pub fn from_bytes(bytes: &[u8]) -> Option<TicketKey> {
+        if bytes.len() == TICKET_KEY_BYTES {
+            Some(TicketKey { key: bytes.to_vec() })
+        } else {
+            None
+        }
+    }
+
+    /// The key itself, for saving or sharing with other servers
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl Drop for TicketKey {
+    fn drop(&mut self) {
+        memzero(&mut self.key);
+    }
 }
 
 /// Begins listening for connections
@@ -45,8 +97,283 @@ pub fn listen(socket_addr: &str) -> Result<TcpListener, Error> {
     Ok( listener )
 }
 
+/// Begins listening for connections on a Unix domain socket. Connections accepted from it can be passed to do_key_exchange() like TCP ones.
+pub fn listen_unix<P: AsRef<Path>>(socket_path: P) -> Result<UnixListener, Error> {
+    sodiumoxide::init();
+
+    let listener = match UnixListener::bind(&socket_path) {
+        Err(e) => {
+            log(&format!("Error starting the server: {}", e), LOG_RELEASE);
+            return Err(Error::Bind(e)); },
+        Ok(l) => {
+            log(&format!("Server bound to {}", socket_path.as_ref().display()), LOG_DEBUG);
+            l },
+    };
+
+    Ok( listener )
+}
+
+/// Begins listening for UDP datagrams. Pass the listener to accept_udp() to wait for each device.
+pub fn listen_udp<A: ToSocketAddrs>(socket_addr: A) -> Result<DatagramListener, Error> {
+    sodiumoxide::init();
+
+    match DatagramListener::bind(socket_addr) {
+        Err(e) => {
+            log(&format!("Error starting the server: {}", e), LOG_RELEASE);
+            Err(Error::Bind(e)) },
+        Ok(s) => {
+            log("Server bound to a UDP socket", LOG_DEBUG);
+            Ok(s) },
+    }
+}
+
+/// Wait for a new device to send its first datagram. The result talks only to that device and can be passed to do_key_exchange(). The listener can keep accepting other devices on the same socket.
+/// See the datagram module for what changes over UDP.
+pub fn accept_udp(listener: &DatagramListener) -> Result<Datagram, io::Error> {
+    listener.accept()
+}
+
+/// What a server accepts during its key exchanges apart from the trusted public keys. ServerConfig::new() accepts the devices in trusted_pks and nothing else.
+/// Every option works with every other, and with every entry point which takes a ServerConfig.
+pub struct ServerConfig<'a> {
+    /// never empty. The first one is used unless the device asks for another
+    long_keypairs: Vec<Keypair>,
+    /// devices whose long-term key is in here are refused even if they are still in trusted_pks
+    pub revocations: Option<&'a RevocationList>,
+    /// devices may also resume a session using a ticket sealed with this key (see Server::issue_ticket()). Resumed devices must still be in trusted_pks
+    pub ticket_key: Option<&'a TicketKey>,
+    /// let in devices without a long-term identity too. Use Server::peer_is_anonymous() to tell them apart. Devices which do present a long-term key must still be in trusted_pks
+    pub allow_anonymous: bool,
+}
+
+impl<'a> ServerConfig<'a> {
+    /// A server with this long-term keypair
+    pub fn new(long_keypair: Keypair) -> ServerConfig<'a> {
+        ServerConfig {
+            long_keypairs: vec![long_keypair],
+            revocations: None,
+            ticket_key: None,
+            allow_anonymous: false,
+        }
+    }
+
+    /// Hold another long-term keypair, for example while the server's key is being replaced
+    pub fn add_keypair(&mut self, long_keypair: Keypair) {
+        self.long_keypairs.push(long_keypair);
+    }
+}
+
 /// Takes an incoming connection and performs a key exchange, returning a set up connection or an error.
-pub fn do_key_exchange(incoming: Result<TcpStream, io::Error>, long_keypair: &Keypair, trusted_pks: &HashMap<key_id::PublicKeyId, PublicKey>) -> Result<Server, Error> {
+/// The connection can be any transport, not just one accepted by the listener from listen().
+/// trusted_pks is looked up while the device connects, so a trust::TrustStore which changes affects the next key exchange.
+/// See ServerConfig for the devices which are let in. A device whose key is in config.revocations makes this fail with Error::Revoked.
+pub fn do_key_exchange<S: Transport, T: TrustStore>(incoming: Result<S, io::Error>, config: &ServerConfig, trusted_pks: &T) -> Result<Server<S>, Error> {
+    key_exchange(incoming, config, trusted_pks, |_, _| Authorization::Accept(()))
+}
+
+/// Like do_key_exchange() but authorize decides whether the device may use the session before it is handed back. It is given the id of the device's long-term key, which the device has proven it holds (None for anonymous devices), and the device's address if the transport has one.
+/// Rejected devices get an authenticated error saying why, so the key exchange succeeds for them but their first read fails. Anything they wrote first is thrown away unread.
+pub fn do_key_exchange_with_authorization<S, T, D, F>(incoming: Result<S, io::Error>, config: &ServerConfig, trusted_pks: &T, authorize: F) -> Result<Server<S, D>, Error>
+    where S: Transport, T: TrustStore, F: FnOnce(Option<&key_id::PublicKeyId>, Option<SocketAddr>) -> Authorization<D> {
+    key_exchange(incoming, config, trusted_pks, authorize)
+}
+
+/// Ask the application whether the device may use a session whose key exchange has finished. A device which may not gets an authenticated error saying why
+fn authorize_session<S, D, F>(mut state: ProtocolState<S>, authorize: F) -> Result<Server<S, D>, Error>
+    where S: Transport, F: FnOnce(Option<&key_id::PublicKeyId>, Option<SocketAddr>) -> Authorization<D> {
+    let peer_key_id = state.peer_long_pk.as_ref().map(key_id::id_of_pk);
+    let peer_addr = state.stream.peer_addr();
+
+    match authorize(peer_key_id.as_ref(), peer_addr) {
+        Authorization::Accept(app_data) => Ok(Server{ state: state, app_data: app_data }),
+        Authorization::Reject(reason) => {
+            log(&format!("The application refused the device: {}", reason), LOG_RELEASE);
+            general_close_with_error(&mut state, reason);
+            Err(Error::Rejected(reason)) },
+    }
+}
+
+fn key_exchange<S, T, D, F>(incoming: Result<S, io::Error>, config: &ServerConfig, trusted_pks: &T, authorize: F) -> Result<Server<S, D>, Error>
+    where S: Transport, T: TrustStore, F: FnOnce(Option<&key_id::PublicKeyId>, Option<SocketAddr>) -> Authorization<D> {
+    let long_keypairs = &config.long_keypairs;
+    let revocations = config.revocations;
+    let mut expected_next_n: u64 = 0;
+    let (mut stream, m) = match receive_first(incoming, &mut expected_next_n) {
+        Ok(x) => x,
+        Err(e) => return Err(e),
+    };
+
+    // devices which predate version negotiation need our answer laid out the way it was then
+    let unversioned = match m.content {
+        MessageContent::UnversionedDeviceFirst(..) => true,
+        _ => false,
+    };
+
+    // was it a DeviceFirst message? The device's long-term key id is None for anonymous devices. The id of the server key it expects is None unless it said
+    let (device_ephemeral_pk, device_long_pk_id, server_long_pk_id, offer) = match (m.content, config.ticket_key) {
+        (MessageContent::DeviceFirst(pk, id, offer), _) => (pk, Some(id), None, offer),
+        (MessageContent::UnversionedDeviceFirst(pk, id), _) => (pk, Some(id), None, VersionOffer::unversioned()),
+        (MessageContent::TargetedDeviceFirst(pk, id, server_id, offer), _) => (pk, Some(id), Some(server_id), offer),
+        (MessageContent::AnonymousDeviceFirst(pk, server_id, offer), _) if config.allow_anonymous => (pk, None, server_id, offer),
+        (MessageContent::HiddenDeviceFirst(pk, server_id, offer), _) =>
+            return hidden_key_exchange(stream, long_keypairs, server_id.as_ref(), trusted_pks, revocations, authorize, &pk, &offer, expected_next_n),
+        (MessageContent::Resume(offer, device_nonce, ticket, binder), Some(key)) =>
+            return resume(stream, long_keypairs, trusted_pks, revocations, authorize, key, &offer, &device_nonce, &ticket, &binder),
+        _ => { send_error(&mut stream, 0);
+               let _ = stream.close();
+               return Err(Error::DeviceFirst(message::Error::InvalidOpcode)); },
+    };
+
+    // anonymous devices and devices choosing our key need a version which knows about them
+    let min_version = if server_long_pk_id.is_some() {
+        message::KEY_SELECTION_VERSION
+    } else if device_long_pk_id.is_some() {
+        message::MIN_PROTOCOL_VERSION
+    } else {
+        message::ANONYMOUS_VERSION
+    };
+    let version = match offer.choose() {
+        Some(v) if v >= min_version => v,
+        _ => {
+            log(&format!("The device offered protocol versions {} to {}, which we do not speak", offer.min, offer.max), LOG_RELEASE);
+            send_error(&mut stream, 0);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::Version)); },
+    };
+
+    let long_keypair = match choose_keypair(long_keypairs, server_long_pk_id.as_ref()) {
+        Some(keypair) => keypair,
+        None => {
+            log("The device expected a long-term key which we don't have", LOG_RELEASE);
+            send_rejection(&mut stream, version, message::ErrorReason::UnknownServerKey);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::ServerKeyId)); },
+    };
+
+    // look up the public key
+    let device_long_pk = match device_long_pk_id {
+        Some(id) => match trusted_pks.lookup(&id) {
+            Some(pk) => Some(pk),
+            None => {
+                log("The device's long-term key is not trusted", LOG_RELEASE);
+                send_rejection(&mut stream, version, message::ErrorReason::UntrustedIdentity);
+                let _ = stream.close();
+                return Err(Error::DeviceFirst(message::Error::PubKeyId)); },
+        },
+        None => {
+            log("The device is anonymous", LOG_DEBUG);
+            None },
+    };
+
+    // a revoked device is told no more than an unknown one
+    let revoked = match device_long_pk {
+        Some(ref pk) => check_revocation(revocations, pk),
+        None => Ok(()),
+    };
+    match revoked {
+        Ok(()) => (),
+        Err(e) => {
+            send_rejection(&mut stream, version, message::ErrorReason::UntrustedIdentity);
+            let _ = stream.close();
+            return Err(e); },
+    };
+
+    log("device_first received successfully", LOG_DEBUG);
+
+    // send response
+    let mut server_first = Vec::new();
+    let sent = match (unversioned, device_long_pk.as_ref()) {
+        (true, Some(pk)) => send::unversioned_server_first(&mut server_first, long_keypair, &device_ephemeral_pk, pk),
+        _ => send::server_first(&mut server_first, long_keypair, &device_ephemeral_pk, device_long_pk.as_ref(), server_long_pk_id.as_ref(), version, &offer),
+    };
+    let (session_keys, challenge) = match sent {
+        Err(e) => return Err(Error::ServerFirst(e)),
+        Ok((k, c)) => (k, c)
+    };
+
+    match finish_key_exchange(&mut stream, &server_first, &mut expected_next_n, |s| receive::device_second(s, &session_keys, &challenge)) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    };
+
+    let server = ProtocolState::new(stream, Some(long_keypair.clone()), device_long_pk, session_keys, false, version, 1, expected_next_n);
+
+    // only now has the device proven that it holds its long-term key
+    authorize_session(server, authorize)
+}
+
+/// The keypair with this id, or our first one if the device did not ask for any. None if we don't have the one it asked for
+fn choose_keypair<'k>(long_keypairs: &'k [Keypair], id: Option<&key_id::PublicKeyId>) -> Option<&'k Keypair> {
+    match id {
+        None => long_keypairs.first(),
+        Some(id) => long_keypairs.iter().find(|keypair| key_id::id_of_pk(&keypair.0) == *id),
+    }
+}
+
+/// Takes an incoming connection and performs a key exchange authenticated by a pre-shared key instead of long-term keypairs.
+/// psks holds every key which a device might use, by id. A device which asks for an id that is not in psks is answered as if it had the wrong key, so that nobody can find out which ids the server has.
+/// The key exchange then fails with Error::DeviceFirst(message::Error::PskId) once the device has given up.
+pub fn do_psk_key_exchange<S: Transport>(incoming: Result<S, io::Error>, psks: &HashMap<Vec<u8>, PreSharedKey>) -> Result<Server<S>, Error> {
+    let mut expected_next_n: u64 = 0;
+    let (mut stream, m) = match receive_first(incoming, &mut expected_next_n) {
+        Ok(x) => x,
+        Err(e) => return Err(e),
+    };
+
+    let (device_ephemeral_pk, psk_id, offer) = match m.content {
+        MessageContent::PskDeviceFirst(pk, id, offer) => (pk, id, offer),
+        _ => { send_error(&mut stream, 0);
+               let _ = stream.close();
+               return Err(Error::DeviceFirst(message::Error::InvalidOpcode)); },
+    };
+
+    // anything which can send this packet speaks a version with pre-shared keys
+    let version = match offer.choose() {
+        Some(v) if v >= message::PSK_VERSION => v,
+        _ => {
+            log(&format!("The device offered protocol versions {} to {} for a pre-shared key, which we do not speak", offer.min, offer.max), LOG_RELEASE);
+            send_error(&mut stream, 0);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::Version)); },
+    };
+
+    log("psk_device_first received successfully", LOG_DEBUG);
+
+    // the device can't authenticate our answer under a random key, just as if its key were wrong
+    let psk_known = psks.contains_key(&psk_id);
+    let mut server_first = Vec::new();
+    let sent = match psks.get(&psk_id) {
+        Some(psk) => send::psk_server_first(&mut server_first, &psk.key, &psk_id, &device_ephemeral_pk, version, &offer),
+        None => {
+            log("The device asked for a pre-shared key which we don't have. Answering with a random key", LOG_RELEASE);
+            send::psk_server_first(&mut server_first, &randombytes::randombytes(MIN_PSK_BYTES), &psk_id, &device_ephemeral_pk, version, &offer) },
+    };
+
+    let (session_keys, challenge) = match sent {
+        Err(e) => return Err(Error::ServerFirst(e)),
+        Ok((k, c)) => (k, c)
+    };
+
+    match finish_key_exchange(&mut stream, &server_first, &mut expected_next_n, |s| receive::device_second(s, &session_keys, &challenge)) {
+        Ok(_) if psk_known => (),
+        Ok(_) => {
+            send_error(&mut stream, 1);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::PskId)); },
+        Err(_) if !psk_known => return Err(Error::DeviceFirst(message::Error::PskId)),
+        Err(e) => return Err(e),
+    };
+
+    let mut server = ProtocolState::new(stream, None, None, session_keys, false, version, 1, expected_next_n);
+    server.psk_id = Some(psk_id);
+
+    Ok(Server{ state: server, app_data: () })
+}
+
+/// Accept the connection and receive the packet which starts the key exchange
+fn receive_first<S: Transport>(incoming: Result<S, io::Error>, expected_next_n: &mut u64) -> Result<(S, message::Message), Error> {
+    sodiumoxide::init();
+
     let mut stream = match incoming {
         Ok(s) => s,
         Err(e) => {
@@ -56,132 +383,344 @@ pub fn do_key_exchange(incoming: Result<TcpStream, io::Error>, long_keypair: &Ke
     
     log("Got connection!", LOG_DEBUG);
 
-    // do key exchange
-    let mut expected_next_n: u16 = 0;
-
     let m = match receive::receive_device_first(&mut stream) {
         Err(e) => {
             log(&format!("Error receiving first message: {:?}", e), LOG_RELEASE);
             send_error(&mut stream, 0);
-            stream.shutdown(Shutdown::Both).unwrap();
+            let _ = stream.close();
             return Err(Error::DeviceFirst(e)); },
         Ok(m) => m,
     };
+    stream.end_packet();
 
-    if !check_message_n(&mut expected_next_n, &m) {
+    if !check_message_n(expected_next_n, &m) {
         send_error(&mut stream, 0);
-        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = stream.close();
         return Err(Error::BadMessageN);
     }
 
-    // was it a DeviceFirst message?
-    let (device_ephemeral_pk, device_long_pk_id) = match m.content {
-        MessageContent::DeviceFirst(pk, id) => (pk, id),
-        _ => { send_error(&mut stream, 0);
-               stream.shutdown(Shutdown::Both).unwrap();
-               return Err(Error::DeviceFirst(message::Error::InvalidOpcode)); },
-    };
-
-    // look up the public key
-    let device_long_pk = match key_id::find_public_key(&device_long_pk_id, &trusted_pks) {
-        Some(pk) => pk,
-        None => return Err(Error::DeviceFirst(message::Error::PubKeyId)),
-    };
-
-    log("device_first received successfully", LOG_DEBUG);
+    Ok((stream, m))
+}
 
-    // send response
-    let (session_keys, challenge) = match send::server_first(&mut stream, &long_keypair, &device_ephemeral_pk, &device_long_pk) {
+/// Send our server_first packet and check the device's answer to the challenge in it with receive_reply
+fn finish_key_exchange<S, F>(stream: &mut S, server_first: &[u8], expected_next_n: &mut u64, receive_reply: F) -> Result<MessageContent, Error>
+    where S: Transport, F: FnMut(&mut S) -> Result<message::Message, message::Error> {
+    match stream.write_all(server_first) {
+        Ok(()) => (),
         Err(e) => {
             log("Error sending server_first", LOG_RELEASE);
-            return Err(Error::ServerFirst(e)); },
-        Ok((k, c)) => (k, c)
+            return Err(Error::ServerFirst(message::Error::Write(e))); },
     };
 
     log("server_first sent successfully", LOG_DEBUG);
 
     // receive challenge response
-    let device_second = match receive::device_second(&mut stream, &session_keys, &challenge) {
+    let device_second = match await_handshake_reply(stream, server_first, receive_reply) {
         Err(e) => {
             log("Error validating device response", LOG_RELEASE);
-            send_error(&mut stream, 1);
-            stream.shutdown(Shutdown::Both).unwrap();
+            send_error(stream, 1);
+            let _ = stream.close();
             return Err(Error::DeviceSecond(e)); },
         Ok(m) => m,
     };
 
-    if !check_message_n(&mut expected_next_n, &device_second) {
-        send_error(&mut stream, 1);
-        stream.shutdown(Shutdown::Both).unwrap();
+    if !check_message_n(expected_next_n, &device_second) {
+        send_error(stream, 1);
+        let _ = stream.close();
         return Err(Error::BadMessageN);
     }
 
     match device_second.content {
-        MessageContent::DeviceSecond => (),
-        _ => { send_error(&mut stream, 1);
-               stream.shutdown(Shutdown::Both).unwrap();
+        MessageContent::Error => { send_error(stream, 1);
+               let _ = stream.close();
                return Err(Error::DeviceFirst(message::Error::InvalidOpcode)); },
+        content => {
+            log("Key exchange completed successfully", LOG_DEBUG);
+            Ok(content) },
+    }
+}
+
+/// Finish a key exchange which the device started with a hidden_device_first packet. We find out who the device is from its last packet. server_long_pk_id is the id of our key which the device asked for, if it did
+fn hidden_key_exchange<S, T, D, F>(mut stream: S, long_keypairs: &[Keypair], server_long_pk_id: Option<&key_id::PublicKeyId>, trusted_pks: &T, revocations: Option<&RevocationList>, authorize: F, device_ephemeral_pk: &PublicKey, offer: &VersionOffer, mut expected_next_n: u64) -> Result<Server<S, D>, Error>
+    where S: Transport, T: TrustStore, F: FnOnce(Option<&key_id::PublicKeyId>, Option<SocketAddr>) -> Authorization<D> {
+    let min_version = if server_long_pk_id.is_some() { message::KEY_SELECTION_VERSION } else { message::HIDDEN_IDENTITY_VERSION };
+    let version = match offer.choose() {
+        Some(v) if v >= min_version => v,
+        _ => {
+            log(&format!("The device offered protocol versions {} to {} to hide its identity, which we do not speak", offer.min, offer.max), LOG_RELEASE);
+            send_error(&mut stream, 0);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::Version)); },
+    };
+
+    let long_keypair = match choose_keypair(long_keypairs, server_long_pk_id) {
+        Some(keypair) => keypair,
+        None => {
+            log("The hidden device expected a long-term key which we don't have", LOG_RELEASE);
+            send_rejection(&mut stream, version, message::ErrorReason::UnknownServerKey);
+            let _ = stream.close();
+            return Err(Error::DeviceFirst(message::Error::ServerKeyId)); },
+    };
+
+    log("hidden_device_first received successfully", LOG_DEBUG);
+
+    let mut server_first = Vec::new();
+    let (anonymous_keys, challenge, session_keypair) = match send::hidden_server_first(&mut server_first, long_keypair, device_ephemeral_pk, server_long_pk_id, version, offer) {
+        Err(e) => return Err(Error::ServerFirst(e)),
+        Ok(x) => x,
+    };
+
+    let device_long_pk = match finish_key_exchange(&mut stream, &server_first, &mut expected_next_n, |s| receive::hidden_device_second(s, &anonymous_keys, &session_keypair, &challenge, trusted_pks)) {
+        Ok(MessageContent::HiddenDeviceSecond(pk)) => pk,
+        Ok(MessageContent::UntrustedHiddenDevice) => {
+            log("The hidden device's long-term public key is not trusted", LOG_RELEASE);
+            send_rejection(&mut stream, version, message::ErrorReason::UntrustedIdentity);
+            let _ = stream.close();
+            return Err(Error::DeviceSecond(message::Error::PubKeyId)); },
+        Ok(_) => return Err(Error::DeviceSecond(message::Error::InvalidOpcode)),
+        Err(e) => return Err(e),
+    };
+
+    // we only find out who the device is from its last packet, so the device waits for our answer to it
+    match check_revocation(revocations, &device_long_pk) {
+        Ok(()) => (),
+        Err(e) => {
+            send_rejection(&mut stream, version, message::ErrorReason::UntrustedIdentity);
+            let _ = stream.close();
+            return Err(e); },
+    };
+
+    let identity_shared = key_exchange::key_exchange(&device_long_pk, &session_keypair.1, &session_keypair.0, false);
+    let session_keys = send::hidden_session_keys(&anonymous_keys, &identity_shared);
+
+    let mut server_second = Vec::new();
+    match send::hidden_server_second(&mut server_second, &session_keys, &challenge) {
+        None => (),
+        Some(e) => return Err(Error::ServerSecond(e)),
+    };
+    match stream.write_all(&server_second) {
+        Ok(()) => (),
+        Err(e) => {
+            log("Error sending hidden_server_second", LOG_RELEASE);
+            return Err(Error::ServerSecond(message::Error::Write(e))); },
+    };
+
+    // message 1 was hidden_server_second
+    let mut server = ProtocolState::new(stream, Some(long_keypair.clone()), Some(device_long_pk), session_keys, false, version, 2, expected_next_n);
+
+    // over datagrams the device sends hidden_device_second again until it has our answer
+    if server.stream.is_datagram() {
+        server.handshake_reply = Some(server_second);
+    }
+
+    authorize_session(server, authorize)
+}
+
+/// Finish a key exchange which the device started with a resume packet. The session goes on with the long-term keypair which the device authenticated when the ticket was issued
+fn resume<S, T, D, F>(mut stream: S, long_keypairs: &[Keypair], trusted_pks: &T, revocations: Option<&RevocationList>, authorize: F, ticket_key: &TicketKey, offer: &VersionOffer, device_nonce: &[u8], ticket: &[u8], binder: &[u8]) -> Result<Server<S, D>, Error>
+    where S: Transport, T: TrustStore, F: FnOnce(Option<&key_id::PublicKeyId>, Option<SocketAddr>) -> Authorization<D> {
+    let (mut secret, device_long_pk, server_long_pk_id, expiry) = match receive::open_ticket(ticket_key.as_bytes(), ticket) {
+        Some(t) => t,
+        None => {
+            log("The device presented a resumption ticket which we did not issue", LOG_RELEASE);
+            return refuse_resume(stream, message::Error::Crypto); },
+    };
+
+    let long_keypair = choose_keypair(long_keypairs, Some(&server_long_pk_id));
+
+    let error = if expiry < unix_time() {
+        log("The device presented an expired resumption ticket", LOG_DEBUG);
+        Some(message::Error::Expired)
+    } else if long_keypair.is_none() {
+        log("The device's resumption ticket was issued with a long-term key which we no longer have", LOG_RELEASE);
+        Some(message::Error::ServerKeyId)
+    } else if trusted_pks.lookup(&key_id::id_of_pk(&device_long_pk)) != Some(device_long_pk.clone()) {
+        log("The device which was issued this resumption ticket is no longer trusted", LOG_RELEASE);
+        Some(message::Error::PubKeyId)
+    } else if check_revocation(revocations, &device_long_pk).is_err() {
+        Some(message::Error::PubKeyId)
+    } else if !receive::check_binder(&secret, offer, device_nonce, ticket, binder) {
+        log("The device does not know the secret for its resumption ticket", LOG_RELEASE);
+        Some(message::Error::Crypto)
+    } else {
+        None
+    };
+
+    // tickets are only issued from version 6 so nothing older can be resumed
+    let version = match offer.choose() {
+        Some(v) if v >= message::RESUMPTION_VERSION => Some(v),
+        _ => None,
+    };
+
+    let result = match (error, version) {
+        (Some(e), _) => Err(e),
+        (None, None) => {
+            log(&format!("The device offered protocol versions {} to {} for resumption, which we do not speak", offer.min, offer.max), LOG_RELEASE);
+            Err(message::Error::Version) },
+        (None, Some(v)) => {
+            let mut resume_accept = Vec::new();
+            match send::resume_accept(&mut resume_accept, &secret, ticket, device_nonce, v, offer) {
+                Ok(keys) => Ok((keys, v, resume_accept)),
+                Err(e) => Err(e),
+            }
+        },
     };
+    memzero(&mut secret);
 
-    log("Key exchange completed successfully", LOG_DEBUG);
+    let (session_keys, version, resume_accept) = match result {
+        Ok(r) => r,
+        Err(e) => return refuse_resume(stream, e),
+    };
 
-    let server = ProtocolState {
-        stream: stream,
-        long_keypair: long_keypair.clone(),
-        next_send_n: 1,
-        next_recv_n: expected_next_n,
-        session_keys: session_keys,
-        send_as_device: false,
+    match stream.write_all(&resume_accept) {
+        Ok(()) => (),
+        Err(e) => {
+            log("Error sending resume accept", LOG_RELEASE);
+            return Err(Error::Resume(message::Error::Write(e))); },
     };
 
-    Ok(Server{ state:server, read_buff: Vec::new() }) 
+    log("Session resumed successfully", LOG_DEBUG);
+
+    // the resume packets were number 0 in each direction
+    let mut server = ProtocolState::new(stream, long_keypair.cloned(), Some(device_long_pk), session_keys, false, version, 1, 1);
+    if server.stream.is_datagram() {
+        server.handshake_reply = Some(resume_accept);
+    }
+
+    authorize_session(server, authorize)
 }
 
-impl Server {
-    /// Give up on IO after a timeout
+fn refuse_resume<S: Transport, D>(mut stream: S, e: message::Error) -> Result<Server<S, D>, Error> {
+    send_error(&mut stream, 0);
+    let _ = stream.close();
+    Err(Error::Resume(e))
+}
+
+impl<S: Transport, D> Server<S, D> {
+    /// Give up on IO after a timeout. Panics if the transport can't time out reads.
     pub fn blocking_off(&mut self, milliseconds: u64) {
-        self.state.stream.set_read_timeout(Some(Duration::from_millis(milliseconds))).unwrap(); // 1ms read timeout
+        general_set_read_timeout(&mut self.state, Some(Duration::from_millis(milliseconds)));
     }
 
     /// Block indefinably for IO
     pub fn blocking_on(&mut self) {
-        self.state.stream.set_read_timeout(None).unwrap();
+        general_set_read_timeout(&mut self.state, None);
+    }
+
+    /// The protocol version agreed with the device
+    pub fn protocol_version(&self) -> u8 {
+        self.state.version
+    }
+
+    /// What the application attached to the session when it authorized the device
+    pub fn app_data(&self) -> &D {
+        &self.app_data
+    }
+
+    /// Like app_data() but it can be changed
+    pub fn app_data_mut(&mut self) -> &mut D {
+        &mut self.app_data
+    }
+
+    /// The device's long-term public key. After a full key exchange the device has proved that it holds the key. A resumed session reuses the key sealed in the ticket, which the device proved it was given by showing the ticket's resumption secret.
+    /// None if the device has no long-term identity in this session (see the pre-shared key and anonymous modes).
+    pub fn peer_long_pk(&self) -> Option<&PublicKey> {
+        self.state.peer_long_pk.as_ref()
+    }
+
+    /// The id of the device's long-term public key, as used in the trusted public key map
+    pub fn peer_key_id(&self) -> Option<key_id::PublicKeyId> {
+        self.state.peer_long_pk.as_ref().map(key_id::id_of_pk)
+    }
+
+    /// The id of our own long-term public key, which the device used to look us up. None if we have no long-term identity in this session.
+    pub fn key_id(&self) -> Option<key_id::PublicKeyId> {
+        self.state.long_keypair.as_ref().map(|keypair| key_id::id_of_pk(&keypair.0))
+    }
+
+    /// 32 bytes of secret keying material which only we and the device can derive, for binding application data to this session.
+    /// Different labels (or contexts) give unrelated secrets. Rekeying does not change the result.
+    pub fn export_keying_material(&self, label: &[u8], context: &[u8]) -> Vec<u8> {
+        send::export(&self.state.exporter_secret, label, context)
+    }
+
+    /// An identifier for this session which is the same on both ends and different for every other session (including resumed ones). It is not secret.
+    pub fn session_id(&self) -> Vec<u8> {
+        send::session_id(&self.state.exporter_secret)
+    }
+
+    /// Send data like write() but return a handle which can be used to find out when the peer has received it
+    pub fn write_tracked(&mut self, buf: &[u8]) -> io::Result<u64> {
+        general_write_tracked(&mut self.state, message::DEFAULT_STREAM, buf)
+    }
+
+    /// Has the peer acknowledged the tracked write with this handle?
+    pub fn is_acknowledged(&self, handle: u64) -> bool {
+        self.state.acked_count >= handle
+    }
+
+    /// Block until the peer acknowledges the tracked write with this handle. Data received in the mean time is kept for read()
+    pub fn wait_for_ack(&mut self, handle: u64) -> io::Result<()> {
+        general_wait_for_ack(&mut self.state, handle)
+    }
+
+    /// Replace the session keys without waiting for the message numbers to run out. Returns straight away: data written before the peer answers is held back and sent under the new keys.
+    /// Call flush() to be sure held back data has been sent. Dropping the session only waits DROP_REKEY_WAIT_MS for the peer's answer
+    pub fn rekey(&mut self) -> io::Result<()> {
+        general_rekey(&mut self.state)
+    }
+
+    /// Tell the peer that we have finished sending. Its reads return 0 once it has read everything we sent, but it can still write to us and we can still read.
+    /// Writes fail from now on. Fails if the peer is too old to understand this or over datagram transports.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        general_shutdown_write(&mut self.state)
+    }
+
+    /// Ping the peer whenever it has been quiet for interval while we are reading or writing. If it stays quiet for liveness_timeout while we read, the connection is closed and the read fails with io::ErrorKind::TimedOut.
+    /// Pings are only sent from within reads and writes, so an application which does neither for a while should read with a timeout (see blocking_off()) to keep the connection alive.
+    /// Passing None for interval turns keepalives off. Fails if the peer is too old to answer pings or the transport can't peek (see Transport::can_peek()), such as Unix sockets.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, liveness_timeout: Duration) -> io::Result<()> {
+        general_set_keepalive(&mut self.state, interval, liveness_timeout)
+    }
+
+    /// Share the session out between logical streams, each of which can be used from its own thread. Reads and writes on the server itself become stream::Streams::default_stream().
+    /// Opening a stream fails if the peer is too old to support streams.
+    pub fn into_streams(self) -> stream::Streams<S> {
+        stream::new(self.state)
+    }
+
+    /// Did the device connect without any identity? See ServerConfig::allow_anonymous.
+    pub fn peer_is_anonymous(&self) -> bool {
+        self.state.peer_long_pk.is_none() && self.state.psk_id.is_none()
+    }
+
+    /// The id of the pre-shared key the device used, or None if it used its long-term keypair
+    pub fn psk_id(&self) -> Option<&[u8]> {
+        self.state.psk_id.as_ref().map(|id| id.as_slice())
+    }
+
+    /// Give the device a ticket which lets it resume this session with a server whose ServerConfig has the same ticket key until lifetime has passed.
+    /// Fails if the device is too old to understand tickets.
+    pub fn issue_ticket(&mut self, ticket_key: &TicketKey, lifetime: Duration) -> io::Result<()> {
+        general_issue_ticket(&mut self.state, ticket_key.as_bytes(), lifetime)
     }
 }
 
 /// Sending data
-impl io::Write for Server {
+impl<S: Transport, D> io::Write for Server<S, D> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        general_write(&mut self.state, buf)
+        general_write(&mut self.state, message::DEFAULT_STREAM, buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.state.stream.flush()
+        general_flush(&mut self.state)
     }
 }
 
 /// Receiving data
-impl io::Read for Server {
+impl<S: Transport, D> io::Read for Server<S, D> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let ret = general_read(&mut self.state, &mut self.read_buff);
-
-        if ret.is_err() {
-            return ret;
-        }
-
-        let num_elements = {
-            if buf.len() > self.read_buff.len() {
-                self.read_buff.len()
-            } else {
-                buf.len()
-            }
-        };
-
-        for i in 0..num_elements {
-            buf[i] = self.read_buff.remove(0);
-        }
-
-        Ok(num_elements)
+        general_read_into(&mut self.state, message::DEFAULT_STREAM, buf)
     }
 }
 
@@ -0,0 +1,236 @@
+//! Logical streams multiplexed over one session
+
+/*  This file is part of project-net.
+    project-net is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-net is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-net.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common::*;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A client or server shared between the handles of its streams, so that each stream can be used from its own thread.
+/// Streams take turns with the session: a read waits for the network for at most STREAM_POLL_INTERVAL_MS at a time, and a write sends one packet at a time, so a long write on one stream does not hold up the others.
+/// Transports which can't time out reads (see Transport::set_read_timeout) keep the session while a stream waits to read, so other streams can only write between packets.
+pub struct Streams<S: Transport> {
+    state: Arc<Mutex<ProtocolState<S>>>,
+}
+
+/// Reads and writes on one stream of a session.
+/// Data which arrives for other streams while this one is being read is kept for them. Dropping the handle does not close the stream.
+pub struct Stream<S: Transport> {
+    state: Arc<Mutex<ProtocolState<S>>>,
+    id: u16,
+}
+
+/// Used by the client and server to share their session out between streams
+pub fn new<S: Transport>(state: ProtocolState<S>) -> Streams<S> {
+    Streams{ state: Arc::new(Mutex::new(state)) }
+}
+
+/// Lock the session, failing if another stream panicked while using it
+fn lock<S: Transport>(state: &Mutex<ProtocolState<S>>) -> io::Result<MutexGuard<ProtocolState<S>>> {
+    match state.lock() {
+        Ok(guard) => Ok(guard),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "another stream panicked while using the session")),
+    }
+}
+
+fn poll_interval() -> Duration {
+    Duration::from_millis(STREAM_POLL_INTERVAL_MS)
+}
+
+impl<S: Transport> Streams<S> {
+    /// Make a handle for a stream which is known to exist
+    fn handle(&self, id: u16) -> io::Result<Stream<S>> {
+        let mut state = match lock(&self.state) {
+            Ok(s) => s,
+            Err(e) => return Err(e),
+        };
+
+        match general_claim_stream(&mut state, id) {
+            Ok(()) => Ok(Stream{ state: self.state.clone(), id: id }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Open a new stream to the peer. The peer finds out about it when data is first sent on it.
+    /// Fails if the peer is too old to support streams, or if MAX_OPEN_STREAMS of the streams we opened have not been closed yet.
+    pub fn open(&self) -> io::Result<Stream<S>> {
+        let id = match lock(&self.state) {
+            Ok(mut state) => match general_open_stream(&mut state) {
+                Ok(id) => id,
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+
+        self.handle(id)
+    }
+
+    /// The stream used by reads and writes on the client or server itself
+    pub fn default_stream(&self) -> io::Result<Stream<S>> {
+        self.handle(message::DEFAULT_STREAM)
+    }
+
+    /// A handle for a stream which either of us has already opened. There can only be one handle for each stream at a time.
+    pub fn stream(&self, id: u16) -> io::Result<Stream<S>> {
+        self.handle(id)
+    }
+
+    /// Block until the peer opens a stream which has not been handed out yet. Data which arrives for other streams in the mean time is kept for them.
+    /// Fails with io::ErrorKind::UnexpectedEof once the peer has finished sending.
+    pub fn accept(&self) -> io::Result<Stream<S>> {
+        let started = Instant::now();
+        loop {
+            let id = match lock(&self.state) {
+                Ok(mut state) => match general_poll_accept_stream(&mut state, started, poll_interval()) {
+                    Ok(id) => id,
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            };
+
+            match id {
+                // it may have been picked up with stream() already
+                Some(id) => match self.handle(id) {
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                    result => return result,
+                },
+                None => thread::yield_now(),
+            };
+        }
+    }
+}
+
+impl<S: Transport> Stream<S> {
+    /// The id of this stream. The peer sees the same id.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Send buf one packet at a time, letting other streams use the session in between. Returns the session's count of tracked writes after the last packet
+    fn send(&mut self, buf: &[u8], tracked: bool) -> io::Result<u64> {
+        let num_packets = match lock(&self.state) {
+            Ok(state) => match general_prepare_write(&state, self.id, buf, tracked) {
+                Ok(n) => n,
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut sent_count = 0;
+        for i in 0..num_packets {
+            if i > 0 {
+                thread::yield_now();
+            }
+
+            let mut state = match lock(&self.state) {
+                Ok(s) => s,
+                Err(e) => return Err(e),
+            };
+
+            match general_write_packet(&mut state, self.id, buf, i, num_packets, tracked) {
+                Ok(()) => (),
+                Err(e) => return Err(e),
+            };
+            sent_count = state.sent_count;
+        }
+
+        Ok(sent_count)
+    }
+
+    /// Send data like write() but return a handle which can be passed to wait_for_ack()
+    pub fn write_tracked(&mut self, buf: &[u8]) -> io::Result<u64> {
+        self.send(buf, true)
+    }
+
+    /// Has the peer acknowledged the tracked write with this handle?
+    pub fn is_acknowledged(&self, handle: u64) -> io::Result<bool> {
+        match lock(&self.state) {
+            Ok(state) => Ok(state.acked_count >= handle),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Block until the peer acknowledges the tracked write with this handle. Data received in the mean time is kept for the streams it was sent on
+    pub fn wait_for_ack(&mut self, handle: u64) -> io::Result<()> {
+        let started = Instant::now();
+        loop {
+            match lock(&self.state) {
+                Ok(mut state) => match general_poll_ack(&mut state, handle, started, poll_interval()) {
+                    Ok(true) => return Ok(()),
+                    Ok(false) => (),
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            };
+
+            thread::yield_now();
+        }
+    }
+
+    /// Tell the peer that nothing more will be written on this stream. Its reads on the stream return 0 once it has read everything before, and both of us can still read what the other sends until it closes the stream too.
+    /// Fails for the default stream, which is closed with shutdown_write() on the client or server, and over datagram transports.
+    pub fn close(&mut self) -> io::Result<()> {
+        match lock(&self.state) {
+            Ok(mut state) => general_close_stream(&mut state, self.id),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Let another handle be made for the stream
+impl<S: Transport> Drop for Stream<S> {
+    fn drop(&mut self) {
+        match self.state.lock() {
+            Ok(mut state) => { state.stream_handles.remove(&self.id); },
+            Err(poisoned) => { poisoned.into_inner().stream_handles.remove(&self.id); },
+        };
+    }
+}
+
+/// Sending data
+impl<S: Transport> io::Write for Stream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.send(buf, false) {
+            Ok(_) => Ok(buf.len()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match lock(&self.state) {
+            Ok(mut state) => general_flush(&mut state),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Receiving data
+impl<S: Transport> io::Read for Stream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            match lock(&self.state) {
+                Ok(mut state) => match general_poll_read_into(&mut state, self.id, buf, started, poll_interval()) {
+                    Ok(Some(n)) => return Ok(n),
+                    Ok(None) => (),
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            };
+
+            thread::yield_now();
+        }
+    }
+}
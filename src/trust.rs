@@ -0,0 +1,218 @@
+//! Where the long-term public keys we trust come from
+
+/*  This file is part of project-net.
+    project-net is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-net is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-net.  If not, see http://www.gnu.org/licenses/.*/
+
+use proj_crypto::asymmetric::*;
+use proj_crypto::asymmetric::key_id::PublicKeyId;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, RwLock};
+use from_utf8_hex;
+
+/// Starts each line of a public key file, as written by key_gen_to_file()
+const KEY_PREFIX: &'static str = "PK: ";
+
+/// A change to the keys in a trust store
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrustChange {
+    /// the key with this id is trusted now
+    Added(PublicKeyId),
+    /// the key with this id is no longer trusted
+    Removed(PublicKeyId),
+}
+
+/// The long-term public keys which the client and server accept from their peers.
+/// The key exchange looks keys up when a peer connects, so a store which changes while a server runs affects every connection from then on.
+pub trait TrustStore {
+    /// The trusted key with this id
+    fn lookup(&self, id: &PublicKeyId) -> Option<PublicKey>;
+
+    /// Every trusted key
+    fn keys(&self) -> Vec<PublicKey>;
+
+    /// Receive every change made to the store from now on. The sender hangs up once the store can't change any more.
+    /// Stores which never change can keep the default, which hangs up straight away.
+    fn watch(&self) -> mpsc::Receiver<TrustChange> {
+        let (_, receiver) = mpsc::channel();
+        receiver
+    }
+}
+
+/// A map which nothing else can change while it is borrowed, as returned by get_keys()
+impl TrustStore for HashMap<PublicKeyId, PublicKey> {
+    fn lookup(&self, id: &PublicKeyId) -> Option<PublicKey> {
+        key_id::find_public_key(id, self)
+    }
+
+    fn keys(&self) -> Vec<PublicKey> {
+        self.values().cloned().collect()
+    }
+}
+
+/// Trusted keys held in memory which can be changed while they are in use, for example from another thread through an Arc
+pub struct MemoryTrustStore {
+    keys: RwLock<HashMap<PublicKeyId, PublicKey>>,
+    watchers: Mutex<Vec<mpsc::Sender<TrustChange>>>,
+}
+
+impl MemoryTrustStore {
+    /// A store which does not trust anything yet
+    pub fn new() -> MemoryTrustStore {
+        MemoryTrustStore::from_map(HashMap::new())
+    }
+
+    /// A store which starts off trusting the keys in this map
+    pub fn from_map(keys: HashMap<PublicKeyId, PublicKey>) -> MemoryTrustStore {
+        MemoryTrustStore { keys: RwLock::new(keys), watchers: Mutex::new(Vec::new()) }
+    }
+
+    /// Trust this key. Returns false if it was already trusted
+    pub fn insert(&self, pk: PublicKey) -> bool {
+        let id = key_id::id_of_pk(&pk);
+        let added = self.keys.write().unwrap().insert(id.clone(), pk).is_none();
+
+        if added {
+            self.notify(vec!(TrustChange::Added(id)));
+        }
+        added
+    }
+
+    /// Stop trusting the key with this id, returning it if it was trusted
+    pub fn remove(&self, id: &PublicKeyId) -> Option<PublicKey> {
+        let removed = self.keys.write().unwrap().remove(id);
+
+        if removed.is_some() {
+            self.notify(vec!(TrustChange::Removed(id.clone())));
+        }
+        removed
+    }
+
+    /// Trust exactly the keys in this map
+    fn replace(&self, keys: HashMap<PublicKeyId, PublicKey>) {
+        let mut changes = Vec::new();
+        {
+            let mut current = self.keys.write().unwrap();
+            for id in current.keys().filter(|id| !keys.contains_key(id)) {
+                changes.push(TrustChange::Removed(id.clone()));
+            }
+            for id in keys.keys().filter(|id| !current.contains_key(id)) {
+                changes.push(TrustChange::Added(id.clone()));
+            }
+            *current = keys;
+        }
+
+        self.notify(changes);
+    }
+
+    /// Tell the watchers about the changes, forgetting the ones which have stopped listening
+    fn notify(&self, changes: Vec<TrustChange>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        for change in changes {
+            watchers.retain(|w| w.send(change.clone()).is_ok());
+        }
+    }
+}
+
+impl TrustStore for MemoryTrustStore {
+    fn lookup(&self, id: &PublicKeyId) -> Option<PublicKey> {
+        self.keys.read().unwrap().get(id).cloned()
+    }
+
+    fn keys(&self) -> Vec<PublicKey> {
+        self.keys.read().unwrap().values().cloned().collect()
+    }
+
+    fn watch(&self) -> mpsc::Receiver<TrustChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Trusted keys read from a public key file like the one given to get_keys(): one "PK: " line per key.
+/// Call reload() after the file has been changed.
+pub struct FileTrustStore {
+    path: PathBuf,
+    keys: MemoryTrustStore,
+}
+
+impl FileTrustStore {
+    /// Read the public key file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileTrustStore> {
+        let keys = match read_public_keys(&path) {
+            Ok(k) => k,
+            Err(e) => return Err(e),
+        };
+
+        Ok(FileTrustStore { path: path.as_ref().to_path_buf(), keys: MemoryTrustStore::from_map(keys) })
+    }
+
+    /// Read the file again, telling the watchers about any keys which have been added or removed. If the file can't be read the keys are left alone.
+    pub fn reload(&self) -> io::Result<()> {
+        match read_public_keys(&self.path) {
+            Ok(keys) => {
+                self.keys.replace(keys);
+                Ok(()) },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl TrustStore for FileTrustStore {
+    fn lookup(&self, id: &PublicKeyId) -> Option<PublicKey> {
+        self.keys.lookup(id)
+    }
+
+    fn keys(&self) -> Vec<PublicKey> {
+        self.keys.keys()
+    }
+
+    fn watch(&self) -> mpsc::Receiver<TrustChange> {
+        self.keys.watch()
+    }
+}
+
+/// Read a public key file, as written by key_gen_to_file(). Every line which is not blank must hold a key
+pub fn read_public_keys<P: AsRef<Path>>(path: P) -> io::Result<HashMap<PublicKeyId, PublicKey>> {
+    let mut contents = String::new();
+    match fs::File::open(&path) {
+        Ok(mut f) => match f.read_to_string(&mut contents) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        },
+        Err(e) => return Err(e),
+    };
+
+    let mut keys = HashMap::new();
+    for line in contents.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let bytes = if line.starts_with(KEY_PREFIX) { from_utf8_hex(&line[KEY_PREFIX.len()..]) } else { None };
+        let key = match bytes {
+            Some(b) => public_key_from_slice(&b),
+            None => None,
+        };
+
+        match key {
+            Some(pk) => { keys.insert(key_id::id_of_pk(&pk), pk); },
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line in the public key file: '{}'", line))),
+        };
+    }
+
+    Ok(keys)
+}